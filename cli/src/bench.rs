@@ -0,0 +1,97 @@
+use core::{Cell, EofPolicy, TapeMode, advance::{bytecode::ir_to_bytecodes, ir::parse_to_ir, verify::VerifiedBytecode, range::generate_range_info, program::Program, tape::Tape, tier::{internal::Tier, run}}, error::BrainrotError};
+use std::{io::{Read, stdin}, num::NonZeroUsize, time::{Duration, Instant}};
+
+pub struct BenchConfig {
+    pub count: NonZeroUsize,
+    pub eof: EofPolicy,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+}
+
+struct Stats {
+    mean: f64,
+    median: f64,
+    min: f64,
+    max: f64,
+    stddev: f64,
+}
+impl Stats {
+    fn from_durations(durations: &[Duration]) -> Stats {
+        let mut secs: Vec<f64> = durations.iter().map(Duration::as_secs_f64).collect();
+        secs.sort_by(f64::total_cmp);
+
+        let n = secs.len() as f64;
+        let mean = secs.iter().sum::<f64>() / n;
+        let median = if secs.len().is_multiple_of(2) {
+            (secs[secs.len() / 2 - 1] + secs[secs.len() / 2]) / 2.0
+        } else {
+            secs[secs.len() / 2]
+        };
+        let variance = secs.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / n;
+
+        Stats {
+            mean, median,
+            min: secs[0],
+            max: secs[secs.len() - 1],
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PhaseTimes {
+    parse: Vec<Duration>,
+    range: Vec<Duration>,
+    bytecode: Vec<Duration>,
+    execute: Vec<Duration>,
+}
+
+fn run_once<C: Cell>(code: &str, input: &[u8], config: &BenchConfig, times: &mut PhaseTimes) -> Result<(), BrainrotError> {
+    let start = Instant::now();
+    let ir = parse_to_ir(code)?;
+    times.parse.push(start.elapsed());
+
+    let start = Instant::now();
+    let range = generate_range_info(&ir, config.tape_length, config.tape_mode)?;
+    times.range.push(start.elapsed());
+
+    let start = Instant::now();
+    let bytecode = VerifiedBytecode::verify(ir_to_bytecodes::<C>(&ir, &range)?, config.tape_length, config.tape_mode, range.do_opt_first)?;
+    times.bytecode.push(start.elapsed());
+
+    // 入力は毎回先頭から流し直し、出力は捨てる
+    let mut input = input.iter().copied();
+    let start = Instant::now();
+    let mut tier = if bytecode.opt_first() { Tier::Opt } else { Tier::Deopt };
+    let mut tape = Tape::<C>::new(config.tape_length, config.tape_mode);
+    let mut program = Program::new(&bytecode, None, || input.next(), |_| {}, false, config.eof);
+    run(&mut tier, &mut tape, &mut program)?;
+    times.execute.push(start.elapsed());
+
+    Ok(())
+}
+
+pub fn benchmark<C: Cell>(code: &str, config: BenchConfig) -> Result<(), BrainrotError> {
+    let mut input = Vec::new();
+    stdin().lock().read_to_end(&mut input)?;
+
+    let mut times = PhaseTimes::default();
+    for i in 0..config.count.get() {
+        run_once::<C>(code, &input, &config, &mut times)?;
+        eprintln!("{}/{}: {}s", i + 1, config.count, times.execute[i].as_secs_f64());
+    }
+
+    println!("Count: {}", config.count);
+    println!("{:<10}{:>14}{:>14}{:>14}{:>14}{:>14}", "Phase(sec)", "mean", "median", "min", "max", "stddev");
+    for (name, durations) in [
+        ("parse", &times.parse),
+        ("range", &times.range),
+        ("bytecode", &times.bytecode),
+        ("execute", &times.execute),
+    ] {
+        let s = Stats::from_durations(durations);
+        println!("{:<10}{:>14.9}{:>14.9}{:>14.9}{:>14.9}{:>14.9}", name, s.mean, s.median, s.min, s.max, s.stddev);
+    }
+
+    Ok(())
+}
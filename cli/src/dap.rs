@@ -0,0 +1,544 @@
+use core::{Brainrot, BrainrotInit, BrainrotResult, Cell, EofPolicy, InterruptHandle, JournalConfig, TapeMode, WatchKind, error::{BrainrotError, RuntimeError}, io::{OutputSink, PushInput}, source::{Location, locate, offset_of}};
+use std::{fs, io::{self, BufRead, Stdout, Write, stdin, stdout}, ops::RangeInclusive, path::Path, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc}, thread};
+
+use serde_json::{Value, json};
+
+use crate::debug::reverse_step;
+
+pub struct DapConfig {
+    pub eof: EofPolicy,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+}
+
+/// BF にスレッドはないので、いつも 1 つだけ
+const THREAD_ID: u64 = 1;
+const FRAME_ID: u64 = 1;
+const TAPE_REFERENCE: u64 = 1;
+
+/// The stdout half of the connection. Messages are framed with a `Content-Length` header.
+#[derive(Clone)]
+struct Dap(Arc<Mutex<Writer>>);
+struct Writer {
+    seq: u64,
+    out: Stdout,
+}
+impl Dap {
+    fn send(&self, mut message: Value) -> io::Result<()> {
+        let mut writer = self.0.lock().unwrap();
+        writer.seq += 1;
+        message["seq"] = json!(writer.seq);
+        write_message(&mut writer.out, &message)
+    }
+    fn event(&self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+    fn respond(&self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+    fn stopped(&self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+    fn exited(&self, code: u8) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": code }))?;
+        self.event("terminated", json!({}))
+    }
+    fn fail(&self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+}
+
+/// Sends what the program writes as `output` events, a line at a time.
+struct DapOutput {
+    dap: Dap,
+    buffer: Vec<u8>,
+}
+impl OutputSink for DapOutput {
+    fn write(&mut self, value: u8) -> io::Result<bool> {
+        self.buffer.push(value);
+        // UTF-8 の途中で切らない
+        if value == b'\n' || (self.buffer.len() >= 4096 && str::from_utf8(&self.buffer).is_ok()) {
+            self.flush()?;
+        }
+        Ok(false)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let output = String::from_utf8_lossy(&self.buffer).into_owned();
+        self.buffer.clear();
+        self.dap.event("output", json!({ "category": "stdout", "output": output }))
+    }
+}
+
+/// Reads one message framed with a `Content-Length` header. `lsp` uses the same framing.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        match line.trim_end().split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Content-Length") => length = value.trim().parse().ok(),
+            None if line.trim_end().is_empty() && length.is_some() => break,
+            _ => {}
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RunTo {
+    /// `true` steps a single bytecode, for `granularity: "instruction"`.
+    Step(bool),
+    Continue,
+}
+
+/// An out-of-bounds access the program stopped on. Resuming after it ends the session.
+struct Fault {
+    err: BrainrotError,
+    source_range: Option<RangeInclusive<usize>>,
+}
+
+struct Launched<C: Cell> {
+    code: String,
+    path: String,
+    vm: Brainrot<PushInput, DapOutput, C>,
+    fault: Option<Fault>,
+    ended: bool,
+}
+
+struct Server<C: Cell> {
+    dap: Dap,
+    config: DapConfig,
+    /// `pause` only interrupts while the program runs, so that it doesn't stop the next `continue` instead.
+    running: Arc<AtomicBool>,
+    /// The handle of the launched program, shared with the thread that reads requests.
+    interrupt: Arc<Mutex<Option<InterruptHandle>>>,
+    /// DAP の行と列は既定で 1 始まりだが、クライアントが 0 始まりを選べる
+    line_base: usize,
+    column_base: usize,
+    breakpoints: Vec<Location>,
+    program: Option<Launched<C>>,
+    configured: bool,
+    stop_on_entry: bool,
+    started: bool,
+}
+
+pub fn serve<C: Cell>(config: DapConfig) -> Result<(), BrainrotError> {
+    let dap = Dap(Arc::new(Mutex::new(Writer { seq: 0, out: stdout() })));
+    let running = Arc::new(AtomicBool::new(false));
+    let interrupt = Arc::new(Mutex::new(None::<InterruptHandle>));
+
+    // 実行中も `pause` を受け取れるように、読み込みは別スレッドで行う
+    let (sender, receiver) = mpsc::channel();
+    let reader = {
+        let running = running.clone();
+        let interrupt = interrupt.clone();
+        thread::spawn(move || -> io::Result<()> {
+            let mut input = stdin().lock();
+            while let Some(message) = read_message(&mut input)? {
+                if message["command"] == "pause" && running.load(Ordering::SeqCst) && let Some(handle) = &*interrupt.lock().unwrap() {
+                    handle.interrupt();
+                }
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let mut server = Server::<C> {
+        dap, config, running,
+        interrupt,
+        line_base: 1,
+        column_base: 1,
+        breakpoints: vec![],
+        program: None,
+        configured: false,
+        stop_on_entry: false,
+        started: false,
+    };
+    for message in receiver {
+        if message["type"] != "request" {
+            continue;
+        }
+        if !server.handle(&message)? {
+            break;
+        }
+    }
+    if reader.is_finished() {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+impl<C: Cell> Server<C> {
+    /// Returns `false` once the client disconnects.
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                self.line_base = if args["linesStartAt1"] == false { 0 } else { 1 };
+                self.column_base = if args["columnsStartAt1"] == false { 0 } else { 1 };
+                self.dap.respond(request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsExceptionInfoRequest": true,
+                    "supportsSteppingGranularity": true,
+                    "supportsStepBack": true,
+                    "supportsDataBreakpoints": true,
+                }))?;
+                self.dap.event("initialized", json!({}))?;
+            }
+            "launch" => match self.launch(args) {
+                Ok(launched) => {
+                    *self.interrupt.lock().unwrap() = Some(launched.vm.interrupt_handle());
+                    self.program = Some(launched);
+                    self.apply_breakpoints();
+                    self.dap.respond(request, json!({}))?;
+                    self.start()?;
+                }
+                Err(message) => self.dap.fail(request, &message)?,
+            },
+            "setBreakpoints" => {
+                let base = |value: &Value, base: usize| value.as_u64().map(|v| v as usize + 1 - base);
+                self.breakpoints = args["breakpoints"].as_array().into_iter().flatten()
+                    .filter_map(|bp| Some(Location {
+                        line: base(&bp["line"], self.line_base)?,
+                        column: base(&bp["column"], self.column_base).unwrap_or(1),
+                    }))
+                    .collect();
+                let breakpoints = self.apply_breakpoints();
+                self.dap.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.dap.respond(request, json!({}))?;
+                self.start()?;
+            }
+            "threads" => self.dap.respond(request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }))?,
+            "stackTrace" => {
+                let frames = self.program.as_ref().filter(|p| !p.ended).map(|p| {
+                    let range = match &p.fault {
+                        Some(fault) => fault.source_range.clone(),
+                        None => p.vm.source_range(),
+                    };
+                    let mut frame = json!({
+                        "id": FRAME_ID,
+                        "name": format!("pc {}", p.vm.pc()),
+                        "source": source(&p.path),
+                        "line": 0,
+                        "column": 0,
+                    });
+                    if let Some(range) = range {
+                        let start = locate(&p.code, *range.start());
+                        let end = locate(&p.code, range.end() + 1);
+                        frame["line"] = json!(start.line - 1 + self.line_base);
+                        frame["column"] = json!(start.column - 1 + self.column_base);
+                        frame["endLine"] = json!(end.line - 1 + self.line_base);
+                        frame["endColumn"] = json!(end.column - 1 + self.column_base);
+                    }
+                    frame
+                });
+                let frames = frames.into_iter().collect::<Vec<_>>();
+                self.dap.respond(request, json!({ "stackFrames": frames, "totalFrames": frames.len() }))?;
+            }
+            "scopes" => {
+                let length = self.program.as_ref().map_or(0, |p| p.vm.tape_len());
+                self.dap.respond(request, json!({ "scopes": [{
+                    "name": "Tape",
+                    "variablesReference": TAPE_REFERENCE,
+                    "indexedVariables": length,
+                    "expensive": false,
+                }] }))?;
+            }
+            "variables" => {
+                let variables = match &self.program {
+                    Some(p) if args["variablesReference"] == TAPE_REFERENCE => {
+                        let start = args["start"].as_u64().map_or(0, |v| v as usize).min(p.vm.tape_len());
+                        let count = args["count"].as_u64().map_or(usize::MAX, |v| v as usize);
+                        (start..p.vm.tape_len()).take(count)
+                            .map(|i| json!({
+                                "name": if i == p.vm.pointer() { format!("[{}] <- pointer", i) } else { format!("[{}]", i) },
+                                "value": p.vm.get_tape(i).map(ToString::to_string).unwrap_or_default(),
+                                "variablesReference": 0,
+                            }))
+                            .collect()
+                    }
+                    _ => vec![],
+                };
+                self.dap.respond(request, json!({ "variables": variables }))?;
+            }
+            // 変数 `[N]` か式 `tape[N]` のセルだけを見張れる
+            "dataBreakpointInfo" => {
+                let name = args["name"].as_str().unwrap_or_default();
+                let cell = match args["variablesReference"] == TAPE_REFERENCE {
+                    true => name.strip_prefix('[').and_then(|name| name.split(']').next()),
+                    false => name.trim().strip_prefix("tape[").and_then(|name| name.strip_suffix(']')),
+                };
+                match cell.and_then(|cell| cell.trim().parse::<usize>().ok()) {
+                    Some(cell) => self.dap.respond(request, json!({
+                        "dataId": cell.to_string(),
+                        "description": format!("Cell {}", cell),
+                        "accessTypes": ["read", "write", "readWrite"],
+                        "canPersist": false,
+                    }))?,
+                    None => self.dap.respond(request, json!({ "dataId": null, "description": "Only tape cells can be watched" }))?,
+                }
+            }
+            "setDataBreakpoints" => {
+                let breakpoints: Vec<Value> = match &mut self.program {
+                    Some(p) => {
+                        p.vm.clear_watches();
+                        args["breakpoints"].as_array().into_iter().flatten().map(|bp| {
+                            let Some(cell) = bp["dataId"].as_str().and_then(|id| id.parse::<usize>().ok()) else {
+                                return json!({ "verified": false, "message": "Not a tape cell" });
+                            };
+                            let kinds = match (bp["accessType"].as_str(), bp["condition"].as_str()) {
+                                (Some("read"), _) => vec![WatchKind::Read],
+                                (Some("readWrite"), _) => vec![WatchKind::Read, WatchKind::Write],
+                                (_, Some(condition)) => match condition.trim().trim_start_matches("==").trim().parse::<u32>() {
+                                    Ok(value) => vec![WatchKind::Equals(C::truncate(value))],
+                                    Err(_) => return json!({ "verified": false, "message": "The condition has to be a value, such as `== 10`" }),
+                                },
+                                _ => vec![WatchKind::Write],
+                            };
+                            for kind in kinds {
+                                p.vm.watch(cell, kind);
+                            }
+                            json!({ "verified": true })
+                        }).collect()
+                    }
+                    None => args["breakpoints"].as_array().into_iter().flatten()
+                        .map(|_| json!({ "verified": false, "message": "The program is not launched yet" }))
+                        .collect(),
+                };
+                self.dap.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "evaluate" => match self.program.as_ref().and_then(|p| evaluate(&p.vm, args["expression"].as_str().unwrap_or_default())) {
+                Some(result) => self.dap.respond(request, json!({ "result": result, "variablesReference": 0 }))?,
+                None => self.dap.fail(request, "Expected `pointer`, `cell`, `pc` or `tape[N]`")?,
+            },
+            "exceptionInfo" => match self.program.as_ref().and_then(|p| p.fault.as_ref()) {
+                Some(fault) => self.dap.respond(request, json!({
+                    "exceptionId": exception_id(&fault.err),
+                    "description": fault.err.to_string(),
+                    "breakMode": "always",
+                }))?,
+                None => self.dap.fail(request, "Not stopped on an exception")?,
+            },
+            "continue" => {
+                self.dap.respond(request, json!({ "allThreadsContinued": true }))?;
+                self.run(RunTo::Continue)?;
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.dap.respond(request, json!({}))?;
+                self.run(RunTo::Step(args["granularity"] == "instruction"))?;
+            }
+            "stepBack" | "reverseContinue" => match &mut self.program {
+                Some(p) if p.vm.journal().is_some() => {
+                    self.dap.respond(request, json!({}))?;
+                    // 一番古い所まで戻ったら、そこで止まる
+                    let hit = match request["command"].as_str() {
+                        Some("reverseContinue") => p.vm.reverse_continue(),
+                        _ if args["granularity"] == "instruction" => {
+                            p.vm.reverse_step_bytecode();
+                            false
+                        }
+                        _ => {
+                            reverse_step(&mut p.vm);
+                            false
+                        }
+                    };
+                    // 戻れば範囲外アクセスはまだ起きていない
+                    p.fault = None;
+                    self.dap.stopped(if hit { "breakpoint" } else { "step" }, None)?;
+                }
+                _ => self.dap.fail(request, "Launch with `record: true` to go backwards")?,
+            },
+            // 実行中なら読み込みスレッドが割り込み済み
+            "pause" => self.dap.respond(request, json!({}))?,
+            "disconnect" | "terminate" => {
+                self.dap.respond(request, json!({}))?;
+                return Ok(false);
+            }
+            command => self.dap.fail(request, &format!("Unsupported request `{}`", command))?,
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Launched<C>, String> {
+        let path = args["program"].as_str().ok_or("`program` is required")?.to_owned();
+        let code = fs::read_to_string(&path).map_err(|err| format!("{}: {}", path, err))?;
+        let mut vm = Brainrot::new(&code, BrainrotInit {
+            input: PushInput::default(),
+            output: DapOutput { dap: self.dap.clone(), buffer: vec![] },
+            io_break: false,
+            timeout_step: None,
+            eof: self.config.eof,
+            tape_length: self.config.tape_length,
+            tape_mode: self.config.tape_mode,
+        }).map_err(|err| err.render(&code))?;
+        // デバッグ対象の stdin はないので、入力は launch の引数で全部渡す
+        vm.feed_input(args["input"].as_str().unwrap_or_default().as_bytes());
+        vm.close_input();
+        self.stop_on_entry = args["stopOnEntry"] == true;
+        if args["record"] == true {
+            vm.start_recording(JournalConfig::default());
+        }
+        Ok(Launched { code, path, vm, fault: None, ended: false })
+    }
+
+    /// Sets the requested breakpoints on the program, if it is launched, and reports where each one landed.
+    fn apply_breakpoints(&mut self) -> Vec<Value> {
+        let Some(p) = &mut self.program else {
+            return self.breakpoints.iter().map(|_| json!({ "verified": false, "message": "The program is not launched yet" })).collect();
+        };
+        p.vm.clear_breakpoints();
+        let mut breakpoints = vec![];
+        for location in &self.breakpoints {
+            let pc = offset_of(&p.code, *location).and_then(|offset| p.vm.pc_at_source(offset));
+            let start = pc.and_then(|pc| p.vm.origins()[pc].source_range.clone());
+            breakpoints.push(match (pc, start) {
+                (Some(pc), Some(range)) => {
+                    p.vm.set_breakpoint(pc);
+                    let at = locate(&p.code, *range.start());
+                    json!({
+                        "verified": true,
+                        "line": at.line - 1 + self.line_base,
+                        "column": at.column - 1 + self.column_base,
+                    })
+                }
+                _ => json!({ "verified": false, "message": "No instruction at or after this line" }),
+            });
+        }
+        breakpoints
+    }
+
+    /// Starts the program once it is both launched and configured.
+    fn start(&mut self) -> io::Result<()> {
+        if self.started || !self.configured || self.program.is_none() {
+            return Ok(());
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            self.dap.stopped("entry", None)
+        } else {
+            self.run(RunTo::Continue)
+        }
+    }
+
+    fn run(&mut self, to: RunTo) -> io::Result<()> {
+        let Some(p) = &mut self.program else { return Ok(()) };
+        if p.ended {
+            return Ok(());
+        }
+        if p.fault.is_some() {
+            // 範囲外アクセスの先へは進めない。run と同じ終了コードで終わる
+            p.ended = true;
+            return self.dap.exited(2);
+        }
+
+        let start = (p.vm.pc(), p.vm.source_range());
+        self.running.store(true, Ordering::SeqCst);
+        let result = loop {
+            let result = match to {
+                RunTo::Step(_) => p.vm.step_bytecode(),
+                RunTo::Continue => p.vm.step(),
+            };
+            match result {
+                Ok(BrainrotResult::Stepped) => {
+                    if to == RunTo::Step(true) || p.vm.pc() == start.0 || p.vm.source_range() != start.1 {
+                        break Ok(BrainrotResult::Stepped);
+                    }
+                }
+                // 入力は launch で閉じているので NeedInput は来ない。出力も止めない
+                Ok(BrainrotResult::NeedInput | BrainrotResult::Output(_) | BrainrotResult::IoBreak | BrainrotResult::OutOfFuel) => {}
+                result => break result,
+            }
+        };
+        self.running.store(false, Ordering::SeqCst);
+
+        match result {
+            Ok(BrainrotResult::Stepped) => self.dap.stopped("step", None),
+            Ok(BrainrotResult::Breakpoint) => self.dap.stopped("breakpoint", None),
+            Ok(BrainrotResult::Interrupted) => self.dap.stopped("pause", None),
+            Ok(BrainrotResult::Watchpoint { cell, old, new, .. }) => {
+                let text = match old == new {
+                    true => format!("Cell {} was read", cell),
+                    false => format!("Cell {} changed from {} to {}", cell, old, new),
+                };
+                self.dap.stopped("data breakpoint", Some(text))
+            }
+            Ok(_) => {
+                p.ended = true;
+                self.dap.exited(0)
+            }
+            Err(err) if exception_id(&err).is_some() => {
+                let text = err.to_string();
+                p.fault = Some(Fault { source_range: err.source_range(), err });
+                self.dap.stopped("exception", Some(text))
+            }
+            Err(err) => {
+                p.ended = true;
+                self.dap.event("output", json!({ "category": "stderr", "output": err.render(&p.code) }))?;
+                self.dap.exited(1)
+            }
+        }
+    }
+}
+
+fn source(path: &str) -> Value {
+    let name = Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy());
+    json!({ "name": name, "path": path })
+}
+
+/// Only the out-of-bounds errors stop the program as exceptions. Anything else ends it.
+fn exception_id(err: &BrainrotError) -> Option<&'static str> {
+    match err {
+        BrainrotError::RuntimeError { err: RuntimeError::OOBGet(..), .. } => Some("OOBGet"),
+        BrainrotError::RuntimeError { err: RuntimeError::OOBSet(..), .. } => Some("OOBSet"),
+        BrainrotError::RuntimeError { err: RuntimeError::OOBAdd(..), .. } => Some("OOBAdd"),
+        BrainrotError::RuntimeError { err: RuntimeError::OOBSub(..), .. } => Some("OOBSub"),
+        _ => None,
+    }
+}
+
+/// Watch expressions: `pointer`, `cell` (the one under the pointer), `pc` and `tape[N]`.
+fn evaluate<C: Cell>(vm: &Brainrot<PushInput, DapOutput, C>, expression: &str) -> Option<String> {
+    let cell = |i: usize| Some(vm.get_tape(i).map_or_else(|| "past the end of the tape".to_owned(), ToString::to_string));
+    match expression.trim() {
+        "pointer" | "ptr" => Some(vm.pointer().to_string()),
+        "cell" => cell(vm.pointer()),
+        "pc" => Some(vm.pc().to_string()),
+        expression => cell(expression.strip_prefix("tape[")?.strip_suffix(']')?.trim().parse().ok()?),
+    }
+}
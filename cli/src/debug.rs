@@ -0,0 +1,417 @@
+use core::{Brainrot, BrainrotInit, BrainrotResult, Cell, EofPolicy, JournalConfig, TapeMode, WatchKind, error::BrainrotError, io::{InputSource, OutputSink, PullOutput, PushInput}, source::{Location, locate, offset_of, render_snippet}};
+use std::{io::{self, BufRead, Write, stdin, stdout}, ops::RangeInclusive};
+
+use crate::{TAPE_WINDOW, tape_window};
+
+pub struct DebugConfig {
+    pub eof: EofPolicy,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+    /// `None` asks for a line on stdin whenever `,` runs out of input.
+    pub input: Option<Vec<u8>>,
+}
+
+const HELP: &str = "\
+break LINE[:COL]    stop before the instruction at LINE:COL     (b)
+delete [LINE[:COL]] remove that breakpoint, or all of them      (d)
+watch CELL [KIND]   stop after CELL is written, or with KIND    (wa)
+                    `read`, read, or `=V`, set to V
+unwatch [CELL]      remove the watchpoints on CELL, or all      (uw)
+list                show the breakpoints and watchpoints        (l)
+step [N]            run N source instructions                   (s)
+continue            run to the next breakpoint                  (c)
+output              run until the program writes a byte         (o)
+tape [RADIUS]       show the cells around the pointer           (t)
+pointer             show the pointer and its cell               (p)
+where               show the next instruction                   (w)
+record [MIB]        keep a journal of the run, to go backwards
+reverse-step [N]    go back N source instructions               (rs)
+reverse-continue    go back to the previous breakpoint          (rc)
+last CELL           show when CELL last changed
+quit                                                            (q)";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RunTo {
+    Step,
+    Continue,
+    Output,
+}
+
+struct Session<'a, C: Cell> {
+    code: &'a str,
+    vm: Brainrot<PushInput, PullOutput, C>,
+    ended: bool,
+    /// 入力を対話的に聞くか。`--input` があれば最初から全部流して閉じている
+    ask_input: bool,
+    /// プログラムの出力の後に改行がないとプロンプトがそれに続いてしまう
+    mid_line: bool,
+}
+
+pub fn debug<C: Cell>(code: &str, config: DebugConfig) -> Result<(), BrainrotError> {
+    let mut vm = Brainrot::<_, _, C>::new(code, BrainrotInit {
+        input: PushInput::default(),
+        output: PullOutput,
+        io_break: false,
+        timeout_step: None,
+        eof: config.eof,
+        tape_length: config.tape_length,
+        tape_mode: config.tape_mode,
+    })?;
+    let ask_input = config.input.is_none();
+    if let Some(input) = config.input {
+        vm.feed_input(&input);
+        vm.close_input();
+    }
+    let interrupt = vm.interrupt_handle();
+    // 実行中の Ctrl-C はプロンプトに戻るだけにする
+    let _ = ctrlc::set_handler(move || interrupt.interrupt());
+
+    let mut session = Session { code, vm, ended: false, ask_input, mid_line: false };
+
+    // `#` はそのままブレークポイントにする
+    for (offset, _) in code.match_indices('#') {
+        if let Some(pc) = session.vm.pc_at_source(offset) {
+            session.vm.set_breakpoint(pc);
+        }
+    }
+    let marks = session.vm.breakpoints().count();
+    if marks != 0 {
+        println!("{} breakpoint(s) from # markers", marks);
+    }
+    println!("Type `help` for commands");
+    session.show_where();
+
+    loop {
+        session.prompt("(brainrot) ")?;
+        let Some(line) = read_line()? else { break };
+        if !session.command(&line)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Goes back to the start of the source instruction before the current one. Returns `false` at the oldest recorded step.
+pub fn reverse_step<I: InputSource, O: OutputSink, C: Cell>(vm: &mut Brainrot<I, O, C>) -> bool {
+    if !vm.reverse_step_bytecode() {
+        return false;
+    }
+    // 1 命令の bytecode は pc の昇順に続くので、その先頭まで戻る
+    while let Some(journal) = vm.journal() && journal.position() > journal.oldest() {
+        let before = journal.pc_at(journal.position() - 1);
+        if before >= vm.pc() || vm.origins()[before].source_range != vm.source_range() {
+            break;
+        }
+        vm.reverse_step_bytecode();
+    }
+    true
+}
+
+/// `write` when left out.
+fn parse_watch_kind<C: Cell>(word: Option<&str>) -> Option<WatchKind<C>> {
+    match word {
+        None | Some("write") => Some(WatchKind::Write),
+        Some("read") => Some(WatchKind::Read),
+        Some(word) => word.strip_prefix('=')?.parse::<u32>().ok().map(|value| WatchKind::Equals(C::truncate(value))),
+    }
+}
+
+fn describe_watch<C: Cell>(kind: WatchKind<C>) -> String {
+    match kind {
+        WatchKind::Read => "reads".to_owned(),
+        WatchKind::Write => "changes".to_owned(),
+        WatchKind::Equals(value) => format!("changes to {}", value),
+    }
+}
+
+/// コマンドと `,` への入力が同じ stdin を取り合うので、ロックは 1 行ごとに取る
+fn read_line() -> io::Result<Option<String>> {
+    let mut line = String::new();
+    Ok(match stdin().lock().read_line(&mut line)? {
+        0 => None,
+        _ => Some(line),
+    })
+}
+
+impl<C: Cell> Session<'_, C> {
+    fn prompt(&mut self, prompt: &str) -> Result<(), BrainrotError> {
+        let mut out = stdout().lock();
+        if self.mid_line {
+            writeln!(out)?;
+            self.mid_line = false;
+        }
+        write!(out, "{}", prompt)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Returns `false` to quit.
+    fn command(&mut self, line: &str) -> Result<bool, BrainrotError> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(true) };
+        let arg = words.next();
+
+        match command {
+            "b" | "break" => match arg.and_then(|arg| self.parse_location(arg)) {
+                Some((location, pc)) => {
+                    self.vm.set_breakpoint(pc);
+                    println!("Breakpoint at {}:{} (pc {})", location.line, location.column, pc);
+                }
+                None => println!("Usage: break LINE[:COL], where LINE:COL is inside the program"),
+            },
+            "d" | "delete" => match arg {
+                None => {
+                    self.vm.clear_breakpoints();
+                    println!("Deleted all breakpoints");
+                }
+                Some(arg) => match self.parse_location(arg) {
+                    Some((location, pc)) if self.vm.remove_breakpoint(pc) => println!("Deleted the breakpoint at {}:{}", location.line, location.column),
+                    Some((location, _)) => println!("No breakpoint at {}:{}", location.line, location.column),
+                    None => println!("Usage: delete [LINE[:COL]]"),
+                },
+            },
+            "wa" | "watch" => match (arg.map(str::parse::<usize>), parse_watch_kind(words.next())) {
+                (Some(Ok(cell)), Some(kind)) => {
+                    self.vm.watch(cell, kind);
+                    println!("Watching cell {} for {}", cell, describe_watch(kind));
+                }
+                _ => println!("Usage: watch CELL [read|write|=VALUE]"),
+            },
+            "uw" | "unwatch" => match arg.map(str::parse::<usize>) {
+                None => {
+                    self.vm.clear_watches();
+                    println!("Deleted all watchpoints");
+                }
+                Some(Ok(cell)) => {
+                    let kinds = self.vm.watches().filter(|(watched, _)| *watched == cell).map(|(_, kind)| kind).collect::<Vec<_>>();
+                    for kind in &kinds {
+                        self.vm.unwatch(cell, *kind);
+                    }
+                    match kinds.len() {
+                        0 => println!("No watchpoint on cell {}", cell),
+                        _ => println!("Deleted the watchpoints on cell {}", cell),
+                    }
+                }
+                Some(Err(_)) => println!("Usage: unwatch [CELL]"),
+            },
+            "l" | "list" => {
+                let breakpoints = self.vm.breakpoints().collect::<Vec<_>>();
+                let watches = self.vm.watches().collect::<Vec<_>>();
+                if breakpoints.is_empty() && watches.is_empty() {
+                    println!("No breakpoints or watchpoints");
+                }
+                for pc in breakpoints {
+                    println!("pc {}: {}", pc, self.describe(pc));
+                }
+                for (cell, kind) in watches {
+                    println!("cell {}: {}", cell, describe_watch(kind));
+                }
+            }
+            "s" | "step" => match arg.map_or(Ok(1), str::parse::<usize>) {
+                Ok(count) => {
+                    for _ in 0..count {
+                        if !self.run(RunTo::Step)? {
+                            break;
+                        }
+                    }
+                    self.show_where();
+                }
+                Err(_) => println!("Usage: step [N]"),
+            },
+            "c" | "continue" => {
+                self.run(RunTo::Continue)?;
+                self.show_where();
+            }
+            "o" | "output" => {
+                self.run(RunTo::Output)?;
+                self.show_where();
+            }
+            "t" | "tape" => match arg.map_or(Ok(TAPE_WINDOW), str::parse::<usize>) {
+                Ok(radius) => println!("{}", tape_window(&self.vm, radius)),
+                Err(_) => println!("Usage: tape [RADIUS]"),
+            },
+            "p" | "pointer" => {
+                let pointer = self.vm.pointer();
+                match self.vm.get_tape(pointer) {
+                    Some(value) => println!("pointer = {}, cell = {}", pointer, value),
+                    None => println!("pointer = {}, past the end of the tape", pointer),
+                }
+            }
+            "w" | "where" => self.show_where(),
+            "record" => match arg.map_or(Ok(JournalConfig::default().memory_cap >> 20), str::parse::<usize>) {
+                Ok(mib) => {
+                    self.vm.start_recording(JournalConfig { memory_cap: mib << 20, ..JournalConfig::default() });
+                    println!("Recording, keeping up to {} MiB of history", mib);
+                }
+                Err(_) => println!("Usage: record [MIB]"),
+            },
+            "rs" | "reverse-step" => match arg.map_or(Ok(1), str::parse::<usize>) {
+                Ok(_) if self.vm.journal().is_none() => println!("Not recording. Start with `record`"),
+                Ok(count) => {
+                    let went = (0..count).take_while(|_| reverse_step(&mut self.vm)).count();
+                    if went < count {
+                        println!("At the oldest recorded step");
+                    }
+                    self.ended &= went == 0;
+                    self.show_where();
+                }
+                Err(_) => println!("Usage: reverse-step [N]"),
+            },
+            "rc" | "reverse-continue" => {
+                if self.vm.journal().is_none() {
+                    println!("Not recording. Start with `record`");
+                } else {
+                    let at = self.vm.journal().map(|j| j.position());
+                    if self.vm.reverse_continue() {
+                        println!("Breakpoint, pc {}", self.vm.pc());
+                    } else {
+                        println!("At the oldest recorded step");
+                    }
+                    self.ended &= at == self.vm.journal().map(|j| j.position());
+                    self.show_where();
+                }
+            }
+            "last" => match arg.map(str::parse::<usize>) {
+                Some(Ok(cell)) => match (self.vm.last_change(cell), self.vm.journal()) {
+                    (Some(change), _) => println!("Cell {} changed from {} to {} at step {}, pc {} ({})", cell, change.old, change.new, change.step, change.pc, self.describe(change.pc)),
+                    (None, Some(journal)) => println!("Cell {} has not changed since step {}", cell, journal.oldest()),
+                    (None, None) => println!("Not recording. Start with `record`"),
+                },
+                _ => println!("Usage: last CELL"),
+            },
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            _ => println!("Unknown command `{}`. Type `help` for commands", command),
+        }
+        Ok(true)
+    }
+
+    /// Maps `LINE[:COL]` to the bytecode that runs first there.
+    fn parse_location(&self, arg: &str) -> Option<(Location, usize)> {
+        let (line, column) = match arg.split_once(':') {
+            Some((line, column)) => (line.parse().ok()?, column.parse().ok()?),
+            None => (arg.parse().ok()?, 1),
+        };
+        let pc = self.vm.pc_at_source(offset_of(self.code, Location { line, column })?)?;
+        let start = *self.vm.origins()[pc].source_range.as_ref()?.start();
+        Some((locate(self.code, start), pc))
+    }
+
+    fn describe(&self, pc: usize) -> String {
+        match &self.vm.origins()[pc].source_range {
+            Some(range) => {
+                let location = locate(self.code, *range.start());
+                format!("{}:{}", location.line, location.column)
+            }
+            None => "end of program".to_owned(),
+        }
+    }
+
+    fn show_where(&mut self) {
+        if self.mid_line {
+            println!();
+            self.mid_line = false;
+        }
+        if self.ended {
+            println!("The program has ended");
+            return;
+        }
+        if let Some(journal) = self.vm.journal() {
+            println!("step {} (recorded {}..={})", journal.position(), journal.oldest(), journal.newest());
+        }
+        match self.vm.source_range() {
+            Some(range) => print!("{}", render_snippet(self.code, &range)),
+            None => println!("pc {}: end of program", self.vm.pc()),
+        }
+    }
+
+    /// Runs until `to` is reached or something else stops the program. Returns `false` if it can't go on.
+    fn run(&mut self, to: RunTo) -> Result<bool, BrainrotError> {
+        if self.ended {
+            return Ok(false);
+        }
+        // 1 命令は同じソース範囲から出た bytecode の並び。ループで同じ pc に戻ってきたら別の回とみなす
+        let start: (usize, Option<RangeInclusive<usize>>) = (self.vm.pc(), self.vm.source_range());
+        loop {
+            let result = match to {
+                RunTo::Step => self.vm.step_bytecode(),
+                RunTo::Continue | RunTo::Output => self.vm.step(),
+            };
+            let stepped = match result {
+                Ok(BrainrotResult::Stepped) => true,
+                Ok(BrainrotResult::Output(value)) => {
+                    stdout().write_all(&[value])?;
+                    stdout().flush()?;
+                    self.mid_line = value != b'\n';
+                    if to == RunTo::Output {
+                        return Ok(true);
+                    }
+                    // `.` の後は Stepped の代わりに Output で止まる
+                    to == RunTo::Step
+                }
+                Ok(BrainrotResult::NeedInput) => {
+                    self.read_input()?;
+                    continue;
+                }
+                Ok(BrainrotResult::Breakpoint) => {
+                    self.stop(&format!("Breakpoint, pc {}", self.vm.pc()));
+                    return Ok(true);
+                }
+                Ok(BrainrotResult::Watchpoint { cell, old, new, pc }) => {
+                    let what = match old == new {
+                        true => format!("cell {} read", cell),
+                        false => format!("cell {} changed from {} to {}", cell, old, new),
+                    };
+                    self.stop(&format!("Watchpoint, {} by pc {} ({})", what, pc, self.describe(pc)));
+                    return Ok(true);
+                }
+                Ok(BrainrotResult::Interrupted) => {
+                    self.stop("Interrupted");
+                    return Ok(true);
+                }
+                Ok(BrainrotResult::End) => {
+                    self.ended = true;
+                    return Ok(false);
+                }
+                // io_break も fuel も使っていない
+                Ok(BrainrotResult::IoBreak | BrainrotResult::OutOfFuel) => continue,
+                Err(err) => {
+                    self.stop("");
+                    eprint!("{}", err.render(self.code));
+                    // 記録していれば、ここから戻って原因を探せる
+                    self.ended = self.vm.journal().is_none();
+                    if !self.ended {
+                        println!("Use `reverse-step` or `last` to find out how it got here");
+                    }
+                    return Ok(false);
+                }
+            };
+            if stepped && (self.vm.pc() == start.0 || self.vm.source_range() != start.1) {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn stop(&mut self, reason: &str) {
+        if self.mid_line {
+            println!();
+            self.mid_line = false;
+        }
+        if !reason.is_empty() {
+            println!("{}", reason);
+        }
+    }
+
+    fn read_input(&mut self) -> Result<(), BrainrotError> {
+        if !self.ask_input {
+            // 閉じた PushInput は NeedInput を返さない
+            return Ok(());
+        }
+        self.prompt("(input) ")?;
+        match read_line()? {
+            Some(line) => self.vm.feed_input(line.as_bytes()),
+            None => self.vm.close_input(),
+        }
+        Ok(())
+    }
+}
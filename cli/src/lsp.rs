@@ -0,0 +1,268 @@
+use core::{Cell, CompiledProgram, TapeMode, advance::{ir::{parse_to_ir, IROp}, range::{certain_oob, generate_range_info}}, error::BrainrotError};
+use std::{collections::HashMap, io::{stdin, stdout}, ops::RangeInclusive};
+
+use serde_json::{Value, json};
+
+use crate::dap::{read_message, write_message};
+
+pub struct LspConfig {
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+}
+
+const SEVERITY_ERROR: u8 = 1;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+pub fn serve<C: Cell>(config: LspConfig) -> Result<(), BrainrotError> {
+    let mut input = stdin().lock();
+    let mut out = stdout().lock();
+    let mut documents = HashMap::<String, String>::new();
+
+    while let Some(message) = read_message(&mut input)? {
+        let Some(method) = message["method"].as_str() else {
+            // クライアントからのレスポンスは使わない
+            continue;
+        };
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        if message.get("id").is_none() {
+            let changed = match method {
+                "textDocument/didOpen" => {
+                    documents.insert(uri.to_owned(), params["textDocument"]["text"].as_str().unwrap_or_default().to_owned());
+                    true
+                }
+                // 同期は全文なので最後の変更だけ見ればよい
+                "textDocument/didChange" => match params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    Some(change) => {
+                        documents.insert(uri.to_owned(), change["text"].as_str().unwrap_or_default().to_owned());
+                        true
+                    }
+                    None => false,
+                },
+                "textDocument/didClose" => {
+                    documents.remove(uri);
+                    true
+                }
+                "exit" => break,
+                _ => false,
+            };
+            if changed {
+                let diagnostics = documents.get(uri).map_or(vec![], |code| diagnostics(code, &config));
+                write_message(&mut out, &json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": diagnostics },
+                }))?;
+            }
+            continue;
+        }
+
+        let document = documents.get(uri).zip(offset_at_position(documents.get(uri), &params["position"]));
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": { "name": "brainrot" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => Ok(document.and_then(|(code, offset)| hover::<C>(code, offset, &config)).unwrap_or(Value::Null)),
+            "textDocument/definition" => Ok(document.and_then(|(code, offset)| {
+                let at = matching_bracket(code, offset)?;
+                Some(json!({ "uri": uri, "range": range(code, &(at..=at)) }))
+            }).unwrap_or(Value::Null)),
+            method => Err(format!("Unsupported method `{}`", method)),
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": message["id"], "error": { "code": METHOD_NOT_FOUND, "message": error } }),
+        };
+        write_message(&mut out, &response)?;
+    }
+    Ok(())
+}
+
+/// Unmatched brackets, and accesses that are out of bounds on every run.
+fn diagnostics(code: &str, config: &LspConfig) -> Vec<Value> {
+    let diagnostic = |at: &RangeInclusive<usize>, message: String| json!({
+        "range": range(code, at),
+        "severity": SEVERITY_ERROR,
+        "source": "brainrot",
+        "message": message,
+    });
+
+    let ir = match parse_to_ir(code) {
+        Ok(ir) => ir,
+        Err(err) => {
+            let err = BrainrotError::from(err);
+            return vec![diagnostic(&err.source_range().unwrap_or(0..=0), err.to_string())];
+        }
+    };
+    match generate_range_info(&ir, config.tape_length, config.tape_mode) {
+        Err(err) => vec![diagnostic(&(0..=0), BrainrotError::from(err).to_string())],
+        // 最初の区間がテープに収まっているなら、確実に範囲外になる場所もない
+        Ok(range) if range.do_opt_first => vec![],
+        Ok(_) => certain_oob(&ir, config.tape_length, config.tape_mode).into_iter().map(|(i, cell)| {
+            let at = ir[i].source_range.clone().unwrap_or(0..=0);
+            diagnostic(&at, format!("Always out of bounds: the pointer is at cell {} here, and the tape has {} cells", cell, config.tape_length))
+        }).collect(),
+    }
+}
+
+/// Which `IROp` the code under the cursor became, and the bytecodes that came from it.
+fn hover<C: Cell>(code: &str, offset: usize, config: &LspConfig) -> Option<Value> {
+    let compiled = CompiledProgram::<C>::new(code, config.tape_length, config.tape_mode).ok()?;
+    // 畳み込まれたループは中身ごと 1 つの IR になるので、一番狭いものを選ぶ
+    let (index, node) = compiled.ir().iter().enumerate()
+        .filter(|(_, node)| node.opcode != IROp::End && node.source_range.as_ref().is_some_and(|r| r.contains(&offset)))
+        .min_by_key(|(_, node)| node.source_range.as_ref().map(|r| r.end() - r.start()))?;
+
+    let mut text = match node.opcode {
+        IROp::Set(_) | IROp::Shift(_) | IROp::MulAndSetZero(_) | IROp::MovesAndSetZero(_) if code[*node.source_range.as_ref()?.start()..].starts_with('[') => {
+            format!("This loop became `{}`", node)
+        }
+        _ => format!("`{}`", node),
+    };
+    let bytecodes = compiled.origins().iter().enumerate()
+        .filter(|(_, origin)| origin.ir.contains(&index))
+        .map(|(pc, _)| format!("{}: {:?}", pc, compiled.verified().insts()[pc]))
+        .collect::<Vec<_>>();
+    if !bytecodes.is_empty() {
+        text += &format!("\n\nBytecode:\n```\n{}\n```", bytecodes.join("\n"));
+    }
+
+    Some(json!({
+        "contents": { "kind": "markdown", "value": text },
+        "range": range(code, node.source_range.as_ref()?),
+    }))
+}
+
+/// The bracket matching the one at `offset`, or the one just before it, since the cursor sits between chars.
+fn matching_bracket(code: &str, offset: usize) -> Option<usize> {
+    let bytes = code.as_bytes();
+    let offset = [Some(offset), offset.checked_sub(1)].into_iter().flatten().find(|&i| matches!(bytes.get(i), Some(b'[' | b']')))?;
+    let forward = bytes[offset] == b'[';
+
+    let mut depth = 0usize;
+    let mut i = offset;
+    loop {
+        match (bytes[i], forward) {
+            (b'[', true) | (b']', false) => depth += 1,
+            (b']', true) | (b'[', false) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i = if forward { i + 1 } else { i.checked_sub(1)? };
+        if i >= bytes.len() {
+            return None;
+        }
+    }
+}
+
+/// LSP の位置は 0 始まりの行と、UTF-16 単位の列
+fn position(code: &str, offset: usize) -> Value {
+    let before = &code[..offset.min(code.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({ "line": before.matches('\n').count(), "character": before[line_start..].encode_utf16().count() })
+}
+
+fn range(code: &str, at: &RangeInclusive<usize>) -> Value {
+    json!({ "start": position(code, *at.start()), "end": position(code, at.end() + 1) })
+}
+
+fn offset_at_position(code: Option<&String>, position: &Value) -> Option<usize> {
+    let code = code?;
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        line => code.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (i, c) in code[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(code.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(tape_mode: TapeMode) -> LspConfig {
+        LspConfig { tape_length: 30000, tape_mode }
+    }
+
+    fn start(diagnostic: &Value) -> (u64, u64) {
+        let start = &diagnostic["range"]["start"];
+        (start["line"].as_u64().unwrap(), start["character"].as_u64().unwrap())
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let opening = diagnostics("+\n+[>+", &config(TapeMode::Fixed));
+        assert_eq!(opening.len(), 1);
+        assert_eq!(start(&opening[0]), (1, 1));
+        assert!(opening[0]["message"].as_str().unwrap().contains("Unmatched opening bracket"));
+
+        let closing = diagnostics("+[-]-]", &config(TapeMode::Fixed));
+        assert_eq!(closing.len(), 1);
+        assert_eq!(start(&closing[0]), (0, 5));
+        assert!(closing[0]["message"].as_str().unwrap().contains("Unmatched closing bracket"));
+    }
+
+    #[test]
+    fn left_of_the_tape() {
+        let fixed = diagnostics("+>+<<+", &config(TapeMode::Fixed));
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(start(&fixed[0]), (0, 5));
+        assert!(fixed[0]["message"].as_str().unwrap().contains("cell -1"));
+
+        // 折り返すテープなら範囲外にはならない
+        assert!(diagnostics("+>+<<+", &config(TapeMode::Wrapping)).is_empty());
+        assert!(diagnostics("+>+<+", &config(TapeMode::Fixed)).is_empty());
+    }
+
+    #[test]
+    fn matches_brackets_around_the_cursor() {
+        let code = "+[>[-]<]";
+        // カーソルが括弧の前でも後でも対応する括弧を返す
+        assert_eq!(matching_bracket(code, 1), Some(7));
+        assert_eq!(matching_bracket(code, 2), Some(7));
+        assert_eq!(matching_bracket(code, 7), Some(1));
+        assert_eq!(matching_bracket(code, 8), Some(1));
+        assert_eq!(matching_bracket(code, 3), Some(5));
+        assert_eq!(matching_bracket(code, 5), Some(3));
+        assert_eq!(matching_bracket(code, 0), None);
+
+        assert_eq!(matching_bracket("+[[-]", 1), None);
+        assert_eq!(matching_bracket("+[-]]", 4), None);
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        let code = "+ コメント 🧠\n>[-]";
+        let at = |line: u64, character: u64| offset_at_position(Some(&code.to_owned()), &json!({ "line": line, "character": character }));
+
+        let brain = code.find('🧠').unwrap();
+        assert_eq!(at(0, 7), Some(brain));
+        // サロゲートペアは 2 単位
+        assert_eq!(at(0, 9), Some(brain + '🧠'.len_utf8()));
+        assert_eq!(at(0, 99), Some(brain + '🧠'.len_utf8()));
+        assert_eq!(at(1, 1), Some(code.find('[').unwrap()));
+        assert_eq!(at(2, 0), None);
+
+        assert_eq!(position(code, brain + '🧠'.len_utf8()), json!({ "line": 0, "character": 9 }));
+        assert_eq!(position(code, code.find('[').unwrap()), json!({ "line": 1, "character": 1 }));
+    }
+}
@@ -1,45 +1,274 @@
-use core::{Brainrot, BrainrotInit, error::{BrainrotError, RuntimeError}};
-use std::{fs, io::{Read, Write, stdin, stdout}, process::ExitCode};
+use core::{Brainrot, BrainrotResult, Cell, CodegenInit, CompiledProgram, DEFAULT_TAPE_LENGTH, EofPolicy, ExecutionInit, TapeMode, advance::format::image_cell_bits, compile_to_c, compile_to_rust, compile_to_wasm, error::{BrainrotError, RuntimeError}, io::{InputSource, OutputSink, ReadInput, WriteOutput}};
+use std::{fs, io::{self, Read, StdinLock, Write, stdin, stdout}, num::NonZeroUsize, path::Path, process::{self, ExitCode}, sync::{Arc, atomic::{AtomicBool, Ordering}}};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
+
+use crate::{bench::{BenchConfig, benchmark}, dap::DapConfig, debug::{DebugConfig, debug}, lsp::LspConfig};
+
+mod bench;
+mod dap;
+mod debug;
+mod lsp;
 
 #[derive(Parser, Debug)]
-#[command(name = "brainrot")]
+#[command(name = "brainrot", args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(value_name = "FILE")]
-    file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run FILE, which is either Brainfuck source or a .bfc file from `compile`. The same as giving no subcommand
+    Run(RunArgs),
+    /// Compile FILE ahead of time instead of running it
+    Compile(CompileArgs),
+    /// Step through FILE interactively, with breakpoints by line and column. Each `#` in FILE is a breakpoint
+    Debug(DebugArgs),
+    /// Serve the Debug Adapter Protocol over stdin and stdout, for editors. The program comes from the `launch` request
+    Dap(DapArgs),
+    /// Serve the Language Server Protocol over stdin and stdout: diagnostics, bracket matching and what each part was optimized into
+    Lsp(LspArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Brainfuck source, or a .bfc file. A .bfc file keeps the tape length, tape mode and cell width it was compiled with
+    #[arg(value_name = "FILE", required = true)]
+    file: Option<String>,
 
     #[arg(short, long)]
     flush: bool,
 
     #[arg(short, long)]
     dump: Option<String>,
+
+    #[arg(long, value_name = "N")]
+    benchmark_count: Option<NonZeroUsize>,
+
+    /// Stop with exit code 3 after this much fuel. Each loop iteration costs the length of its body in bytecodes, each scan step costs 1
+    #[arg(long, value_name = "N")]
+    max_steps: Option<usize>,
+
+    #[command(flatten)]
+    tape: TapeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
+    #[arg(value_name = "FILE")]
+    file: String,
+
+    /// Defaults to the one matching the extension of --output
+    #[arg(long, value_enum)]
+    target: Option<Target>,
+
+    /// Write to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    #[command(flatten)]
+    tape: TapeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct DebugArgs {
+    /// Brainfuck source
+    #[arg(value_name = "FILE")]
+    file: String,
+
+    /// Feed this file to `,` instead of asking for a line whenever the program reads
+    #[arg(long, value_name = "FILE")]
+    input: Option<String>,
+
+    #[command(flatten)]
+    tape: TapeArgs,
 }
 
-fn resulty_main(args: Args) -> Result<(), BrainrotError> {
-    let code = fs::read_to_string(args.file)?;
-    
-    let mut stdin = stdin().lock();
-    let mut stdout = stdout().lock();
-    let mut stdin_buf = [0u8; 1];
+#[derive(clap::Args, Debug)]
+struct DapArgs {
+    #[command(flatten)]
+    tape: TapeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct LspArgs {
+    #[command(flatten)]
+    tape: TapeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct TapeArgs {
+    #[arg(long, value_enum, default_value_t = Eof::Zero)]
+    eof: Eof,
+
+    #[arg(long, value_enum, default_value_t = CellWidth::U8)]
+    cell_width: CellWidth,
+
+    #[arg(long, default_value_t = DEFAULT_TAPE_LENGTH)]
+    tape_length: usize,
+
+    #[arg(long, value_enum, default_value_t = Mode::Fixed)]
+    tape_mode: Mode,
+}
+impl From<&TapeArgs> for CodegenInit {
+    fn from(value: &TapeArgs) -> Self {
+        CodegenInit {
+            eof: value.eof.into(),
+            tape_length: value.tape_length,
+            tape_mode: value.tape_mode.into(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Target {
+    C,
+    Rust,
+    Wasm,
+    /// Bytecode for `brainrot run`, which skips parsing and optimizing
+    Bfc,
+}
+impl Target {
+    fn from_path(path: &str) -> Option<Target> {
+        match Path::new(path).extension()?.to_str()? {
+            "c" => Some(Target::C),
+            "rs" => Some(Target::Rust),
+            "wasm" => Some(Target::Wasm),
+            "bfc" => Some(Target::Bfc),
+            _ => None,
+        }
+    }
+}
 
-    let mut vm = Brainrot::new(&code, BrainrotInit {
-        input: || {
-            match stdin.read_exact(&mut stdin_buf) {
-                Ok(_) => stdin_buf[0],
-                Err(_) => 0,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Mode {
+    Fixed,
+    Growable,
+    Wrapping,
+}
+impl From<Mode> for TapeMode {
+    fn from(value: Mode) -> Self {
+        match value {
+            Mode::Fixed => TapeMode::Fixed,
+            Mode::Growable => TapeMode::Growable,
+            Mode::Wrapping => TapeMode::Wrapping,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CellWidth {
+    #[value(name = "8")]
+    U8,
+    #[value(name = "16")]
+    U16,
+    #[value(name = "32")]
+    U32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Eof {
+    #[value(alias = "0")]
+    Zero,
+    #[value(alias = "-1", alias = "255")]
+    MinusOne,
+    Unchanged,
+}
+impl From<Eof> for EofPolicy {
+    fn from(value: Eof) -> Self {
+        match value {
+            Eof::Zero => EofPolicy::Zero,
+            Eof::MinusOne => EofPolicy::MinusOne,
+            Eof::Unchanged => EofPolicy::Unchanged,
+        }
+    }
+}
+
+fn run_main(args: RunArgs) -> Result<(), BrainrotError> {
+    let file = fs::read(args.file.as_deref().unwrap_or_default())?;
+
+    // .bfc はコンパイル時のセル幅でしか読めないので、ヘッダの値を優先する
+    let cell_width = match image_cell_bits(&file) {
+        Some(8) => CellWidth::U8,
+        Some(16) => CellWidth::U16,
+        Some(32) => CellWidth::U32,
+        _ => args.tape.cell_width,
+    };
+    match cell_width {
+        CellWidth::U8 => resulty_main::<u8>(args, file),
+        CellWidth::U16 => resulty_main::<u16>(args, file),
+        CellWidth::U32 => resulty_main::<u32>(args, file),
+    }
+}
+
+fn resulty_main<C: Cell>(args: RunArgs, file: Vec<u8>) -> Result<(), BrainrotError> {
+    let compiled = if image_cell_bits(&file).is_some() {
+        if args.benchmark_count.is_some() {
+            return Err(BrainrotError::FetureError("--benchmark-count needs Brainfuck source, not a .bfc file".to_owned()));
+        }
+        CompiledProgram::<C>::load(file.as_slice())?
+    } else {
+        let code = String::from_utf8(file).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+
+        if let Some(count) = args.benchmark_count {
+            return benchmark::<C>(&code, BenchConfig {
+                count,
+                eof: args.tape.eof.into(),
+                tape_length: args.tape.tape_length,
+                tape_mode: args.tape.tape_mode.into(),
+            });
+        }
+
+        CompiledProgram::<C>::new(&code, args.tape.tape_length, args.tape.tape_mode.into())?
+    };
+
+    let input = WatchedStdin { stdin: stdin().lock(), reading: Arc::new(AtomicBool::new(false)) };
+    let reading = input.reading.clone();
+    let mut vm = Brainrot::from_compiled(Arc::new(compiled), ExecutionInit {
+        input: ReadInput::new(input),
+        output: WriteOutput::new(stdout().lock()).flush_each(args.flush),
+        io_break: false,
+        timeout_step: args.max_steps,
+        eof: args.tape.eof.into(),
+    });
+    let interrupt = vm.interrupt_handle();
+    let pressed = AtomicBool::new(false);
+    // 失敗しても Ctrl-C で普通に終了するだけなので無視する
+    let _ = ctrlc::set_handler(move || {
+        // `,` で止まっている間は割り込みを見に来ないので、その場で終わる。2 回目の Ctrl-C も同じ
+        if reading.load(Ordering::SeqCst) || pressed.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+        interrupt.interrupt();
+    });
+
+    let err = loop {
+        match vm.step()? {
+            // `#` (debug feature のみ) は場所とテープを見せて続ける
+            BrainrotResult::Breakpoint => {
+                eprintln!("Breakpoint, pc {}, pointer {}", vm.pc(), vm.pointer());
+                eprintln!("{}", tape_window(&vm, TAPE_WINDOW));
             }
-        },
-        output: |v| {
-            let _ = stdout.write_all(&[v]);
-            if args.flush {
-                let _ = stdout.flush();
+            BrainrotResult::OutOfFuel => break Some(RuntimeError::TimeoutError),
+            BrainrotResult::Interrupted => {
+                eprintln!("{}", tape_window(&vm, TAPE_WINDOW));
+                break Some(RuntimeError::Interrupted);
             }
-        },
-        io_break: false,
-        timeout_step: None,
-    })?;
-    vm.step()?;
+            _ => break None,
+        }
+    };
+    if let Some(err) = err {
+        return Err(BrainrotError::RuntimeError {
+            err,
+            pc: vm.pc(),
+            pointer: vm.pointer(),
+            source_range: vm.source_range(),
+        });
+    }
 
     if let Some(dump) = args.dump {
         fs::write(&dump, vm.generate_trace())?;
@@ -48,19 +277,157 @@ fn resulty_main(args: Args) -> Result<(), BrainrotError> {
     Ok(())
 }
 
+/// Stdin that tells the Ctrl-C handler when a read is blocking, since the interrupt flag is only polled at back-edges and scans.
+struct WatchedStdin {
+    stdin: StdinLock<'static>,
+    reading: Arc<AtomicBool>,
+}
+impl Read for WatchedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reading.store(true, Ordering::SeqCst);
+        let result = self.stdin.read(buf);
+        self.reading.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+/// How many cells on each side of the pointer to show when interrupted.
+const TAPE_WINDOW: usize = 8;
+
+/// The cells around the pointer, with the current one in brackets.
+fn tape_window<I: InputSource, O: OutputSink, C: Cell>(vm: &Brainrot<I, O, C>, radius: usize) -> String {
+    let pointer = vm.pointer();
+    let start = pointer.saturating_sub(radius);
+    let cells = (start..=pointer.saturating_add(radius))
+        .map_while(|i| vm.get_tape(i).map(|v| if i == pointer { format!("[{}]", v) } else { v.to_string() }))
+        .collect::<Vec<_>>();
+    format!("TAPE {}..: {}", start, cells.join(" "))
+}
+
+fn compile_to_bfc<C: Cell>(code: &str, tape: &TapeArgs) -> Result<Vec<u8>, BrainrotError> {
+    let mut bytes = vec![];
+    CompiledProgram::<C>::new(code, tape.tape_length, tape.tape_mode.into())?.save(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn compile_main(args: CompileArgs, target: Target) -> Result<(), BrainrotError> {
+    let code = fs::read_to_string(&args.file)?;
+    let init = CodegenInit::from(&args.tape);
+
+    let output = match target {
+        Target::C => match args.tape.cell_width {
+            CellWidth::U8 => compile_to_c::<u8>(&code, &init)?,
+            CellWidth::U16 => compile_to_c::<u16>(&code, &init)?,
+            CellWidth::U32 => compile_to_c::<u32>(&code, &init)?,
+        }.into_bytes(),
+        Target::Rust => match args.tape.cell_width {
+            CellWidth::U8 => compile_to_rust::<u8>(&code, &init)?,
+            CellWidth::U16 => compile_to_rust::<u16>(&code, &init)?,
+            CellWidth::U32 => compile_to_rust::<u32>(&code, &init)?,
+        }.into_bytes(),
+        Target::Wasm => match args.tape.cell_width {
+            CellWidth::U8 => compile_to_wasm::<u8>(&code, &init)?,
+            CellWidth::U16 => compile_to_wasm::<u16>(&code, &init)?,
+            CellWidth::U32 => compile_to_wasm::<u32>(&code, &init)?,
+        },
+        Target::Bfc => match args.tape.cell_width {
+            CellWidth::U8 => compile_to_bfc::<u8>(&code, &args.tape)?,
+            CellWidth::U16 => compile_to_bfc::<u16>(&code, &args.tape)?,
+            CellWidth::U32 => compile_to_bfc::<u32>(&code, &args.tape)?,
+        },
+    };
+
+    match args.output {
+        Some(path) => fs::write(path, output)?,
+        None => stdout().write_all(&output)?,
+    }
+
+    Ok(())
+}
+
+fn debug_main(args: DebugArgs) -> Result<(), BrainrotError> {
+    let file = fs::read(&args.file)?;
+    if image_cell_bits(&file).is_some() {
+        return Err(BrainrotError::FetureError("debug needs Brainfuck source, not a .bfc file".to_owned()));
+    }
+    let code = String::from_utf8(file).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+
+    let config = DebugConfig {
+        eof: args.tape.eof.into(),
+        tape_length: args.tape.tape_length,
+        tape_mode: args.tape.tape_mode.into(),
+        input: args.input.map(fs::read).transpose()?,
+    };
+    match args.tape.cell_width {
+        CellWidth::U8 => debug::<u8>(&code, config),
+        CellWidth::U16 => debug::<u16>(&code, config),
+        CellWidth::U32 => debug::<u32>(&code, config),
+    }
+}
+
+fn dap_main(args: DapArgs) -> Result<(), BrainrotError> {
+    let config = DapConfig {
+        eof: args.tape.eof.into(),
+        tape_length: args.tape.tape_length,
+        tape_mode: args.tape.tape_mode.into(),
+    };
+    match args.tape.cell_width {
+        CellWidth::U8 => dap::serve::<u8>(config),
+        CellWidth::U16 => dap::serve::<u16>(config),
+        CellWidth::U32 => dap::serve::<u32>(config),
+    }
+}
+
+fn lsp_main(args: LspArgs) -> Result<(), BrainrotError> {
+    let config = LspConfig {
+        tape_length: args.tape.tape_length,
+        tape_mode: args.tape.tape_mode.into(),
+    };
+    match args.tape.cell_width {
+        CellWidth::U8 => lsp::serve::<u8>(config),
+        CellWidth::U16 => lsp::serve::<u16>(config),
+        CellWidth::U32 => lsp::serve::<u32>(config),
+    }
+}
+
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    match resulty_main(args) {
+    let file = match &args.command {
+        Some(Command::Compile(compile)) => compile.file.clone(),
+        Some(Command::Run(run)) => run.file.clone().unwrap_or_default(),
+        Some(Command::Debug(debug)) => debug.file.clone(),
+        Some(Command::Dap(_) | Command::Lsp(_)) => String::new(),
+        None => args.run.file.clone().unwrap_or_default(),
+    };
+
+    let result = match args.command {
+        Some(Command::Compile(compile)) => {
+            let Some(target) = compile.target.or_else(|| compile.output.as_deref().and_then(Target::from_path)) else {
+                Args::command().error(ErrorKind::MissingRequiredArgument, "--target is required unless --output ends in .c, .rs, .wasm or .bfc").exit();
+            };
+            compile_main(compile, target)
+        }
+        Some(Command::Run(run)) => run_main(run),
+        Some(Command::Debug(debug)) => debug_main(debug),
+        Some(Command::Dap(dap)) => dap_main(dap),
+        Some(Command::Lsp(lsp)) => lsp_main(lsp),
+        None => run_main(args.run),
+    };
+
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             if cfg!(feature = "debug") {
                 eprintln!("{err:?}");
+            } else if let Ok(code) = fs::read_to_string(&file) && image_cell_bits(code.as_bytes()).is_none() {
+                eprint!("{}", err.render(&code));
             } else {
                 eprintln!("{err}");
             }
             match err {
                 BrainrotError::RuntimeError { err: RuntimeError::TimeoutError, .. } => ExitCode::from(3),
+                BrainrotError::RuntimeError { err: RuntimeError::Interrupted, .. } => ExitCode::from(130),
                 | BrainrotError::RuntimeError { err: RuntimeError::OOBGet(..), .. }
                 | BrainrotError::RuntimeError { err: RuntimeError::OOBSet(..), .. }
                 | BrainrotError::RuntimeError { err: RuntimeError::OOBAdd(..), .. }
@@ -0,0 +1,141 @@
+use std::{fs, io::{BufRead, BufReader, Read, Write}, process::{Child, ChildStdin, Command, Stdio}, sync::mpsc::{self, Receiver}, thread, time::Duration};
+
+use serde_json::{Value, json};
+
+/// A `cli dap` process, spoken to over its stdin and stdout.
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Value>,
+    seq: u64,
+}
+impl Client {
+    fn spawn() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cli")).arg("dap")
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn().unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        // 応答が来ないときに固まらないよう、読み込みは別スレッドで行う
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                let mut length = None;
+                loop {
+                    let mut line = String::new();
+                    if stdout.read_line(&mut line).unwrap() == 0 {
+                        return;
+                    }
+                    match line.trim_end().split_once(':') {
+                        Some(("Content-Length", value)) => length = value.trim().parse::<usize>().ok(),
+                        None if line.trim_end().is_empty() => break,
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length.unwrap()];
+                stdout.read_exact(&mut body).unwrap();
+                if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                    return;
+                }
+            }
+        });
+        Client { child, stdin, messages, seq: 0 }
+    }
+
+    fn send(&mut self, command: &str, arguments: Value) -> u64 {
+        self.seq += 1;
+        let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments }).to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        self.seq
+    }
+
+    /// Skips messages until one matches, such as the `output` events in between.
+    fn until(&self, what: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let message = self.messages.recv_timeout(Duration::from_secs(10)).unwrap_or_else(|_| panic!("no {what}"));
+            if matches(&message) {
+                return message;
+            }
+        }
+    }
+
+    /// Sends a request and returns its response, which has to be successful.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let response = self.response(command, arguments);
+        assert_eq!(response["success"], true, "{command}: {response}");
+        response["body"].clone()
+    }
+
+    fn response(&mut self, command: &str, arguments: Value) -> Value {
+        let seq = self.send(command, arguments);
+        self.until(command, |message| message["type"] == "response" && message["request_seq"] == seq)
+    }
+
+    fn event(&self, event: &str) -> Value {
+        self.until(event, |message| message["type"] == "event" && message["event"] == event)["body"].clone()
+    }
+
+    fn evaluate(&mut self, expression: &str) -> Value {
+        self.request("evaluate", json!({ "expression": expression, "frameId": 1 }))["result"].clone()
+    }
+
+    /// Line and column of the only frame.
+    fn location(&mut self) -> (Value, Value) {
+        let body = self.request("stackTrace", json!({ "threadId": 1 }));
+        let frame = &body["stackFrames"][0];
+        (frame["line"].clone(), frame["column"].clone())
+    }
+}
+
+#[test]
+fn debugs_a_fault() {
+    // 3 行目の `-` がテープの左端を越える
+    let path = std::env::temp_dir().join(format!("brainrot-dap-{}.bf", std::process::id()));
+    fs::write(&path, "+++.\n>++.\n<<-\n").unwrap();
+    let mut client = Client::spawn();
+
+    let capabilities = client.request("initialize", json!({ "adapterID": "brainrot", "linesStartAt1": true, "columnsStartAt1": true }));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.event("initialized");
+    client.request("launch", json!({ "program": path }));
+
+    let body = client.request("setBreakpoints", json!({
+        "source": { "path": path },
+        "breakpoints": [{ "line": 2, "column": 1 }, { "line": 9 }],
+    }));
+    // `>` は次の `++` にまとめられている
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+    assert_eq!(body["breakpoints"][0]["line"], 2);
+    assert_eq!(body["breakpoints"][0]["column"], 2);
+    assert_eq!(body["breakpoints"][1]["verified"], false);
+
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(client.location(), (json!(2), json!(2)));
+    assert_eq!(client.evaluate("tape[0]"), "3");
+    assert_eq!(client.evaluate("pointer"), "0");
+    assert_eq!(client.evaluate("pc"), "2");
+    assert_eq!(client.response("evaluate", json!({ "expression": "bogus" }))["success"], false);
+
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(client.location(), (json!(2), json!(4)));
+    assert_eq!(client.evaluate("pointer"), "1");
+    assert_eq!(client.evaluate("tape[1]"), "2");
+
+    client.request("continue", json!({ "threadId": 1 }));
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["reason"], "exception");
+    assert!(stopped["text"].as_str().unwrap().contains("Out of bounds"), "{stopped}");
+    assert_eq!(client.location(), (json!(3), json!(3)));
+    assert_eq!(client.request("exceptionInfo", json!({ "threadId": 1 }))["exceptionId"], "OOBAdd");
+
+    // 範囲外アクセスの先へは進めず、run と同じ終了コードで終わる
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("exited")["exitCode"], 2);
+    client.event("terminated");
+    client.request("disconnect", json!({}));
+    assert!(client.child.wait().unwrap().success());
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,32 @@
+#![cfg(unix)]
+
+use std::{fs, process::{Command, Stdio}, thread, time::Duration};
+
+/// Sends SIGINT once the program has had time to start, and returns its exit code.
+fn interrupt(code: &str, name: &str) -> Option<i32> {
+    let path = std::env::temp_dir().join(format!("brainrot-{}-{}.bf", name, std::process::id()));
+    fs::write(&path, code).unwrap();
+    // stdin は開いたままにして `,` を待たせる
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cli")).arg(&path)
+        .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null())
+        .spawn().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    // wait は stdin を閉じてしまうので、終わるまで持っておく
+    let stdin = child.stdin.take();
+    let code = child.wait().unwrap().code();
+    drop(stdin);
+    fs::remove_file(&path).unwrap();
+    code
+}
+
+#[test]
+fn interrupts_a_loop() {
+    assert_eq!(interrupt("+[>+<]", "loop"), Some(130));
+}
+
+#[test]
+fn interrupts_a_blocking_read() {
+    assert_eq!(interrupt(",[.,]", "read"), Some(130));
+}
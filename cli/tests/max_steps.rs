@@ -0,0 +1,24 @@
+use std::{fs, process::{Command, Stdio}};
+
+/// Runs `code` under `--max-steps` and returns its exit code.
+fn run(code: &str, name: &str, max_steps: usize) -> Option<i32> {
+    let path = std::env::temp_dir().join(format!("brainrot-{}-{}.bf", name, std::process::id()));
+    fs::write(&path, code).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_cli")).arg(&path).args(["--max-steps", &max_steps.to_string()])
+        .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+        .status().unwrap();
+    fs::remove_file(&path).unwrap();
+    status.code()
+}
+
+#[test]
+fn stops_an_infinite_loop() {
+    assert_eq!(run("+[]", "infinite", 1000), Some(3));
+    assert_eq!(run("+[>+<]", "infinite-body", 1000), Some(3));
+}
+
+#[test]
+fn stops_a_scan() {
+    assert_eq!(run("+>+>+>+<<<[>]", "scan", 3), Some(3));
+    assert_eq!(run("+>+>+>+<<<[>]", "scan-enough", 4), Some(0));
+}
@@ -1,65 +1,376 @@
-use crate::{bytecode::bytecode::ir_to_bytecodes, error::BrainrotError, ir::{ir::{IR, parse_to_ir}, range::{RangeInfo, generate_range_info}}, trace::{generate_bytecode_trace, generate_ir_trace}, vm::{program::Program, tape::Tape, tier::{BrainrotResult, internal::Tier, run}}};
+use std::{io::{Read, Write}, ops::RangeInclusive, sync::Arc};
 
-pub struct BrainrotInit<I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
-{
+use crate::{bytecode::bytecode::BytecodeOrigin, cell::Cell, compiled::CompiledProgram, error::BrainrotError, ir::ir::IR, trace::{OperationCountMap, generate_bytecode_trace, generate_ir_trace}, vm::{io::{InputSource, OutputSink, PushInput, ReadInput, WriteOutput}, journal::{CellChange, Journal, JournalConfig}, program::{EofPolicy, InterruptHandle, Program}, tape::{Tape, TapeMode}, tier::{BrainrotResult, internal::{InterpreterResult, Tier}, run}, watch::WatchKind}};
+
+/// `input` and `output` are an `InputSource` and an `OutputSink` for `Brainrot::new`, or a `Read` and a `Write` for `Brainrot::with_io`.
+pub struct BrainrotInit<I, O> {
+    pub input: I,
+    pub output: O,
+    pub io_break: bool,
+    pub timeout_step: Option<usize>,
+    pub eof: EofPolicy,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+}
+
+/// The per-run half of `BrainrotInit`, for `Brainrot::from_compiled`. The tape settings come from the `CompiledProgram`.
+pub struct ExecutionInit<I, O> {
     pub input: I,
     pub output: O,
     pub io_break: bool,
     pub timeout_step: Option<usize>,
+    pub eof: EofPolicy,
 }
 
-pub struct Brainrot<I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+/// A run of a `CompiledProgram`: the tape, pc, tier, counters and I/O.
+pub type Execution<I, O, C = u8> = Brainrot<I, O, C>;
+
+pub struct Brainrot<I, O, C = u8>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
 {
-    ir: Vec<IR>, range: RangeInfo,
+    compiled: Arc<CompiledProgram<C>>,
 
     tier: Tier,
-    tape: Tape,
-    program: Program<I, O>,
+    tape: Tape<C>,
+    program: Program<I, O, C>,
 }
 
-impl<I, O> Brainrot<I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+impl<I, O, C> Brainrot<I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
 {
-    pub fn new(code: &str, init: BrainrotInit<I, O>) -> Result<Brainrot<I, O>, BrainrotError> {
-        let ir = parse_to_ir(code)?;
-        let range = generate_range_info(&ir)?;
-        let bytecode = ir_to_bytecodes(&ir, &range)?;
-
-        let tier = if range.do_opt_first { Tier::Opt } else { Tier::Deopt };
+    pub fn new(code: &str, init: BrainrotInit<I, O>) -> Result<Brainrot<I, O, C>, BrainrotError> {
+        let compiled = CompiledProgram::new(code, init.tape_length, init.tape_mode)?;
+        Ok(Brainrot::from_compiled(Arc::new(compiled), ExecutionInit {
+            input: init.input,
+            output: init.output,
+            io_break: init.io_break,
+            timeout_step: init.timeout_step,
+            eof: init.eof,
+        }))
+    }
+    /// Starts a fresh run of an already compiled program, without parsing or lowering it again.
+    pub fn from_compiled(compiled: Arc<CompiledProgram<C>>, init: ExecutionInit<I, O>) -> Brainrot<I, O, C> {
+        let tier = if compiled.verified().opt_first() { Tier::Opt } else { Tier::Deopt };
 
-        Ok(Brainrot {
-            ir, range,
+        let mut program = Program::new(compiled.verified(), init.timeout_step, init.input, init.output, init.io_break, init.eof);
+        program.set_origins(compiled.origins().clone());
 
+        Brainrot {
             tier,
-            tape: Tape::new(),
-            program: Program::new(bytecode.into_boxed_slice(), init.timeout_step, init.input, init.output, init.io_break),
-        })
+            tape: Tape::new(compiled.tape_length(), compiled.tape_mode()),
+            program,
+
+            compiled,
+        }
+    }
+    pub fn compiled(&self) -> &Arc<CompiledProgram<C>> {
+        &self.compiled
     }
     pub fn step(&mut self) -> Result<BrainrotResult, BrainrotError> {
+        self.program.debug_mut().set_step_budget(None);
+        self.resume()
+    }
+    /// Runs a single bytecode in the deopt tier, then returns `BrainrotResult::Stepped` unless something else stopped it first.
+    pub fn step_bytecode(&mut self) -> Result<BrainrotResult, BrainrotError> {
+        self.program.debug_mut().set_step_budget(Some(1));
+        self.resume()
+    }
+    fn resume(&mut self) -> Result<BrainrotResult, BrainrotError> {
+        // 戻った後は今に追いつくまで記録をなぞる。入出力はやり直さない
+        while let Some(journal) = self.program.debug().journal() && !journal.is_live() {
+            let pc = self.program.pc();
+            if let Some(InterpreterResult::Watchpoint { cell, old, new, pc }) = self.program.check_watches(&self.tape) {
+                return Ok(BrainrotResult::Watchpoint { cell, old, new, pc });
+            }
+            match self.program.debug_mut().check(pc) {
+                Some(InterpreterResult::Breakpoint) => return Ok(BrainrotResult::Breakpoint),
+                Some(_) => return Ok(BrainrotResult::Stepped),
+                None => {}
+            }
+            self.program.debug_mut().watches_mut().before(&self.tape, pc);
+            let pc = self.program.debug_mut().journal_mut().unwrap().advance(&mut self.tape);
+            self.program.jump_abs(pc);
+        }
+        // deopt にはどの pc からでも落ちられる
+        if self.program.debug().forces_deopt() {
+            self.tier = Tier::Deopt;
+        }
         run(&mut self.tier, &mut self.tape, &mut self.program)
     }
-    pub fn get_tape(&self, pointer: usize) -> Option<&u8> {
+    /// Makes `step` stop with `BrainrotResult::Breakpoint` before the bytecode at `pc` runs.
+    /// While any breakpoint is set, the program only runs in the deopt tier.
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.program.debug_mut().breakpoints_mut().insert(pc);
+    }
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.program.debug_mut().breakpoints_mut().remove(&pc)
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.program.debug_mut().breakpoints_mut().clear();
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.program.debug().breakpoints().iter().copied()
+    }
+    /// Makes `step` stop with `BrainrotResult::Watchpoint` after a bytecode touches the cell at `index` the way `kind` says.
+    /// While any watchpoint is set, the program only runs in the deopt tier. Replaying the journal after going back stops on them too.
+    pub fn watch(&mut self, index: usize, kind: WatchKind<C>) {
+        self.program.debug_mut().watches_mut().insert(&self.tape, index, kind);
+    }
+    pub fn unwatch(&mut self, index: usize, kind: WatchKind<C>) -> bool {
+        self.program.debug_mut().watches_mut().remove(&self.tape, index, kind)
+    }
+    pub fn clear_watches(&mut self) {
+        self.program.debug_mut().watches_mut().clear();
+    }
+    /// The watched cells, as tape indices. They follow their cells when a Growable tape grows to the left.
+    pub fn watches(&self) -> impl Iterator<Item = (usize, WatchKind<C>)> + '_ {
+        self.program.debug().watches().iter(&self.tape)
+    }
+    /// Starts journaling every bytecode, so that the run can be stepped backwards. While recording, the program only runs in the deopt tier.
+    /// After going back, `step` replays the journal up to where the program was, without doing its input and output again.
+    pub fn start_recording(&mut self, config: JournalConfig) {
+        let pc = self.program.pc();
+        let journal = Journal::new(config, &mut self.tape, pc);
+        self.program.debug_mut().set_journal(Some(journal));
+    }
+    /// Drops the journal. If it was gone back in, the program carries on from there and the undone part is lost.
+    pub fn stop_recording(&mut self) {
+        self.tape.writes = None;
+        self.program.debug_mut().set_journal(None);
+    }
+    pub fn journal(&self) -> Option<&Journal<C>> {
+        self.program.debug().journal()
+    }
+    /// Goes back one bytecode. Returns `false` if there is nothing to go back to.
+    pub fn reverse_step_bytecode(&mut self) -> bool {
+        let Some(journal) = self.journal() else { return false };
+        if journal.position() == journal.oldest() {
+            return false;
+        }
+        self.seek(journal.position() - 1);
+        true
+    }
+    /// Goes back to the last time the program stopped before a breakpoint. If there was none, goes back to the oldest step and returns `false`.
+    pub fn reverse_continue(&mut self) -> bool {
+        let Some(journal) = self.journal() else { return false };
+        let breakpoints = self.program.debug().breakpoints();
+        let target = (journal.oldest()..journal.position()).rev().find(|&step| breakpoints.contains(&journal.pc_at(step)));
+        self.seek(target.unwrap_or(journal.oldest()));
+        target.is_some()
+    }
+    fn seek(&mut self, step: u64) {
+        let debug = self.program.debug_mut();
+        let pc = debug.journal_mut().unwrap().seek(step, &mut self.tape);
+        // 止まった位置のブレークポイントで続きがまた止まらないように
+        debug.resume_at(pc);
+        debug.watches_mut().forget();
+        self.program.jump_abs(pc);
+        // 記録は bytecode の切れ目にしかないので、途中で止まったスキャンは最初からやり直す
+        self.program.fuel().resume_scan();
+    }
+    /// When the cell at `index` last took a new value before the current step, as far back as the journal goes.
+    pub fn last_change(&self, index: usize) -> Option<CellChange<C>> {
+        self.journal()?.last_change(&self.tape, index)
+    }
+    /// The first bytecode whose source range covers the byte offset `offset`, or failing that, the one starting closest after it.
+    pub fn pc_at_source(&self, offset: usize) -> Option<usize> {
+        let ranges = || self.program.origins().iter().enumerate().filter_map(|(pc, origin)| Some((pc, origin.source_range.clone()?)));
+        ranges().find(|(_, range)| range.contains(&offset))
+            .or_else(|| ranges().filter(|(_, range)| *range.start() > offset).min_by_key(|(pc, range)| (*range.start(), *pc)))
+            .map(|(pc, _)| pc)
+    }
+    pub fn pc(&self) -> usize {
+        self.program.pc()
+    }
+    pub fn pointer(&self) -> usize {
+        self.tape.data_pointer
+    }
+    /// Source range of the bytecode that runs next.
+    pub fn source_range(&self) -> Option<RangeInclusive<usize>> {
+        self.program.source_range(self.program.pc())
+    }
+    pub fn get_tape(&self, pointer: usize) -> Option<&C> {
         self.tape.buffer.get(pointer)
     }
-    pub fn get_tape_mut(&mut self, pointer: usize) -> Option<&mut u8> {
+    pub fn get_tape_mut(&mut self, pointer: usize) -> Option<&mut C> {
         self.tape.buffer.get_mut(pointer)
     }
+    /// How many cells the tape has right now. A growable tape gets longer as the program writes past its end.
+    pub fn tape_len(&self) -> usize {
+        self.tape.buffer.len()
+    }
+    /// Sets the remaining fuel, `None` for no limit. After `BrainrotResult::OutOfFuel`, top up and call `step` again to resume.
     pub fn set_timeout(&mut self, value: Option<usize>) {
-        self.program.step_remains = value;
+        self.program.step_remains.set(value);
+    }
+    /// A `Send + Sync` handle that makes `step` return `BrainrotResult::Interrupted` at the next loop back-edge.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.program.fuel().interrupt_handle()
+    }
+    pub fn remaining_fuel(&self) -> Option<usize> {
+        self.program.step_remains.get()
+    }
+    pub fn ir(&self) -> &[IR] {
+        self.compiled.ir()
+    }
+    /// Which IR nodes and source range each bytecode came from, indexed by pc.
+    pub fn origins(&self) -> &[BytecodeOrigin] {
+        self.program.origins()
+    }
+    /// Execution counts per bytecode, indexed by pc. Map them back to the source with `origins`.
+    pub fn operation_counts(&self) -> &OperationCountMap {
+        &self.program.ocm
     }
     pub fn generate_trace(&self) -> String {
         let mut trace = String::new();
 
         trace += "IR:\n";
-        trace += &generate_ir_trace(&self.ir, &self.range);
+        trace += &generate_ir_trace(self.compiled.ir(), self.compiled.range_info());
         trace += "\nBytecode:\n";
         trace += &generate_bytecode_trace(&self.program);
 
         return trace;
     }
 }
+impl<O, C> Brainrot<PushInput, O, C>
+where O: OutputSink,
+      C: Cell,
+{
+    /// Queues input for `,`. Call `step` again after `BrainrotResult::NeedInput`.
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.program.input_source().feed(bytes);
+    }
+    /// Marks the end of input, so that `,` sees EOF once the queued bytes run out.
+    pub fn close_input(&mut self) {
+        self.program.input_source().close();
+    }
+}
+impl<R, W, C> Brainrot<ReadInput<R>, WriteOutput<W>, C>
+where R: Read,
+      W: Write,
+      C: Cell,
+{
+    /// Like `new`, but reads from a `Read` and writes to a `Write`, both buffered.
+    /// I/O failures become `RuntimeError::IOError`, and output is flushed whenever `step` returns.
+    pub fn with_io(code: &str, init: BrainrotInit<R, W>) -> Result<Brainrot<ReadInput<R>, WriteOutput<W>, C>, BrainrotError> {
+        Brainrot::new(code, BrainrotInit {
+            input: ReadInput::new(init.input),
+            output: WriteOutput::new(init.output),
+            io_break: init.io_break,
+            timeout_step: init.timeout_step,
+            eof: init.eof,
+            tape_length: init.tape_length,
+            tape_mode: init.tape_mode,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{brainrot::{Brainrot, BrainrotInit}, cell::Cell, codegen::tests::PROGRAMS, vm::{io::{PullOutput, PushInput}, program::EofPolicy, tape::TapeMode, tier::BrainrotResult}};
+
+    /// Loops hot enough to be compiled by the JIT when there is no limit.
+    const HOT: &[&str] = &[
+        "++++++++++++[>++++++++++++[>++++++++++++[>+>++>---<<<-]<-]<-]>>>.>.>.",
+        "++++++++[>++++++++[>+++[->+>-<<]>[-<+>]>[->+>+>+<<<]<<<-]<-]>>>.>.>.>.>.",
+        "+[>+>+<]",
+    ];
+
+    /// Scans long enough to run out of fuel midway.
+    const SCANS: &[&str] = &[
+        "+>+>+>+>+>+>+>+>+<<<<<<<<[>]+.",
+        ">>+>+>+>+>+<<<<<[-]<<[>>>]+.",
+        "->>>-<<[<<]+.",
+    ];
+
+    /// Output, tape, pointer, and the message of a runtime error, running `slice` fuel at a time.
+    fn run<C: Cell>(code: &str, input: &[u8], tape_mode: TapeMode, slice: Option<usize>) -> (Vec<u8>, Vec<u32>, usize, Option<String>) {
+        let mut output = vec![];
+        let mut input = input.iter().copied();
+        let mut vm = Brainrot::<_, _, C>::new(code, BrainrotInit {
+            input: || input.next(),
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: slice,
+            eof: EofPolicy::Zero,
+            tape_length: 64,
+            tape_mode,
+        }).unwrap();
+        let error = loop {
+            match vm.step() {
+                Ok(BrainrotResult::End) => break None,
+                Ok(BrainrotResult::OutOfFuel) => vm.set_timeout(slice),
+                Ok(_) => panic!("unexpected result"),
+                Err(err) => break Some(err.to_string()),
+            }
+        };
+        let tape = (0..64).map(|i| vm.get_tape(i).unwrap().to_u32()).collect();
+        let pointer = vm.pointer();
+        drop(vm);
+        (output, tape, pointer, error)
+    }
+
+    fn check<C: Cell>(code: &str, input: &[u8]) {
+        for tape_mode in [TapeMode::Fixed, TapeMode::Wrapping] {
+            let expected = run::<C>(code, input, tape_mode, None);
+            for slice in [1, 2, 3, 7, 50] {
+                assert_eq!(run::<C>(code, input, tape_mode, Some(slice)), expected, "{code} {tape_mode:?} {slice}");
+            }
+        }
+    }
+
+    #[test]
+    fn resumes_after_out_of_fuel() {
+        for &(code, input) in PROGRAMS {
+            check::<u8>(code, input);
+            check::<u16>(code, input);
+        }
+        for code in HOT.iter().chain(SCANS) {
+            check::<u8>(code, b"");
+            check::<u16>(code, b"");
+        }
+    }
+
+    /// Output and tape, feeding `input` in pieces of `piece` bytes whenever `,` runs dry.
+    fn run_pushed(code: &str, input: &[u8], piece: usize) -> (Vec<u8>, Vec<u32>) {
+        let mut pieces = input.chunks(piece);
+        let mut output = vec![];
+        let mut vm = Brainrot::<_, _, u8>::new(code, BrainrotInit {
+            input: PushInput::default(),
+            output: PullOutput,
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+            tape_length: 64,
+            tape_mode: TapeMode::Wrapping,
+        }).unwrap();
+        loop {
+            match vm.step().unwrap() {
+                BrainrotResult::End => break,
+                BrainrotResult::Output(value) => output.push(value),
+                BrainrotResult::NeedInput => match pieces.next() {
+                    Some(bytes) => vm.feed_input(bytes),
+                    None => vm.close_input(),
+                },
+                _ => panic!("unexpected result"),
+            }
+        }
+        (output, (0..64).map(|i| vm.get_tape(i).unwrap().to_u32()).collect())
+    }
+
+    #[test]
+    fn push_and_pull_match_closures() {
+        let programs = PROGRAMS.iter().copied().chain([(",>,>,<<[.>]", &b"xyz"[..]), (",[>,]<[.<]", b"reverse me"), (HOT[0], b"")]);
+        for (code, input) in programs {
+            let (output, tape, _, error) = run::<u8>(code, input, TapeMode::Wrapping, None);
+            assert_eq!(error, None, "{code}");
+            for piece in [1, 2, 3, 64] {
+                assert_eq!(run_pushed(code, input, piece), (output.clone(), tape.clone()), "{code} {piece}");
+            }
+        }
+    }
+}
@@ -1,33 +1,33 @@
-use std::{fmt::Debug, ops::{Range, RangeFrom, RangeTo}};
+use std::{fmt::Debug, ops::{Range, RangeFrom, RangeInclusive, RangeTo}};
 
-use crate::{bytecode::error::OptimizationError, ir::{ir::{IR, IROp}, range::{MidRange, RangeInfo}}};
+use crate::{bytecode::error::OptimizationError, cell::Cell, ir::{ir::{IR, IROp}, range::{MidRange, RangeInfo}}};
 
 // メモ: jz ゼロ時ジャンプ jnz 非ゼロ時ジャンプ
 
 #[derive(Clone, Debug)]
-pub enum Bytecode {
+pub enum Bytecode<C> {
     Breakpoint { delta: i16 },
 
-    SingleAdd { delta: i16, val: u8 },
-    SingleSet { delta: i16, val: u8 },
-    AddAdd { delta1: i16, val1: u8, delta2: i16, val2: u8 },
-    AddSet { delta1: i16, val1: u8, delta2: i16, val2: u8 },
-    SetAdd { delta1: i16, val1: u8, delta2: i16, val2: u8 },
-    SetSet { delta1: i16, val1: u8, delta2: i16, val2: u8 },
+    SingleAdd { delta: i16, val: C },
+    SingleSet { delta: i16, val: C },
+    AddAdd { delta1: i16, val1: C, delta2: i16, val2: C },
+    AddSet { delta1: i16, val1: C, delta2: i16, val2: C },
+    SetAdd { delta1: i16, val1: C, delta2: i16, val2: C },
+    SetSet { delta1: i16, val1: C, delta2: i16, val2: C },
 
-    BothRangeCheck { range: Range<u16> },
+    BothRangeCheck { range: Range<u32> },
     Shift  { delta: i16, step: i16 },
-    ShiftN { delta: i16, step: i16, range: RangeFrom<u16> },
-    ShiftP { delta: i16, step: i16, range: RangeTo<u16> },
-    ShiftAdd  { delta1: i16, step: i8, delta2: i8, val: u8 },
-    ShiftAddN { delta1: i16, step: i8, delta2: i8, val: u8, range: RangeFrom<u16> },
-    ShiftAddP { delta1: i16, step: i8, delta2: i8, val: u8, range: RangeTo<u16> },
-    ShiftSet  { delta1: i16, step: i8, delta2: i8, val: u8 },
-    ShiftSetN { delta1: i16, step: i8, delta2: i8, val: u8, range: RangeFrom<u16> },
-    ShiftSetP { delta1: i16, step: i8, delta2: i8, val: u8, range: RangeTo<u16> },
+    ShiftN { delta: i16, step: i16, range: RangeFrom<u32> },
+    ShiftP { delta: i16, step: i16, range: RangeTo<u32> },
+    ShiftAdd  { delta1: i16, step: i8, delta2: i8, val: C },
+    ShiftAddN { delta1: i16, step: i8, delta2: i8, val: C, range: RangeFrom<u32> },
+    ShiftAddP { delta1: i16, step: i8, delta2: i8, val: C, range: RangeTo<u32> },
+    ShiftSet  { delta1: i16, step: i8, delta2: i8, val: C },
+    ShiftSetN { delta1: i16, step: i8, delta2: i8, val: C, range: RangeFrom<u32> },
+    ShiftSetP { delta1: i16, step: i8, delta2: i8, val: C, range: RangeTo<u32> },
 
     MulStart { delta: i16, jz_abs: u32 },
-    Mul { delta: i16, val: u8 },
+    Mul { delta: i16, val: C },
 
     SingleMoveAdd { delta: i16, to: i16 },
     SingleMoveSub { delta: i16, to: i16 },
@@ -46,25 +46,107 @@ pub enum Bytecode {
 
     JmpIfZero { delta: i16, addr_abs: u32 },
     JmpIfNotZero { delta: i16, addr_abs: u32 },
-    NegativeRangeCheckJNZ { delta: i16, addr_back: u16, range: RangeFrom<u16> },
-    PositiveRangeCheckJNZ { delta: i16, addr_back: u16, range: RangeTo<u16> },
-    BothRangeCheckJNZ { delta: i8, addr_back: u16, range: Range<u16> },
+    NegativeRangeCheckJNZ { delta: i16, addr_back: u16, range: RangeFrom<u32> },
+    PositiveRangeCheckJNZ { delta: i16, addr_back: u16, range: RangeTo<u32> },
+    BothRangeCheckJNZ { delta: i8, addr_back: u16, range: Range<u32> },
 
     End { delta: i16 },
 }
 
-pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<Bytecode>, OptimizationError> {
-    let mut bytecodes: Vec<Bytecode> = vec![];
+/// Where a bytecode was lowered from. Fused bytecodes cover several IR nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BytecodeOrigin {
+    pub ir: Range<usize>,
+    pub source_range: Option<RangeInclusive<usize>>,
+}
+impl BytecodeOrigin {
+    fn new(ir_nodes: &[IR], ir: Range<usize>) -> BytecodeOrigin {
+        let source_range = ir_nodes[ir.clone()].iter()
+            .filter_map(|node| node.source_range.clone())
+            .reduce(|a, b| (*a.start()).min(*b.start())..=(*a.end()).max(*b.end()));
+        BytecodeOrigin { ir, source_range }
+    }
+}
+
+/// A cell a bytecode may read or write. Offsets are from where the pointer is after the bytecode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(isize),
+    Write(isize),
+    /// Reads every `step`-th cell from `delta` past where the pointer was before, up to the zero cell at `end`.
+    Scan { delta: isize, step: isize, end: isize },
+}
+
+impl<C: Cell> Bytecode<C> {
+    /// Calls `f` with every cell this bytecode may touch. Some accesses only happen on one path, such as a move's writes.
+    /// A `Mul`, `MoveAdd` or `MoveSub` also depends on the cell its `MulStart` or `MoveStart` read.
+    pub fn accesses(&self, mut f: impl FnMut(Access)) {
+        match *self {
+            Bytecode::Breakpoint { .. } | Bytecode::BothRangeCheck { .. } | Bytecode::End { .. } => {}
+
+            Bytecode::SingleAdd { .. } | Bytecode::SingleSet { .. } | Bytecode::In { .. } => f(Access::Write(0)),
+            Bytecode::AddAdd { delta2, .. } | Bytecode::AddSet { delta2, .. } | Bytecode::SetAdd { delta2, .. } | Bytecode::SetSet { delta2, .. } => {
+                f(Access::Write(-(delta2 as isize)));
+                f(Access::Write(0));
+            }
+
+            Bytecode::Shift { delta, step } | Bytecode::ShiftN { delta, step, .. } | Bytecode::ShiftP { delta, step, .. } => {
+                f(Access::Scan { delta: delta as isize, step: step as isize, end: 0 });
+            }
+            Bytecode::ShiftAdd { delta1, step, delta2, .. } | Bytecode::ShiftAddN { delta1, step, delta2, .. } | Bytecode::ShiftAddP { delta1, step, delta2, .. }
+            | Bytecode::ShiftSet { delta1, step, delta2, .. } | Bytecode::ShiftSetN { delta1, step, delta2, .. } | Bytecode::ShiftSetP { delta1, step, delta2, .. } => {
+                f(Access::Scan { delta: delta1 as isize, step: step as isize, end: -(delta2 as isize) });
+                f(Access::Write(0));
+            }
+
+            Bytecode::MulStart { .. } | Bytecode::MoveStart { .. } => {
+                f(Access::Read(0));
+                f(Access::Write(0));
+            }
+            Bytecode::Mul { delta, .. } | Bytecode::MoveAdd { delta } | Bytecode::MoveSub { delta } => f(Access::Write(delta as isize)),
+
+            Bytecode::SingleMoveAdd { to, .. } | Bytecode::SingleMoveSub { to, .. } => {
+                f(Access::Read(0));
+                f(Access::Write(0));
+                f(Access::Write(to as isize));
+            }
+            Bytecode::DoubleMoveAddAdd { to1, to2, .. } | Bytecode::DoubleMoveAddSub { to1, to2, .. }
+            | Bytecode::DoubleMoveSubAdd { to1, to2, .. } | Bytecode::DoubleMoveSubSub { to1, to2, .. } => {
+                f(Access::Read(0));
+                f(Access::Write(to1 as isize));
+                f(Access::Write(to2 as isize));
+                f(Access::Write(0));
+            }
+
+            Bytecode::Out { .. } | Bytecode::JmpIfZero { .. } | Bytecode::JmpIfNotZero { .. }
+            | Bytecode::NegativeRangeCheckJNZ { .. } | Bytecode::PositiveRangeCheckJNZ { .. } | Bytecode::BothRangeCheckJNZ { .. } => f(Access::Read(0)),
+        }
+    }
+}
+
+pub fn ir_to_bytecodes<C: Cell>(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<Bytecode<C>>, OptimizationError> {
+    ir_to_bytecodes_with_origins(ir_nodes, range_info).map(|(bytecodes, _)| bytecodes)
+}
+
+/// Same as `ir_to_bytecodes`, but also returns a side table indexed by pc, telling which IR nodes and source each bytecode came from.
+pub fn ir_to_bytecodes_with_origins<C: Cell>(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<(Vec<Bytecode<C>>, Vec<BytecodeOrigin>), OptimizationError> {
+    let mut bytecodes: Vec<Bytecode<C>> = vec![];
+    let mut origins: Vec<BytecodeOrigin> = vec![];
     let mut loop_stack: Vec<usize> = vec![];
 
     let mut i = 0usize;
     let mut last_ptr = 0isize;
+    let mut consumed = 0usize;
 
     loop {
+        // 前回のループで消費した IR ノードを、その間に積んだバイトコードの出自として記録する
+        origins.resize(bytecodes.len(), BytecodeOrigin::new(ir_nodes, consumed..i));
+        consumed = i;
+
         match ir_nodes.get(i) {
             None => {
                 // Finalize?
-                return Ok(bytecodes);
+                return Ok((bytecodes, origins));
             }
             Some(node) => {
                 let delta = i16::try_from(node.pointer.wrapping_sub(last_ptr)).map_err(|e| OptimizationError::Delta(e))?;
@@ -78,19 +160,19 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
                             IR { opcode: IROp::Add(val2), pointer: ptr2, .. } => {
                                 let delta2 = i16::try_from(ptr2 - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                 last_ptr = ptr2;
-                                bytecodes.push(Bytecode::AddAdd { delta1: delta, val1: *val1, delta2, val2 });
+                                bytecodes.push(Bytecode::AddAdd { delta1: delta, val1: C::truncate(*val1), delta2, val2: C::truncate(val2) });
                                 i += 2;
                                 continue;
                             }
                             IR { opcode: IROp::Set(val2), pointer: ptr2, .. } => {
                                 let delta2 = i16::try_from(ptr2 - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                 last_ptr = ptr2;
-                                bytecodes.push(Bytecode::AddSet { delta1: delta, val1: *val1, delta2, val2 });
+                                bytecodes.push(Bytecode::AddSet { delta1: delta, val1: C::truncate(*val1), delta2, val2: C::truncate(val2) });
                                 i += 2;
                                 continue;
                             }
                             _ => {
-                                bytecodes.push(Bytecode::SingleAdd { delta, val: *val1 });
+                                bytecodes.push(Bytecode::SingleAdd { delta, val: C::truncate(*val1) });
                             }
                         }
                     }
@@ -99,19 +181,19 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
                             IR { opcode: IROp::Add(val2), pointer: ptr2, .. } => {
                                 let delta2 = i16::try_from(ptr2 - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                 last_ptr = ptr2;
-                                bytecodes.push(Bytecode::SetAdd { delta1: delta, val1: *val1, delta2, val2 });
+                                bytecodes.push(Bytecode::SetAdd { delta1: delta, val1: C::truncate(*val1), delta2, val2: C::truncate(val2) });
                                 i += 2;
                                 continue;
                             }
                             IR { opcode: IROp::Set(val2), pointer: ptr2, .. } => {
                                 let delta2 = i16::try_from(ptr2 - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                 last_ptr = ptr2;
-                                bytecodes.push(Bytecode::SetSet { delta1: delta, val1: *val1, delta2, val2 });
+                                bytecodes.push(Bytecode::SetSet { delta1: delta, val1: C::truncate(*val1), delta2, val2: C::truncate(val2) });
                                 i += 2;
                                 continue;
                             }
                             _ => {
-                                bytecodes.push(Bytecode::SingleSet { delta, val: *val1 });
+                                bytecodes.push(Bytecode::SingleSet { delta, val: C::truncate(*val1) });
                             }
                         }
                     }
@@ -130,9 +212,9 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
                                     let delta2 = i8::try_from(ptr - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                     last_ptr = ptr;
                                     match mid_range {
-                                        MidRange::None => bytecodes.push(Bytecode::ShiftAdd { delta1: delta, step: step_i8, delta2, val }),
-                                        MidRange::Positive(range) => bytecodes.push(Bytecode::ShiftAddP { delta1: delta, step: step_i8, delta2, val, range: *range }),
-                                        MidRange::Negative(range) => bytecodes.push(Bytecode::ShiftAddN { delta1: delta, step: step_i8, delta2, val, range: range.clone() }),
+                                        MidRange::None => bytecodes.push(Bytecode::ShiftAdd { delta1: delta, step: step_i8, delta2, val: C::truncate(val) }),
+                                        MidRange::Positive(range) => bytecodes.push(Bytecode::ShiftAddP { delta1: delta, step: step_i8, delta2, val: C::truncate(val), range: *range }),
+                                        MidRange::Negative(range) => bytecodes.push(Bytecode::ShiftAddN { delta1: delta, step: step_i8, delta2, val: C::truncate(val), range: range.clone() }),
                                         MidRange::Both { .. } => { unreachable!(); /* 上でMemoryRange::Bothは処理済みのはず */ }
                                     }
                                     i += 2;
@@ -142,9 +224,9 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
                                     let delta2 = i8::try_from(ptr - last_ptr).map_err(|e| OptimizationError::Delta(e))?;
                                     last_ptr = ptr;
                                     match mid_range {
-                                        MidRange::None => bytecodes.push(Bytecode::ShiftSet { delta1: delta, step: step_i8, delta2, val }),
-                                        MidRange::Positive(range) => bytecodes.push(Bytecode::ShiftSetP { delta1: delta, step: step_i8, delta2, val, range: *range }),
-                                        MidRange::Negative(range) => bytecodes.push(Bytecode::ShiftSetN { delta1: delta, step: step_i8, delta2, val, range: range.clone() }),
+                                        MidRange::None => bytecodes.push(Bytecode::ShiftSet { delta1: delta, step: step_i8, delta2, val: C::truncate(val) }),
+                                        MidRange::Positive(range) => bytecodes.push(Bytecode::ShiftSetP { delta1: delta, step: step_i8, delta2, val: C::truncate(val), range: *range }),
+                                        MidRange::Negative(range) => bytecodes.push(Bytecode::ShiftSetN { delta1: delta, step: step_i8, delta2, val: C::truncate(val), range: range.clone() }),
                                         MidRange::Both { .. } => { unreachable!(); /* 上でMemoryRange::Bothは処理済みのはず */ }
                                     }
                                     i += 2;
@@ -166,7 +248,7 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
                         bytecodes.push(Bytecode::MulStart { delta, jz_abs: skip_pc });
 
                         for (dest_ptr, dest_val) in dests {
-                            bytecodes.push(Bytecode::Mul { delta: i16::try_from(dest_ptr.wrapping_sub(last_ptr)).map_err(|e| OptimizationError::Delta(e))?, val: *dest_val });
+                            bytecodes.push(Bytecode::Mul { delta: i16::try_from(dest_ptr.wrapping_sub(last_ptr)).map_err(|e| OptimizationError::Delta(e))?, val: C::truncate(*dest_val) });
                         }
                     }
                     IROp::MovesAndSetZero(dests) => {
@@ -254,3 +336,91 @@ pub fn ir_to_bytecodes(ir_nodes: &[IR], range_info: &RangeInfo) -> Result<Vec<By
         i += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{ir::{ir::parse_to_ir, range::generate_range_info}, vm::tape::TapeMode};
+
+    use super::*;
+
+    fn lower(code: &str) -> (Vec<IR>, Vec<Bytecode<u8>>, Vec<BytecodeOrigin>) {
+        let ir = parse_to_ir(code).unwrap();
+        let range = generate_range_info(&ir, 16, TapeMode::Fixed).unwrap();
+        let (bytecodes, origins) = ir_to_bytecodes_with_origins(&ir, &range).unwrap();
+        (ir, bytecodes, origins)
+    }
+
+    #[test]
+    fn one_origin_per_bytecode() {
+        for code in ["", "+[-]>.", "+[->++<]>.", ">+[<]+[>+<-]", "++[>+[>]<<-]", ",[.,]"] {
+            let (ir, bytecodes, origins) = lower(code);
+            assert_eq!(bytecodes.len(), origins.len(), "{code}");
+            // IR ノードは順に、漏れなくどれかのバイトコードに割り当たる
+            let mut next = 0;
+            for origin in &origins {
+                assert!(origin.ir.start == next || origin.ir.end == next, "{code} {origins:?}");
+                next = origin.ir.end;
+            }
+            assert_eq!(next, ir.len(), "{code}");
+        }
+    }
+
+    #[test]
+    fn folded_loop_points_at_its_brackets() {
+        let (ir, bytecodes, origins) = lower("+[->++<]>.");
+        assert!(matches!(ir[1].opcode, IROp::MulAndSetZero(_)));
+        assert!(matches!(bytecodes[1], Bytecode::MulStart { .. }));
+        assert!(matches!(bytecodes[2], Bytecode::Mul { .. }));
+        assert_eq!(origins[1], BytecodeOrigin { ir: 1..2, source_range: Some(1..=7) });
+        assert_eq!(origins[2], BytecodeOrigin { ir: 1..2, source_range: Some(1..=7) });
+        assert_eq!(origins[3].source_range, Some(9..=9));
+
+        let (_, bytecodes, origins) = lower(">+[<]+[>+<-]");
+        assert!(matches!(bytecodes[1], Bytecode::ShiftAddP { .. }));
+        assert_eq!(origins[1].source_range, Some(2..=5));
+        assert!(matches!(bytecodes[2], Bytecode::SingleMoveAdd { .. }));
+        assert_eq!(origins[2].source_range, Some(6..=11));
+    }
+
+    fn accesses(inst: Bytecode<u8>) -> Vec<Access> {
+        let mut accesses = vec![];
+        inst.accesses(|access| accesses.push(access));
+        accesses
+    }
+
+    #[test]
+    fn mul_reads_its_counter_and_writes_targets() {
+        assert_eq!(accesses(Bytecode::MulStart { delta: 3, jz_abs: 9 }), [Access::Read(0), Access::Write(0)]);
+        assert_eq!(accesses(Bytecode::Mul { delta: -2, val: 3 }), [Access::Write(-2)]);
+        assert_eq!(accesses(Bytecode::MoveSub { delta: 4 }), [Access::Write(4)]);
+    }
+
+    #[test]
+    fn moves_read_the_source_and_write_both_ends() {
+        assert_eq!(accesses(Bytecode::SingleMoveAdd { delta: 1, to: -3 }), [Access::Read(0), Access::Write(0), Access::Write(-3)]);
+        for inst in [
+            Bytecode::DoubleMoveAddAdd { delta: 1, to1: 2, to2: -1 },
+            Bytecode::DoubleMoveAddSub { delta: 1, to1: 2, to2: -1 },
+            Bytecode::DoubleMoveSubAdd { delta: 1, to1: 2, to2: -1 },
+            Bytecode::DoubleMoveSubSub { delta: 1, to1: 2, to2: -1 },
+        ] {
+            assert_eq!(accesses(inst), [Access::Read(0), Access::Write(2), Access::Write(-1), Access::Write(0)]);
+        }
+    }
+
+    #[test]
+    fn shift_writes_after_the_scan() {
+        // 走査は delta2 だけ手前で止まっている
+        let scan = Access::Scan { delta: 2, step: -3, end: 4 };
+        assert_eq!(accesses(Bytecode::ShiftAdd { delta1: 2, step: -3, delta2: -4, val: 1 }), [scan, Access::Write(0)]);
+        assert_eq!(accesses(Bytecode::ShiftSetN { delta1: 2, step: -3, delta2: -4, val: 0, range: 0.. }), [scan, Access::Write(0)]);
+        assert_eq!(accesses(Bytecode::ShiftP { delta: 2, step: 1, range: ..5 }), [Access::Scan { delta: 2, step: 1, end: 0 }]);
+    }
+
+    #[test]
+    fn fused_writes_are_relative_to_the_end() {
+        assert_eq!(accesses(Bytecode::AddSet { delta1: 1, val1: 1, delta2: -2, val2: 0 }), [Access::Write(2), Access::Write(0)]);
+        assert_eq!(accesses(Bytecode::BothRangeCheckJNZ { delta: 1, addr_back: 2, range: 0..4 }), [Access::Read(0)]);
+        assert_eq!(accesses(Bytecode::End { delta: 1 }), []);
+    }
+}
@@ -16,3 +16,66 @@ pub enum OptimizationError {
     #[error("Program relative address overflow")]
     ProgramRel(#[source] TryFromIntError),
 }
+
+#[derive(Error, Debug)]
+pub enum FormatError {
+    #[error("Not a bytecode file")]
+    Magic,
+
+    #[error("Unsupported format version {0}")]
+    Version(u16),
+
+    #[error("Cell width mismatch: the file has {found}-bit cells, expected {expected}")]
+    CellWidth { found: u32, expected: u32 },
+
+    #[error("Unknown tape mode {0}")]
+    TapeMode(u8),
+
+    #[error("Unknown opcode {1} at {0}")]
+    Opcode(usize, u8),
+
+    #[error("{0} origins for {1} bytecodes")]
+    Origins(usize, usize),
+
+    #[error("Tape length {0} is too large")]
+    TapeLength(usize),
+
+    #[error("Unexpected end of file")]
+    Truncated,
+
+    #[error("Trailing bytes after the program")]
+    TrailingBytes,
+
+    #[error("Value does not fit in the file format")]
+    TooLarge,
+}
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("Program does not end with End")]
+    MissingEnd,
+
+    #[error("End at {0} is not the last bytecode")]
+    EarlyEnd(usize),
+
+    #[error("Jump at {0} targets {1}, which is out of the program")]
+    JumpTarget(usize, isize),
+
+    #[error("Loop starting at {0} is never closed")]
+    UnclosedLoop(usize),
+
+    #[error("Loop end at {0} has no loop start")]
+    UnmatchedLoopEnd(usize),
+
+    #[error("Loop end at {0} does not jump to and from its loop start at {1}")]
+    LoopMismatch(usize, usize),
+
+    #[error("Operand at {0} is outside a MulStart or MoveStart run")]
+    StrayOperand(usize),
+
+    #[error("Cell access at {0} may be out of bounds in the opt tier")]
+    UncheckedAccess(usize),
+
+    #[error("Tape length {0} is too large")]
+    TapeLength(usize),
+}
@@ -0,0 +1,462 @@
+use std::ops::{Range, RangeFrom, RangeTo};
+
+use crate::{bytecode::{bytecode::{Bytecode, BytecodeOrigin}, error::FormatError}, cell::Cell, vm::tape::TapeMode};
+
+// ファイルの形 (数値はすべてリトルエンディアン):
+//   magic "BFC\0", version: u16, cell_bits: u8, tape_mode: u8, tape_length: u64, do_opt_first: u8
+//   bytecode_count: u32, bytecode * bytecode_count    ; opcode: u8 のあとにフィールドを宣言順に並べる。セルの値は u32
+//   origin_count: u32, origin * origin_count          ; 0 か bytecode_count。ir.start: u64, ir.end: u64, has_source: u8, start: u64, end: u64
+
+pub const MAGIC: [u8; 4] = *b"BFC\0";
+pub const VERSION: u16 = 1;
+
+mod op {
+    pub const BREAKPOINT: u8 = 0;
+    pub const SINGLE_ADD: u8 = 1;
+    pub const SINGLE_SET: u8 = 2;
+    pub const ADD_ADD: u8 = 3;
+    pub const ADD_SET: u8 = 4;
+    pub const SET_ADD: u8 = 5;
+    pub const SET_SET: u8 = 6;
+    pub const BOTH_RANGE_CHECK: u8 = 7;
+    pub const SHIFT: u8 = 8;
+    pub const SHIFT_N: u8 = 9;
+    pub const SHIFT_P: u8 = 10;
+    pub const SHIFT_ADD: u8 = 11;
+    pub const SHIFT_ADD_N: u8 = 12;
+    pub const SHIFT_ADD_P: u8 = 13;
+    pub const SHIFT_SET: u8 = 14;
+    pub const SHIFT_SET_N: u8 = 15;
+    pub const SHIFT_SET_P: u8 = 16;
+    pub const MUL_START: u8 = 17;
+    pub const MUL: u8 = 18;
+    pub const SINGLE_MOVE_ADD: u8 = 19;
+    pub const SINGLE_MOVE_SUB: u8 = 20;
+    pub const DOUBLE_MOVE_ADD_ADD: u8 = 21;
+    pub const DOUBLE_MOVE_ADD_SUB: u8 = 22;
+    pub const DOUBLE_MOVE_SUB_ADD: u8 = 23;
+    pub const DOUBLE_MOVE_SUB_SUB: u8 = 24;
+    pub const MOVE_START: u8 = 25;
+    pub const MOVE_ADD: u8 = 26;
+    pub const MOVE_SUB: u8 = 27;
+    pub const IN: u8 = 28;
+    pub const OUT: u8 = 29;
+    pub const JMP_IF_ZERO: u8 = 30;
+    pub const JMP_IF_NOT_ZERO: u8 = 31;
+    pub const NEGATIVE_RANGE_CHECK_JNZ: u8 = 32;
+    pub const POSITIVE_RANGE_CHECK_JNZ: u8 = 33;
+    pub const BOTH_RANGE_CHECK_JNZ: u8 = 34;
+    pub const END: u8 = 35;
+}
+
+/// Everything a bytecode file holds. The IR is not stored, so programs loaded from a file have none.
+pub struct BytecodeImage<C> {
+    pub insts: Vec<Bytecode<C>>,
+    pub origins: Vec<BytecodeOrigin>,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+    pub do_opt_first: bool,
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.out.push(value);
+    }
+    fn i8(&mut self, value: i8) {
+        self.out.push(value as u8);
+    }
+    fn i16(&mut self, value: i16) {
+        self.out.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u16(&mut self, value: u16) {
+        self.out.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u32(&mut self, value: u32) {
+        self.out.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u64(&mut self, value: u64) {
+        self.out.extend_from_slice(&value.to_le_bytes());
+    }
+    fn cell<C: Cell>(&mut self, value: C) {
+        self.u32(value.to_u32());
+    }
+    fn len(&mut self, value: usize) -> Result<(), FormatError> {
+        self.u32(u32::try_from(value).map_err(|_| FormatError::TooLarge)?);
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+        let bytes = self.bytes.get(self.at..self.at + N).ok_or(FormatError::Truncated)?;
+        self.at += N;
+        Ok(bytes.try_into().unwrap())
+    }
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take::<1>()?[0])
+    }
+    fn i8(&mut self) -> Result<i8, FormatError> {
+        Ok(self.take::<1>()?[0] as i8)
+    }
+    fn i16(&mut self) -> Result<i16, FormatError> {
+        Ok(i16::from_le_bytes(self.take()?))
+    }
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+    fn usize(&mut self) -> Result<usize, FormatError> {
+        usize::try_from(self.u64()?).map_err(|_| FormatError::TooLarge)
+    }
+    fn cell<C: Cell>(&mut self) -> Result<C, FormatError> {
+        Ok(C::truncate(self.u32()?))
+    }
+    fn range(&mut self) -> Result<Range<u32>, FormatError> {
+        Ok(self.u32()?..self.u32()?)
+    }
+    fn range_from(&mut self) -> Result<RangeFrom<u32>, FormatError> {
+        Ok(self.u32()?..)
+    }
+    fn range_to(&mut self) -> Result<RangeTo<u32>, FormatError> {
+        Ok(..self.u32()?)
+    }
+    /// A count of items that take at least `min_size` bytes each, checked against what is left so that a corrupted count cannot make us allocate too much.
+    fn count(&mut self, min_size: usize) -> Result<usize, FormatError> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(min_size) > self.bytes.len() - self.at {
+            return Err(FormatError::Truncated);
+        }
+        Ok(count)
+    }
+}
+
+/// The cell width in bits if `bytes` starts like a bytecode file, so that callers can pick the `Cell` type to load it with.
+pub fn image_cell_bits(bytes: &[u8]) -> Option<u32> {
+    (bytes.len() > 7 && bytes[..4] == MAGIC).then(|| bytes[6] as u32)
+}
+
+pub fn encode_image<C: Cell>(image: &BytecodeImage<C>) -> Result<Vec<u8>, FormatError> {
+    let mut w = Writer { out: vec![] };
+
+    w.out.extend_from_slice(&MAGIC);
+    w.u16(VERSION);
+    w.u8(C::BITS as u8);
+    w.u8(match image.tape_mode {
+        TapeMode::Fixed => 0,
+        TapeMode::Growable => 1,
+        TapeMode::Wrapping => 2,
+    });
+    w.u64(image.tape_length as u64);
+    w.u8(image.do_opt_first as u8);
+
+    w.len(image.insts.len())?;
+    for inst in &image.insts {
+        match inst {
+            Bytecode::Breakpoint { delta } => { w.u8(op::BREAKPOINT); w.i16(*delta); }
+
+            Bytecode::SingleAdd { delta, val } => { w.u8(op::SINGLE_ADD); w.i16(*delta); w.cell(*val); }
+            Bytecode::SingleSet { delta, val } => { w.u8(op::SINGLE_SET); w.i16(*delta); w.cell(*val); }
+            Bytecode::AddAdd { delta1, val1, delta2, val2 } => { w.u8(op::ADD_ADD); w.i16(*delta1); w.cell(*val1); w.i16(*delta2); w.cell(*val2); }
+            Bytecode::AddSet { delta1, val1, delta2, val2 } => { w.u8(op::ADD_SET); w.i16(*delta1); w.cell(*val1); w.i16(*delta2); w.cell(*val2); }
+            Bytecode::SetAdd { delta1, val1, delta2, val2 } => { w.u8(op::SET_ADD); w.i16(*delta1); w.cell(*val1); w.i16(*delta2); w.cell(*val2); }
+            Bytecode::SetSet { delta1, val1, delta2, val2 } => { w.u8(op::SET_SET); w.i16(*delta1); w.cell(*val1); w.i16(*delta2); w.cell(*val2); }
+
+            Bytecode::BothRangeCheck { range } => { w.u8(op::BOTH_RANGE_CHECK); w.u32(range.start); w.u32(range.end); }
+            Bytecode::Shift { delta, step } => { w.u8(op::SHIFT); w.i16(*delta); w.i16(*step); }
+            Bytecode::ShiftN { delta, step, range } => { w.u8(op::SHIFT_N); w.i16(*delta); w.i16(*step); w.u32(range.start); }
+            Bytecode::ShiftP { delta, step, range } => { w.u8(op::SHIFT_P); w.i16(*delta); w.i16(*step); w.u32(range.end); }
+            Bytecode::ShiftAdd { delta1, step, delta2, val } => { w.u8(op::SHIFT_ADD); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); }
+            Bytecode::ShiftAddN { delta1, step, delta2, val, range } => { w.u8(op::SHIFT_ADD_N); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); w.u32(range.start); }
+            Bytecode::ShiftAddP { delta1, step, delta2, val, range } => { w.u8(op::SHIFT_ADD_P); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); w.u32(range.end); }
+            Bytecode::ShiftSet { delta1, step, delta2, val } => { w.u8(op::SHIFT_SET); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); }
+            Bytecode::ShiftSetN { delta1, step, delta2, val, range } => { w.u8(op::SHIFT_SET_N); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); w.u32(range.start); }
+            Bytecode::ShiftSetP { delta1, step, delta2, val, range } => { w.u8(op::SHIFT_SET_P); w.i16(*delta1); w.i8(*step); w.i8(*delta2); w.cell(*val); w.u32(range.end); }
+
+            Bytecode::MulStart { delta, jz_abs } => { w.u8(op::MUL_START); w.i16(*delta); w.u32(*jz_abs); }
+            Bytecode::Mul { delta, val } => { w.u8(op::MUL); w.i16(*delta); w.cell(*val); }
+
+            Bytecode::SingleMoveAdd { delta, to } => { w.u8(op::SINGLE_MOVE_ADD); w.i16(*delta); w.i16(*to); }
+            Bytecode::SingleMoveSub { delta, to } => { w.u8(op::SINGLE_MOVE_SUB); w.i16(*delta); w.i16(*to); }
+
+            Bytecode::DoubleMoveAddAdd { delta, to1, to2 } => { w.u8(op::DOUBLE_MOVE_ADD_ADD); w.i16(*delta); w.i16(*to1); w.i16(*to2); }
+            Bytecode::DoubleMoveAddSub { delta, to1, to2 } => { w.u8(op::DOUBLE_MOVE_ADD_SUB); w.i16(*delta); w.i16(*to1); w.i16(*to2); }
+            Bytecode::DoubleMoveSubAdd { delta, to1, to2 } => { w.u8(op::DOUBLE_MOVE_SUB_ADD); w.i16(*delta); w.i16(*to1); w.i16(*to2); }
+            Bytecode::DoubleMoveSubSub { delta, to1, to2 } => { w.u8(op::DOUBLE_MOVE_SUB_SUB); w.i16(*delta); w.i16(*to1); w.i16(*to2); }
+
+            Bytecode::MoveStart { delta, jz_abs } => { w.u8(op::MOVE_START); w.i16(*delta); w.u32(*jz_abs); }
+            Bytecode::MoveAdd { delta } => { w.u8(op::MOVE_ADD); w.i16(*delta); }
+            Bytecode::MoveSub { delta } => { w.u8(op::MOVE_SUB); w.i16(*delta); }
+
+            Bytecode::In { delta } => { w.u8(op::IN); w.i16(*delta); }
+            Bytecode::Out { delta } => { w.u8(op::OUT); w.i16(*delta); }
+
+            Bytecode::JmpIfZero { delta, addr_abs } => { w.u8(op::JMP_IF_ZERO); w.i16(*delta); w.u32(*addr_abs); }
+            Bytecode::JmpIfNotZero { delta, addr_abs } => { w.u8(op::JMP_IF_NOT_ZERO); w.i16(*delta); w.u32(*addr_abs); }
+            Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range } => { w.u8(op::NEGATIVE_RANGE_CHECK_JNZ); w.i16(*delta); w.u16(*addr_back); w.u32(range.start); }
+            Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range } => { w.u8(op::POSITIVE_RANGE_CHECK_JNZ); w.i16(*delta); w.u16(*addr_back); w.u32(range.end); }
+            Bytecode::BothRangeCheckJNZ { delta, addr_back, range } => { w.u8(op::BOTH_RANGE_CHECK_JNZ); w.i8(*delta); w.u16(*addr_back); w.u32(range.start); w.u32(range.end); }
+
+            Bytecode::End { delta } => { w.u8(op::END); w.i16(*delta); }
+        }
+    }
+
+    w.len(image.origins.len())?;
+    for origin in &image.origins {
+        w.u64(origin.ir.start as u64);
+        w.u64(origin.ir.end as u64);
+        match &origin.source_range {
+            Some(range) => { w.u8(1); w.u64(*range.start() as u64); w.u64(*range.end() as u64); }
+            None => { w.u8(0); w.u64(0); w.u64(0); }
+        }
+    }
+
+    Ok(w.out)
+}
+
+/// Parses a bytecode file. Only the encoding is checked here; pass the bytecode through `VerifiedBytecode::verify` before running it.
+pub fn decode_image<C: Cell>(bytes: &[u8]) -> Result<BytecodeImage<C>, FormatError> {
+    let mut r = Reader { bytes, at: 0 };
+
+    if r.take::<4>().ok() != Some(MAGIC) {
+        return Err(FormatError::Magic);
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(FormatError::Version(version));
+    }
+    let cell_bits = r.u8()? as u32;
+    if cell_bits != C::BITS {
+        return Err(FormatError::CellWidth { found: cell_bits, expected: C::BITS });
+    }
+    let tape_mode = match r.u8()? {
+        0 => TapeMode::Fixed,
+        1 => TapeMode::Growable,
+        2 => TapeMode::Wrapping,
+        mode => return Err(FormatError::TapeMode(mode)),
+    };
+    let tape_length = r.usize()?;
+    if tape_length > i32::MAX as usize {
+        return Err(FormatError::TapeLength(tape_length));
+    }
+    let do_opt_first = r.u8()? != 0;
+
+    let count = r.count(3)?;
+    let mut insts = Vec::with_capacity(count);
+    for pc in 0..count {
+        let inst = match r.u8()? {
+            op::BREAKPOINT => Bytecode::Breakpoint { delta: r.i16()? },
+
+            op::SINGLE_ADD => Bytecode::SingleAdd { delta: r.i16()?, val: r.cell()? },
+            op::SINGLE_SET => Bytecode::SingleSet { delta: r.i16()?, val: r.cell()? },
+            op::ADD_ADD => Bytecode::AddAdd { delta1: r.i16()?, val1: r.cell()?, delta2: r.i16()?, val2: r.cell()? },
+            op::ADD_SET => Bytecode::AddSet { delta1: r.i16()?, val1: r.cell()?, delta2: r.i16()?, val2: r.cell()? },
+            op::SET_ADD => Bytecode::SetAdd { delta1: r.i16()?, val1: r.cell()?, delta2: r.i16()?, val2: r.cell()? },
+            op::SET_SET => Bytecode::SetSet { delta1: r.i16()?, val1: r.cell()?, delta2: r.i16()?, val2: r.cell()? },
+
+            op::BOTH_RANGE_CHECK => Bytecode::BothRangeCheck { range: r.range()? },
+            op::SHIFT => Bytecode::Shift { delta: r.i16()?, step: r.i16()? },
+            op::SHIFT_N => Bytecode::ShiftN { delta: r.i16()?, step: r.i16()?, range: r.range_from()? },
+            op::SHIFT_P => Bytecode::ShiftP { delta: r.i16()?, step: r.i16()?, range: r.range_to()? },
+            op::SHIFT_ADD => Bytecode::ShiftAdd { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()? },
+            op::SHIFT_ADD_N => Bytecode::ShiftAddN { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()?, range: r.range_from()? },
+            op::SHIFT_ADD_P => Bytecode::ShiftAddP { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()?, range: r.range_to()? },
+            op::SHIFT_SET => Bytecode::ShiftSet { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()? },
+            op::SHIFT_SET_N => Bytecode::ShiftSetN { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()?, range: r.range_from()? },
+            op::SHIFT_SET_P => Bytecode::ShiftSetP { delta1: r.i16()?, step: r.i8()?, delta2: r.i8()?, val: r.cell()?, range: r.range_to()? },
+
+            op::MUL_START => Bytecode::MulStart { delta: r.i16()?, jz_abs: r.u32()? },
+            op::MUL => Bytecode::Mul { delta: r.i16()?, val: r.cell()? },
+
+            op::SINGLE_MOVE_ADD => Bytecode::SingleMoveAdd { delta: r.i16()?, to: r.i16()? },
+            op::SINGLE_MOVE_SUB => Bytecode::SingleMoveSub { delta: r.i16()?, to: r.i16()? },
+
+            op::DOUBLE_MOVE_ADD_ADD => Bytecode::DoubleMoveAddAdd { delta: r.i16()?, to1: r.i16()?, to2: r.i16()? },
+            op::DOUBLE_MOVE_ADD_SUB => Bytecode::DoubleMoveAddSub { delta: r.i16()?, to1: r.i16()?, to2: r.i16()? },
+            op::DOUBLE_MOVE_SUB_ADD => Bytecode::DoubleMoveSubAdd { delta: r.i16()?, to1: r.i16()?, to2: r.i16()? },
+            op::DOUBLE_MOVE_SUB_SUB => Bytecode::DoubleMoveSubSub { delta: r.i16()?, to1: r.i16()?, to2: r.i16()? },
+
+            op::MOVE_START => Bytecode::MoveStart { delta: r.i16()?, jz_abs: r.u32()? },
+            op::MOVE_ADD => Bytecode::MoveAdd { delta: r.i16()? },
+            op::MOVE_SUB => Bytecode::MoveSub { delta: r.i16()? },
+
+            op::IN => Bytecode::In { delta: r.i16()? },
+            op::OUT => Bytecode::Out { delta: r.i16()? },
+
+            op::JMP_IF_ZERO => Bytecode::JmpIfZero { delta: r.i16()?, addr_abs: r.u32()? },
+            op::JMP_IF_NOT_ZERO => Bytecode::JmpIfNotZero { delta: r.i16()?, addr_abs: r.u32()? },
+            op::NEGATIVE_RANGE_CHECK_JNZ => Bytecode::NegativeRangeCheckJNZ { delta: r.i16()?, addr_back: r.u16()?, range: r.range_from()? },
+            op::POSITIVE_RANGE_CHECK_JNZ => Bytecode::PositiveRangeCheckJNZ { delta: r.i16()?, addr_back: r.u16()?, range: r.range_to()? },
+            op::BOTH_RANGE_CHECK_JNZ => Bytecode::BothRangeCheckJNZ { delta: r.i8()?, addr_back: r.u16()?, range: r.range()? },
+
+            op::END => Bytecode::End { delta: r.i16()? },
+            opcode => return Err(FormatError::Opcode(pc, opcode)),
+        };
+        insts.push(inst);
+    }
+
+    let count = r.count(25)?;
+    if count != 0 && count != insts.len() {
+        return Err(FormatError::Origins(count, insts.len()));
+    }
+    let mut origins = Vec::with_capacity(count);
+    for _ in 0..count {
+        let ir = r.usize()?..r.usize()?;
+        let has_source = r.u8()? != 0;
+        let (start, end) = (r.usize()?, r.usize()?);
+        origins.push(BytecodeOrigin { ir, source_range: has_source.then_some(start..=end) });
+    }
+
+    if r.at != bytes.len() {
+        return Err(FormatError::TrailingBytes);
+    }
+
+    Ok(BytecodeImage { insts, origins, tape_length, tape_mode, do_opt_first })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{brainrot::{Brainrot, ExecutionInit}, bytecode::{error::FormatError, format::{BytecodeImage, decode_image, encode_image}}, codegen::tests::{PROGRAMS, TAPE_MODES}, compiled::CompiledProgram, error::BrainrotError, vm::{program::EofPolicy, tape::TapeMode}};
+
+    // ヘッダは magic, version, cell_bits, tape_mode, tape_length, do_opt_first の 17 バイト
+    const COUNT_AT: usize = 17;
+    const FIRST_OPCODE_AT: usize = COUNT_AT + 4;
+
+    fn saved(code: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        CompiledProgram::<u16>::new(code, 16, TapeMode::Fixed).unwrap().save(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn format_error(bytes: &[u8]) -> FormatError {
+        match CompiledProgram::<u16>::load(bytes) {
+            Err(BrainrotError::FormatError(err)) => err,
+            Err(err) => panic!("expected a format error, got {err}"),
+            Ok(_) => panic!("expected a format error, got a program"),
+        }
+    }
+
+    fn run(compiled: CompiledProgram<u16>, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut output = vec![];
+        let mut input = input.iter().copied();
+        let mut vm = Brainrot::from_compiled(Arc::new(compiled), ExecutionInit {
+            input: || input.next(),
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+        });
+        let failed = vm.step().is_err();
+        drop(vm);
+        (output, failed)
+    }
+
+    #[test]
+    fn round_trip() {
+        for (code, input) in PROGRAMS {
+            for mode in TAPE_MODES {
+                let compiled = CompiledProgram::<u16>::new(code, 16, mode).unwrap();
+                let mut bytes = vec![];
+                compiled.save(&mut bytes).unwrap();
+                let loaded = CompiledProgram::<u16>::load(bytes.as_slice()).unwrap();
+
+                assert_eq!(format!("{:?}", loaded.bytecodes()), format!("{:?}", compiled.bytecodes()), "{code} on {mode:?}");
+                assert_eq!(loaded.origins(), compiled.origins(), "{code} on {mode:?}");
+                assert_eq!(loaded.tape_length(), compiled.tape_length());
+                assert_eq!(loaded.tape_mode(), compiled.tape_mode());
+                assert_eq!(loaded.verified().opt_first(), compiled.verified().opt_first(), "{code} on {mode:?}");
+                assert_eq!(run(loaded, input), run(compiled, input), "{code} on {mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = saved(PROGRAMS[0].0);
+        for len in 0..bytes.len() {
+            assert!(CompiledProgram::<u16>::load(&bytes[..len]).is_err(), "accepted the first {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = saved(PROGRAMS[0].0);
+        bytes[0] = b'X';
+        assert!(matches!(format_error(&bytes), FormatError::Magic));
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut bytes = saved(PROGRAMS[0].0);
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(format_error(&bytes), FormatError::Version(2)));
+    }
+
+    #[test]
+    fn rejects_other_cell_widths() {
+        let bytes = saved(PROGRAMS[0].0);
+        assert!(matches!(CompiledProgram::<u8>::load(bytes.as_slice()), Err(BrainrotError::FormatError(FormatError::CellWidth { found: 16, expected: 8 }))));
+    }
+
+    #[test]
+    fn rejects_bad_opcode() {
+        let mut bytes = saved(PROGRAMS[0].0);
+        bytes[FIRST_OPCODE_AT] = 0xff;
+        assert!(matches!(format_error(&bytes), FormatError::Opcode(0, 0xff)));
+    }
+
+    #[test]
+    fn rejects_oversized_counts() {
+        let mut bytes = saved(PROGRAMS[0].0);
+        bytes[COUNT_AT..FIRST_OPCODE_AT].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(format_error(&bytes), FormatError::Truncated));
+
+        // origin の数はファイルの最後の 4 バイト
+        let compiled = CompiledProgram::<u16>::new(PROGRAMS[0].0, 16, TapeMode::Fixed).unwrap();
+        let mut bytes = encode_image(&BytecodeImage {
+            insts: compiled.bytecodes().to_vec(),
+            origins: vec![],
+            tape_length: 16,
+            tape_mode: TapeMode::Fixed,
+            do_opt_first: false,
+        }).unwrap();
+        let at = bytes.len() - 4;
+        bytes[at..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode_image::<u16>(&bytes), Err(FormatError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_origin_count_mismatch() {
+        let compiled = CompiledProgram::<u16>::new(PROGRAMS[0].0, 16, TapeMode::Fixed).unwrap();
+        let insts = compiled.bytecodes().to_vec();
+        let bytes = encode_image(&BytecodeImage {
+            origins: compiled.origins()[1..].to_vec(),
+            tape_length: 16,
+            tape_mode: TapeMode::Fixed,
+            do_opt_first: false,
+            insts,
+        }).unwrap();
+        let count = compiled.bytecodes().len();
+        assert!(matches!(format_error(&bytes), FormatError::Origins(found, expected) if found == count - 1 && expected == count));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = saved(PROGRAMS[0].0);
+        bytes.push(0);
+        assert!(matches!(format_error(&bytes), FormatError::TrailingBytes));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod error;
 pub mod bytecode;
+pub mod format;
+pub mod verify;
@@ -0,0 +1,661 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use crate::{bytecode::{bytecode::Bytecode, error::VerifyError}, cell::Cell, vm::tape::TapeMode};
+
+/// Growable テープもこれ以上は伸びない (vm::tape と同じ値)
+const MAX_TAPE_LENGTH: i64 = i32::MAX as i64;
+/// これより大きい境界は「不明」として扱う
+const INF: i64 = 1 << 40;
+/// 同じ pc の状態がこの回数を超えて変わったら、変わった側の境界を次の閾値まで広げる
+const WIDEN_AFTER: u32 = 16;
+
+/// Bytecode checked by `VerifiedBytecode::verify` for a tape of the given length and mode.
+/// The opt tier and the JIT only ever run bytecode wrapped in this.
+#[derive(Clone)]
+pub struct VerifiedBytecode<C: Cell> {
+    insts: Arc<[Bytecode<C>]>,
+    tape_length: usize,
+    tape_mode: TapeMode,
+    opt_first: bool,
+}
+
+impl<C: Cell> VerifiedBytecode<C> {
+    /// Checks that jumps stay inside the program, loops and `MulStart`/`MoveStart` runs are well formed, the program
+    /// ends with its only `End`, and that every cell the opt tier touches without a bounds check is inside the tape,
+    /// given the range operands. `opt_first` is whether execution starts in the opt tier, as with `RangeInfo::do_opt_first`.
+    pub fn verify(insts: impl Into<Arc<[Bytecode<C>]>>, tape_length: usize, tape_mode: TapeMode, opt_first: bool) -> Result<VerifiedBytecode<C>, VerifyError> {
+        let insts = insts.into();
+        if tape_length as u64 > MAX_TAPE_LENGTH as u64 {
+            return Err(VerifyError::TapeLength(tape_length));
+        }
+        check_structure(&insts)?;
+        // 空でない循環テープは opt でもアクセスのたびに剰余を取るので、範囲外には出ない
+        if !(tape_mode == TapeMode::Wrapping && tape_length != 0) {
+            Analysis::new(tape_length, tape_mode).run(&insts, opt_first)?;
+        }
+        Ok(VerifiedBytecode { insts, tape_length, tape_mode, opt_first })
+    }
+    /// Like `verify`, but if only the range analysis fails, empties every range operand so that the bytecode runs in
+    /// the deopt tier alone instead of being rejected. The compiler does not use this: a range it cannot prove is a bug
+    /// there, so `CompiledProgram::new` reports it. Call this only to run such bytecode anyway, slowly.
+    pub fn verify_or_deopt(insts: Vec<Bytecode<C>>, tape_length: usize, tape_mode: TapeMode, opt_first: bool) -> Result<VerifiedBytecode<C>, VerifyError> {
+        match VerifiedBytecode::verify(insts.clone(), tape_length, tape_mode, opt_first) {
+            Err(VerifyError::UncheckedAccess(_)) => {}
+            verified => return verified,
+        }
+        // can_opt はバッファ内でしか通らず、バッファは i32::MAX 以下なので u32::MAX.. にも入らない
+        let insts: Vec<Bytecode<C>> = insts.into_iter().map(|inst| match inst {
+            Bytecode::BothRangeCheck { .. } => Bytecode::BothRangeCheck { range: 0..0 },
+            Bytecode::ShiftP { delta, step, .. } => Bytecode::ShiftP { delta, step, range: ..0 },
+            Bytecode::ShiftN { delta, step, .. } => Bytecode::ShiftN { delta, step, range: u32::MAX.. },
+            Bytecode::ShiftAddP { delta1, step, delta2, val, .. } => Bytecode::ShiftAddP { delta1, step, delta2, val, range: ..0 },
+            Bytecode::ShiftAddN { delta1, step, delta2, val, .. } => Bytecode::ShiftAddN { delta1, step, delta2, val, range: u32::MAX.. },
+            Bytecode::ShiftSetP { delta1, step, delta2, val, .. } => Bytecode::ShiftSetP { delta1, step, delta2, val, range: ..0 },
+            Bytecode::ShiftSetN { delta1, step, delta2, val, .. } => Bytecode::ShiftSetN { delta1, step, delta2, val, range: u32::MAX.. },
+            Bytecode::PositiveRangeCheckJNZ { delta, addr_back, .. } => Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range: ..0 },
+            Bytecode::NegativeRangeCheckJNZ { delta, addr_back, .. } => Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range: u32::MAX.. },
+            Bytecode::BothRangeCheckJNZ { delta, addr_back, .. } => Bytecode::BothRangeCheckJNZ { delta, addr_back, range: 0..0 },
+            inst => inst,
+        }).collect();
+        VerifiedBytecode::verify(insts, tape_length, tape_mode, false)
+    }
+    pub fn insts(&self) -> &Arc<[Bytecode<C>]> {
+        &self.insts
+    }
+    pub fn tape_length(&self) -> usize {
+        self.tape_length
+    }
+    pub fn tape_mode(&self) -> TapeMode {
+        self.tape_mode
+    }
+    pub fn opt_first(&self) -> bool {
+        self.opt_first
+    }
+}
+
+fn check_structure<C: Cell>(insts: &[Bytecode<C>]) -> Result<(), VerifyError> {
+    let Some((Bytecode::End { .. }, _)) = insts.split_last() else {
+        return Err(VerifyError::MissingEnd);
+    };
+    let mut loops: Vec<usize> = vec![];
+    let mut pc = 0;
+    while pc < insts.len() {
+        match &insts[pc] {
+            Bytecode::End { .. } if pc + 1 != insts.len() => return Err(VerifyError::EarlyEnd(pc)),
+
+            Bytecode::MulStart { jz_abs, .. } | Bytecode::MoveStart { jz_abs, .. } => {
+                let end = *jz_abs as usize;
+                if end <= pc || end >= insts.len() {
+                    return Err(VerifyError::JumpTarget(pc, end as isize));
+                }
+                let is_mul = matches!(insts[pc], Bytecode::MulStart { .. });
+                for (i, inst) in insts.iter().enumerate().take(end).skip(pc + 1) {
+                    match inst {
+                        Bytecode::Mul { .. } if is_mul => {}
+                        Bytecode::MoveAdd { .. } | Bytecode::MoveSub { .. } if !is_mul => {}
+                        _ => return Err(VerifyError::StrayOperand(i)),
+                    }
+                }
+                pc = end;
+                continue;
+            }
+            Bytecode::Mul { .. } | Bytecode::MoveAdd { .. } | Bytecode::MoveSub { .. } => return Err(VerifyError::StrayOperand(pc)),
+
+            Bytecode::JmpIfZero { .. } => loops.push(pc),
+            Bytecode::JmpIfNotZero { addr_abs, .. } => close_loop(insts, &mut loops, pc, *addr_abs as isize)?,
+            Bytecode::NegativeRangeCheckJNZ { addr_back, .. }
+            | Bytecode::PositiveRangeCheckJNZ { addr_back, .. }
+            | Bytecode::BothRangeCheckJNZ { addr_back, .. } => close_loop(insts, &mut loops, pc, pc as isize - *addr_back as isize)?,
+
+            _ => {}
+        }
+        pc += 1;
+    }
+    match loops.pop() {
+        Some(start) => Err(VerifyError::UnclosedLoop(start)),
+        None => Ok(()),
+    }
+}
+
+/// `[` は対応する `]` の次へ、`]` は対応する `[` の次へ飛ぶ
+fn close_loop<C: Cell>(insts: &[Bytecode<C>], loops: &mut Vec<usize>, pc: usize, target: isize) -> Result<(), VerifyError> {
+    let Some(start) = loops.pop() else {
+        return Err(VerifyError::UnmatchedLoopEnd(pc));
+    };
+    match &insts[start] {
+        Bytecode::JmpIfZero { addr_abs, .. } if *addr_abs as usize == pc + 1 && target == start as isize + 1 => Ok(()),
+        _ => Err(VerifyError::LoopMismatch(pc, start)),
+    }
+}
+
+/// What is known about the pointer `p` at some pc: `lo <= p` and `p - len <= hi`, where `len` is the current buffer
+/// length. A cell at offset `o` is inside the buffer when `lo + o >= 0` and `hi + o <= -1`. Growing the tape keeps
+/// both bounds true. `zero` is whether the cell at `p` is known to be zero, which rules out some loop edges.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Bounds {
+    lo: i64,
+    hi: i64,
+    zero: bool,
+}
+impl Bounds {
+    const UNKNOWN: Bounds = Bounds { lo: -INF, hi: INF, zero: false };
+    /// Somewhere on a zero cell inside the buffer.
+    const SCANNED: Bounds = Bounds { lo: 0, hi: -1, zero: true };
+
+    fn step(self, delta: i64) -> Bounds {
+        let add = |bound: i64| if bound.abs() >= INF { bound } else { (bound + delta).clamp(-INF, INF) };
+        Bounds { lo: add(self.lo), hi: add(self.hi), zero: self.zero && delta == 0 }
+    }
+    fn zero(self, zero: bool) -> Bounds {
+        Bounds { zero, ..self }
+    }
+    fn contains(self, offset: i64) -> bool {
+        self.lo + offset >= 0 && self.hi + offset <= -1
+    }
+    /// The bounds after a checked access to offset `offset` succeeded.
+    fn inside(self, offset: i64) -> Bounds {
+        Bounds { lo: self.lo.max(-offset), hi: self.hi.min(-1 - offset), zero: self.zero }
+    }
+    fn join(self, other: Bounds) -> Bounds {
+        Bounds { lo: self.lo.min(other.lo), hi: self.hi.max(other.hi), zero: self.zero && other.zero }
+    }
+}
+
+/// The bounds after adding `val` to, or setting it in, the cell at `p`.
+fn written<C: Cell>(bounds: Bounds, set: bool, val: C) -> Bounds {
+    bounds.zero(val == C::ZERO && (set || bounds.zero))
+}
+
+const DEOPT: usize = 0;
+const OPT: usize = 1;
+
+/// Per-tier states and the worklist. The JIT mirrors the opt tier, so it shares its states.
+struct Flow {
+    states: [Vec<Option<Bounds>>; 2],
+    changes: [Vec<u32>; 2],
+    queue: BTreeSet<(usize, usize)>,
+    /// Where widening stops before giving up on a bound: the buffer's edges and those of the range operands, sorted.
+    lo_steps: Vec<i64>,
+    hi_steps: Vec<i64>,
+    max_len: i64,
+}
+impl Flow {
+    fn merge(&mut self, tier: usize, pc: usize, bounds: Bounds) {
+        // lo <= p <= hi + len を満たす p がなければ、その経路は実行されない
+        if bounds.lo > bounds.hi + self.max_len {
+            return;
+        }
+        // deopt にはどの pc からでも落ちられる
+        if tier == OPT {
+            self.merge(DEOPT, pc, bounds);
+        }
+        let old = self.states[tier][pc];
+        let mut new = old.map_or(bounds, |old| old.join(bounds));
+        if old == Some(new) {
+            return;
+        }
+        if let Some(old) = old {
+            self.changes[tier][pc] += 1;
+            if self.changes[tier][pc] > WIDEN_AFTER {
+                // 閾値で止めても変わり続けるなら諦める
+                let give_up = self.changes[tier][pc] > WIDEN_AFTER * 2;
+                if new.lo < old.lo {
+                    new.lo = self.lo_steps.iter().rev().find(|&&step| step <= new.lo && !give_up).copied().unwrap_or(-INF);
+                }
+                if new.hi > old.hi {
+                    new.hi = self.hi_steps.iter().find(|&&step| step >= new.hi && !give_up).copied().unwrap_or(INF);
+                }
+            }
+        }
+        self.states[tier][pc] = Some(new);
+        self.queue.insert((tier, pc));
+    }
+}
+
+struct Analysis {
+    /// The buffer is at least this long
+    min_len: i64,
+    /// and at most this long.
+    max_len: i64,
+    /// Whether a checked read fails outside the buffer. A Growable tape reads zero there instead.
+    checked_reads: bool,
+}
+impl Analysis {
+    fn new(tape_length: usize, tape_mode: TapeMode) -> Analysis {
+        let min_len = tape_length as i64;
+        let growable = tape_mode == TapeMode::Growable;
+        let max_len = if growable { MAX_TAPE_LENGTH } else { min_len };
+        Analysis { min_len, max_len, checked_reads: !growable }
+    }
+
+    /// The bounds after the deopt tier's `can_opt` passed: the pointer is inside both the buffer and `start..end`.
+    /// An empty range never lets it in.
+    fn enter(&self, bounds: Bounds, start: u32, end: Option<u32>) -> Option<Bounds> {
+        if end.is_some_and(|end| end <= start) {
+            return None;
+        }
+        let bounds = bounds.inside(0);
+        Some(Bounds {
+            lo: bounds.lo.max(start as i64),
+            hi: end.map_or(bounds.hi, |end| bounds.hi.min(end as i64 - 1 - self.min_len)),
+            zero: bounds.zero,
+        })
+    }
+
+    /// The bounds after the opt tier's `range.contains(&(ptr as u32))` passed. The check only narrows them when
+    /// the `u32` cast cannot alias a pointer outside the buffer onto the range.
+    fn refine(&self, bounds: Bounds, start: u32, end: Option<u32>) -> Option<Bounds> {
+        if end.is_some_and(|end| end <= start) {
+            return None;
+        }
+        let exact = bounds.hi.abs() < INF && self.max_len + bounds.hi < 1 << 32
+            && (bounds.lo >= 0 || (bounds.lo > -(1 << 31) && end.is_some_and(|end| end <= 1 << 31)));
+        if !exact {
+            return Some(bounds);
+        }
+        Some(Bounds {
+            lo: bounds.lo.max(start as i64),
+            hi: end.map_or(bounds.hi, |end| bounds.hi.min(end as i64 - 1 - self.min_len)),
+            zero: bounds.zero,
+        })
+    }
+
+    /// Where a scan that starts at `start` stops. It only moves in the direction of `step`, and unless it reads
+    /// zero past a Growable tape's allocation, it stops on a cell inside the buffer.
+    fn scanned(&self, start: Bounds, step: i16, opt: bool) -> Bounds {
+        let bounds = if opt || self.checked_reads { Bounds::SCANNED } else { Bounds::UNKNOWN.zero(true) };
+        // 0 から始まる走査は動かない
+        if start.zero {
+            return Bounds { lo: start.lo.max(bounds.lo), hi: start.hi.min(bounds.hi), zero: true };
+        }
+        Bounds {
+            lo: if step >= 0 { bounds.lo.max(start.lo) } else { bounds.lo },
+            hi: if step <= 0 { bounds.hi.min(start.hi) } else { bounds.hi },
+            zero: true,
+        }
+    }
+
+    /// Follows both tiers from the start of the program. An access in the opt tier must be provably inside the buffer;
+    /// one in the deopt tier is checked, so getting past it narrows the bounds instead.
+    fn run<C: Cell>(&self, insts: &[Bytecode<C>], opt_first: bool) -> Result<(), VerifyError> {
+        let mut flow = Flow {
+            states: [vec![None; insts.len()], vec![None; insts.len()]],
+            changes: [vec![0; insts.len()], vec![0; insts.len()]],
+            queue: BTreeSet::new(),
+            lo_steps: vec![0],
+            hi_steps: vec![-1],
+            max_len: self.max_len,
+        };
+        for inst in insts {
+            let (start, end) = match inst {
+                Bytecode::BothRangeCheck { range } | Bytecode::BothRangeCheckJNZ { range, .. } => (range.start, Some(range.end)),
+                Bytecode::ShiftP { range, .. } | Bytecode::ShiftAddP { range, .. } | Bytecode::ShiftSetP { range, .. }
+                | Bytecode::PositiveRangeCheckJNZ { range, .. } => (0, Some(range.end)),
+                Bytecode::ShiftN { range, .. } | Bytecode::ShiftAddN { range, .. } | Bytecode::ShiftSetN { range, .. }
+                | Bytecode::NegativeRangeCheckJNZ { range, .. } => (range.start, None),
+                _ => continue,
+            };
+            flow.lo_steps.push(start as i64);
+            if let Some(end) = end {
+                flow.hi_steps.push(end as i64 - 1 - self.min_len);
+            }
+        }
+        for steps in [&mut flow.lo_steps, &mut flow.hi_steps] {
+            steps.sort_unstable();
+            steps.dedup();
+        }
+        // テープは 0 で初期化されている
+        flow.merge(if opt_first { OPT } else { DEOPT }, 0, Bounds { lo: 0, hi: -self.min_len, zero: true });
+
+        while let Some((tier, pc)) = flow.queue.pop_first() {
+            let bounds = flow.states[tier][pc].unwrap();
+            let opt = tier == OPT;
+            let access = |bounds: Bounds, offset: i64, write: bool| -> Result<Bounds, VerifyError> {
+                if !opt {
+                    Ok(if write || self.checked_reads { bounds.inside(offset) } else { bounds })
+                } else if bounds.contains(offset) {
+                    Ok(bounds)
+                } else {
+                    Err(VerifyError::UncheckedAccess(pc))
+                }
+            };
+
+            match &insts[pc] {
+                Bytecode::Breakpoint { delta } => flow.merge(tier, pc + 1, bounds.step(*delta as i64)),
+
+                Bytecode::SingleAdd { .. } | Bytecode::SingleSet { .. } => {
+                    let (delta, set, val) = match &insts[pc] {
+                        Bytecode::SingleAdd { delta, val } => (*delta, false, *val),
+                        Bytecode::SingleSet { delta, val } => (*delta, true, *val),
+                        _ => unreachable!(),
+                    };
+                    let bounds = access(bounds.step(delta as i64), 0, true)?;
+                    flow.merge(tier, pc + 1, written(bounds, set, val));
+                }
+                Bytecode::AddAdd { .. } | Bytecode::SetAdd { .. } | Bytecode::AddSet { .. } | Bytecode::SetSet { .. } => {
+                    let (delta1, set1, val1, delta2, set2, val2) = match &insts[pc] {
+                        Bytecode::AddAdd { delta1, val1, delta2, val2 } => (*delta1, false, *val1, *delta2, false, *val2),
+                        Bytecode::AddSet { delta1, val1, delta2, val2 } => (*delta1, false, *val1, *delta2, true, *val2),
+                        Bytecode::SetAdd { delta1, val1, delta2, val2 } => (*delta1, true, *val1, *delta2, false, *val2),
+                        Bytecode::SetSet { delta1, val1, delta2, val2 } => (*delta1, true, *val1, *delta2, true, *val2),
+                        _ => unreachable!(),
+                    };
+                    let bounds = written(access(bounds.step(delta1 as i64), 0, true)?, set1, val1);
+                    let bounds = written(access(bounds.step(delta2 as i64), 0, true)?, set2, val2);
+                    flow.merge(tier, pc + 1, bounds);
+                }
+                Bytecode::In { delta } => {
+                    // EOF なら読むだけ
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    flow.merge(tier, pc + 1, bounds.zero(false));
+                }
+                Bytecode::Out { delta } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    flow.merge(tier, pc + 1, bounds);
+                }
+
+                Bytecode::BothRangeCheck { range } => {
+                    if opt {
+                        if let Some(bounds) = self.refine(bounds, range.start, Some(range.end)) {
+                            flow.merge(OPT, pc + 1, bounds);
+                        }
+                    } else if let Some(bounds) = self.enter(bounds, range.start, Some(range.end)) {
+                        flow.merge(OPT, pc + 1, bounds);
+                    }
+                    flow.merge(DEOPT, pc + 1, bounds);
+                }
+                Bytecode::Shift { .. } | Bytecode::ShiftP { .. } | Bytecode::ShiftN { .. }
+                | Bytecode::ShiftAdd { .. } | Bytecode::ShiftSet { .. }
+                | Bytecode::ShiftAddP { .. } | Bytecode::ShiftSetP { .. }
+                | Bytecode::ShiftAddN { .. } | Bytecode::ShiftSetN { .. } => {
+                    // (delta, step, range, 走査後の書き込み (delta2, Set か, val))
+                    let (delta, step, range, write) = match &insts[pc] {
+                        Bytecode::Shift { delta, step } => (*delta, *step, None, None),
+                        Bytecode::ShiftP { delta, step, range } => (*delta, *step, Some((0, Some(range.end))), None),
+                        Bytecode::ShiftN { delta, step, range } => (*delta, *step, Some((range.start, None)), None),
+                        Bytecode::ShiftAdd { delta1, step, delta2, val } => (*delta1, *step as i16, None, Some((*delta2, false, *val))),
+                        Bytecode::ShiftSet { delta1, step, delta2, val } => (*delta1, *step as i16, None, Some((*delta2, true, *val))),
+                        Bytecode::ShiftAddP { delta1, step, delta2, val, range } => (*delta1, *step as i16, Some((0, Some(range.end))), Some((*delta2, false, *val))),
+                        Bytecode::ShiftSetP { delta1, step, delta2, val, range } => (*delta1, *step as i16, Some((0, Some(range.end))), Some((*delta2, true, *val))),
+                        Bytecode::ShiftAddN { delta1, step, delta2, val, range } => (*delta1, *step as i16, Some((range.start, None)), Some((*delta2, false, *val))),
+                        Bytecode::ShiftSetN { delta1, step, delta2, val, range } => (*delta1, *step as i16, Some((range.start, None)), Some((*delta2, true, *val))),
+                        _ => unreachable!(),
+                    };
+                    let after = |bounds: Bounds| match write {
+                        Some((delta2, set, val)) => written(bounds.step(delta2 as i64).inside(0), set, val),
+                        None => bounds,
+                    };
+                    let scanned = self.scanned(bounds.step(delta as i64), step, opt);
+                    if opt {
+                        let passed = match range {
+                            Some((start, end)) => self.refine(scanned, start, end),
+                            None => Some(scanned),
+                        };
+                        if let Some(bounds) = passed {
+                            if let Some((delta2, ..)) = write {
+                                access(bounds.step(delta2 as i64), 0, true)?;
+                            }
+                            flow.merge(OPT, pc + 1, after(bounds));
+                        }
+                        // 走査がバッファを出たら、書き込みだけ検査付きで済ませて deopt
+                        flow.merge(DEOPT, pc + 1, after(Bounds::UNKNOWN));
+                    } else {
+                        if let Some(bounds) = range.and_then(|(start, end)| self.enter(scanned, start, end)) {
+                            flow.merge(OPT, pc + 1, after(bounds));
+                        }
+                        flow.merge(DEOPT, pc + 1, after(scanned));
+                    }
+                }
+
+                // 0 なら飛ばし、そうでなければ値を取ってから 0 にする。どちらの行き先でもセルは 0
+                Bytecode::MulStart { delta, jz_abs } | Bytecode::MoveStart { delta, jz_abs } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    flow.merge(tier, *jz_abs as usize, bounds.zero(true));
+                    if !bounds.zero {
+                        flow.merge(tier, pc + 1, access(bounds, 0, true)?.zero(true));
+                    }
+                }
+                Bytecode::Mul { delta, .. } | Bytecode::MoveAdd { delta } | Bytecode::MoveSub { delta } => {
+                    let bounds = access(bounds, *delta as i64, true)?;
+                    flow.merge(tier, pc + 1, bounds.zero(bounds.zero && *delta != 0));
+                }
+                // deopt は値が 0 なら書き込まないので、移動先の検査は opt だけ
+                Bytecode::SingleMoveAdd { delta, to } | Bytecode::SingleMoveSub { delta, to } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    if opt {
+                        access(bounds, *to as i64, true)?;
+                    }
+                    flow.merge(tier, pc + 1, bounds.zero(true));
+                }
+                Bytecode::DoubleMoveAddAdd { delta, to1, to2 } | Bytecode::DoubleMoveAddSub { delta, to1, to2 }
+                | Bytecode::DoubleMoveSubAdd { delta, to1, to2 } | Bytecode::DoubleMoveSubSub { delta, to1, to2 } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    if opt {
+                        access(bounds, *to1 as i64, true)?;
+                        access(bounds, *to2 as i64, true)?;
+                    }
+                    flow.merge(tier, pc + 1, bounds.zero(true));
+                }
+
+                Bytecode::JmpIfZero { delta, addr_abs } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    flow.merge(tier, *addr_abs as usize, bounds.zero(true));
+                    if !bounds.zero {
+                        flow.merge(tier, pc + 1, bounds);
+                    }
+                }
+                Bytecode::JmpIfNotZero { delta, addr_abs } => {
+                    let bounds = access(bounds.step(*delta as i64), 0, false)?;
+                    if !bounds.zero {
+                        flow.merge(tier, *addr_abs as usize, bounds);
+                    }
+                    flow.merge(tier, pc + 1, bounds.zero(true));
+                }
+                Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range } => {
+                    self.loop_end(&mut flow, tier, pc, bounds.step(*delta as i64), *addr_back, 0, Some(range.end), access)?;
+                }
+                Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range } => {
+                    self.loop_end(&mut flow, tier, pc, bounds.step(*delta as i64), *addr_back, range.start, None, access)?;
+                }
+                Bytecode::BothRangeCheckJNZ { delta, addr_back, range } => {
+                    self.loop_end(&mut flow, tier, pc, bounds.step(*delta as i64), *addr_back, range.start, Some(range.end), access)?;
+                }
+
+                Bytecode::End { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// `*RangeCheckJNZ`: inside `start..end` the loop goes on in the opt tier, outside it in the deopt tier.
+    #[allow(clippy::too_many_arguments)]
+    fn loop_end(&self, flow: &mut Flow, tier: usize, pc: usize, bounds: Bounds, addr_back: u16, start: u32, end: Option<u32>, access: impl Fn(Bounds, i64, bool) -> Result<Bounds, VerifyError>) -> Result<(), VerifyError> {
+        let back = pc - addr_back as usize;
+        let branch = |flow: &mut Flow, tier: usize, bounds: Bounds| {
+            if !bounds.zero {
+                flow.merge(tier, back, bounds);
+            }
+            flow.merge(tier, pc + 1, bounds.zero(true));
+        };
+        let inside = if tier == OPT { self.refine(bounds, start, end) } else { self.enter(bounds, start, end) };
+        if let Some(inside) = inside {
+            if tier == OPT {
+                access(inside, 0, false)?;
+            }
+            branch(flow, OPT, inside);
+        }
+        // 範囲外では検査付きで読んでから deopt
+        let outside = if self.checked_reads { bounds.inside(0) } else { bounds };
+        branch(flow, DEOPT, outside);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bytecode::{bytecode::{Bytecode, ir_to_bytecodes}, error::VerifyError, verify::VerifiedBytecode}, cell::Cell, codegen::tests::{PROGRAMS, TAPE_MODES}, ir::{ir::parse_to_ir, range::generate_range_info}, vm::tape::TapeMode};
+
+    /// Scans, nested and unbalanced loops, on top of the codegen programs.
+    const MORE: &[&str] = &[
+        "+>+>+>+[<]>[>]<[-<]",
+        ">>>>+[-<+>]<[->>+<<]",
+        "++[>++[>+>-<<-]<-]>>[>>+<<-]>>.",
+        ",[>,]<[.<]",
+        "+[>+>+<]",
+        "+[[>]+]",
+        "++++++++++++[>++++++++++++[>++++++++++++[>+>++>---<<<-]<-]<-]>>>.>.>.",
+        "+[>>>+<<<-]>>>[<<+>>-]<<[<]>[[-]>]",
+    ];
+
+    fn corpus() -> impl Iterator<Item = &'static str> {
+        PROGRAMS.iter().map(|(code, _)| *code).chain(MORE.iter().copied())
+    }
+
+    fn verify(insts: Vec<Bytecode<u8>>, tape_length: usize, opt_first: bool) -> Result<VerifiedBytecode<u8>, VerifyError> {
+        VerifiedBytecode::verify(insts, tape_length, TapeMode::Fixed, opt_first)
+    }
+
+    fn accepts_corpus<C: Cell>() {
+        for code in corpus() {
+            for mode in TAPE_MODES {
+                for tape_length in [1, 16, 30000] {
+                    let ir = parse_to_ir(code).unwrap();
+                    let range = generate_range_info(&ir, tape_length, mode).unwrap();
+                    let insts = ir_to_bytecodes::<C>(&ir, &range).unwrap();
+                    let case = format!("{code} on {tape_length} {mode:?} with {} bits", C::BITS);
+
+                    // コンパイラの出力はそのまま通り、verify_or_deopt でも範囲は残る
+                    let verified = VerifiedBytecode::verify(insts.clone(), tape_length, mode, range.do_opt_first)
+                        .unwrap_or_else(|err| panic!("{case}: {err}"));
+                    assert_eq!(verified.opt_first(), range.do_opt_first, "{case}");
+                    let deopted = VerifiedBytecode::verify_or_deopt(insts.clone(), tape_length, mode, range.do_opt_first).unwrap();
+                    assert_eq!(format!("{:?}", deopted.insts()), format!("{insts:?}"), "{case}");
+                    assert_eq!(deopted.opt_first(), range.do_opt_first, "{case}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_compiler_output_u8() {
+        accepts_corpus::<u8>();
+    }
+
+    #[test]
+    fn accepts_compiler_output_u16() {
+        accepts_corpus::<u16>();
+    }
+
+    #[test]
+    fn accepts_compiler_output_u32() {
+        accepts_corpus::<u32>();
+    }
+
+    #[test]
+    fn rejects_bad_jump_targets() {
+        let out_of_program = vec![Bytecode::MulStart { delta: 0, jz_abs: 5 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(out_of_program, 16, false), Err(VerifyError::JumpTarget(0, 5))));
+        let backwards = vec![Bytecode::SingleAdd { delta: 0, val: 1 }, Bytecode::MoveStart { delta: 0, jz_abs: 0 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(backwards, 16, false), Err(VerifyError::JumpTarget(1, 0))));
+    }
+
+    #[test]
+    fn rejects_unpaired_loops() {
+        let unclosed = vec![Bytecode::JmpIfZero { delta: 0, addr_abs: 1 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(unclosed, 16, false), Err(VerifyError::UnclosedLoop(0))));
+        let unopened = vec![Bytecode::JmpIfNotZero { delta: 0, addr_abs: 0 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(unopened, 16, false), Err(VerifyError::UnmatchedLoopEnd(0))));
+    }
+
+    #[test]
+    fn rejects_mismatched_loops() {
+        let paired = |jz: u32, jnz: u32| vec![
+            Bytecode::JmpIfZero { delta: 0, addr_abs: jz },
+            Bytecode::JmpIfNotZero { delta: 0, addr_abs: jnz },
+            Bytecode::End { delta: 0 },
+        ];
+        assert!(verify(paired(2, 1), 16, false).is_ok());
+        assert!(matches!(verify(paired(2, 0), 16, false), Err(VerifyError::LoopMismatch(1, 0))));
+        assert!(matches!(verify(paired(1, 1), 16, false), Err(VerifyError::LoopMismatch(1, 0))));
+
+        let range_check = |addr_back: u16| vec![
+            Bytecode::JmpIfZero { delta: 0, addr_abs: 3 },
+            Bytecode::SingleAdd { delta: 1, val: 1 },
+            Bytecode::PositiveRangeCheckJNZ { delta: 0, addr_back, range: ..15 },
+            Bytecode::End { delta: 0 },
+        ];
+        assert!(verify(range_check(1), 16, false).is_ok());
+        assert!(matches!(verify(range_check(2), 16, false), Err(VerifyError::LoopMismatch(2, 0))));
+    }
+
+    #[test]
+    fn rejects_misplaced_end() {
+        assert!(matches!(verify(vec![], 16, false), Err(VerifyError::MissingEnd)));
+        assert!(matches!(verify(vec![Bytecode::SingleAdd { delta: 0, val: 1 }], 16, false), Err(VerifyError::MissingEnd)));
+        let early = vec![Bytecode::End { delta: 0 }, Bytecode::SingleAdd { delta: 0, val: 1 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(early, 16, false), Err(VerifyError::EarlyEnd(0))));
+    }
+
+    #[test]
+    fn rejects_stray_operands() {
+        assert!(matches!(verify(vec![Bytecode::Mul { delta: 1, val: 2 }, Bytecode::End { delta: 0 }], 16, false), Err(VerifyError::StrayOperand(0))));
+        assert!(matches!(verify(vec![Bytecode::MoveAdd { delta: 1 }, Bytecode::End { delta: 0 }], 16, false), Err(VerifyError::StrayOperand(0))));
+        let wrong_kind = vec![Bytecode::MulStart { delta: 0, jz_abs: 2 }, Bytecode::MoveAdd { delta: 1 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(wrong_kind, 16, false), Err(VerifyError::StrayOperand(1))));
+        let wrong_kind = vec![Bytecode::MoveStart { delta: 0, jz_abs: 2 }, Bytecode::Mul { delta: 1, val: 2 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(verify(wrong_kind, 16, false), Err(VerifyError::StrayOperand(1))));
+    }
+
+    /// Leaves the pointer anywhere on the tape, on a non-zero cell, followed by `insts`.
+    fn anywhere(insts: Vec<Bytecode<u8>>) -> Vec<Bytecode<u8>> {
+        let mut prefix = vec![
+            Bytecode::SingleAdd { delta: 0, val: 1 },
+            Bytecode::Shift { delta: 0, step: 1 },
+            Bytecode::SingleAdd { delta: 0, val: 1 },
+        ];
+        prefix.extend(insts);
+        prefix.push(Bytecode::End { delta: 0 });
+        prefix
+    }
+
+    #[test]
+    fn rejects_ranges_wider_than_the_tape() {
+        // 範囲の端から delta だけ動いた先も、テープに収まっていなければならない
+        let both = |range| anywhere(vec![Bytecode::BothRangeCheck { range }, Bytecode::SingleAdd { delta: 1, val: 1 }]);
+        assert!(verify(both(0..15), 16, false).is_ok());
+        assert!(matches!(verify(both(0..16), 16, false), Err(VerifyError::UncheckedAccess(4))));
+
+        let shift = |end| anywhere(vec![Bytecode::ShiftP { delta: 0, step: 1, range: ..end }, Bytecode::SingleAdd { delta: 1, val: 1 }]);
+        assert!(verify(shift(15), 16, false).is_ok());
+        assert!(matches!(verify(shift(16), 16, false), Err(VerifyError::UncheckedAccess(4))));
+
+        let negative = |start| anywhere(vec![Bytecode::ShiftN { delta: 0, step: -1, range: start.. }, Bytecode::SingleAdd { delta: -2, val: 1 }]);
+        assert!(verify(negative(2), 16, false).is_ok());
+        assert!(matches!(verify(negative(1), 16, false), Err(VerifyError::UncheckedAccess(4))));
+    }
+
+    #[test]
+    fn rejects_opt_first_outside_the_tape() {
+        for delta in [-1, 16] {
+            let insts = || vec![Bytecode::SingleAdd { delta, val: 1 }, Bytecode::End { delta: 0 }];
+            assert!(matches!(verify(insts(), 16, true), Err(VerifyError::UncheckedAccess(0))), "{delta}");
+            // deopt は検査付きで触るので通る
+            assert!(verify(insts(), 16, false).is_ok(), "{delta}");
+            // 循環テープは opt でも剰余を取る
+            assert!(VerifiedBytecode::verify(insts(), 16, TapeMode::Wrapping, true).is_ok(), "{delta}");
+        }
+        let insts = vec![Bytecode::SingleAdd { delta: 15, val: 1 }, Bytecode::End { delta: 0 }];
+        assert!(verify(insts, 16, true).is_ok());
+    }
+
+    #[test]
+    fn deopts_only_on_unchecked_access() {
+        let insts = anywhere(vec![Bytecode::BothRangeCheck { range: 0..16 }, Bytecode::SingleAdd { delta: 1, val: 1 }]);
+        let verified = VerifiedBytecode::verify_or_deopt(insts, 16, TapeMode::Fixed, true).unwrap();
+        assert!(!verified.opt_first());
+        assert!(matches!(verified.insts()[3], Bytecode::BothRangeCheck { ref range } if range.is_empty()));
+
+        let stray = vec![Bytecode::Mul { delta: 1, val: 2 }, Bytecode::End { delta: 0 }];
+        assert!(matches!(VerifiedBytecode::<u8>::verify_or_deopt(stray, 16, TapeMode::Fixed, false), Err(VerifyError::StrayOperand(0))));
+    }
+}
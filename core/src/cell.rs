@@ -0,0 +1,103 @@
+use std::fmt::{Debug, Display};
+
+/// A tape cell. IR values are kept modulo 2^32 and truncated to the cell width when lowered,
+/// so `Add(u32::MAX)` is a decrement for every width.
+pub trait Cell: Copy + Eq + Default + Debug + Display + Send + Sync + 'static {
+    const ZERO: Self;
+    const MAX: Self;
+    const BITS: u32;
+
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+
+    fn from_u8(value: u8) -> Self;
+    fn truncate(value: u32) -> Self;
+    fn to_u8(self) -> u8;
+    fn to_u32(self) -> u32;
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {
+        $(
+            impl Cell for $t {
+                const ZERO: Self = 0;
+                const MAX: Self = <$t>::MAX;
+                const BITS: u32 = <$t>::BITS;
+
+                fn wrapping_add(self, rhs: Self) -> Self {
+                    <$t>::wrapping_add(self, rhs)
+                }
+                fn wrapping_sub(self, rhs: Self) -> Self {
+                    <$t>::wrapping_sub(self, rhs)
+                }
+                fn wrapping_mul(self, rhs: Self) -> Self {
+                    <$t>::wrapping_mul(self, rhs)
+                }
+
+                fn from_u8(value: u8) -> Self {
+                    value as $t
+                }
+                fn truncate(value: u32) -> Self {
+                    value as $t
+                }
+                fn to_u8(self) -> u8 {
+                    self as u8
+                }
+                fn to_u32(self) -> u32 {
+                    self as u32
+                }
+            }
+        )*
+    };
+}
+
+impl_cell!(u8, u16, u32);
+
+#[cfg(test)]
+mod tests {
+    use crate::{brainrot::{Brainrot, BrainrotInit}, cell::Cell};
+
+    /// The first cell after running `code`.
+    fn run<C: Cell>(code: &str) -> u32 {
+        let mut vm = Brainrot::<_, _, C>::new(code, BrainrotInit {
+            input: || None,
+            output: |_| {},
+            io_break: false,
+            timeout_step: None,
+            eof: Default::default(),
+            tape_length: 16,
+            tape_mode: Default::default(),
+        }).unwrap();
+        vm.step().unwrap();
+        vm.get_tape(0).unwrap().to_u32()
+    }
+
+    #[test]
+    fn truncate_keeps_the_low_bits() {
+        assert_eq!(u8::truncate(u32::MAX), u8::MAX);
+        assert_eq!(u16::truncate(u32::MAX), u16::MAX);
+        assert_eq!(u32::truncate(u32::MAX), u32::MAX);
+        assert_eq!(u8::truncate(0x1_0203), 0x03);
+        assert_eq!(u16::truncate(0x1_0203), 0x0203);
+    }
+
+    #[test]
+    fn decrement_wraps_at_each_width() {
+        assert_eq!(run::<u8>("-"), 0xff);
+        assert_eq!(run::<u16>("-"), 0xffff);
+        assert_eq!(run::<u32>("-"), u32::MAX);
+        assert_eq!(run::<u16>("--+"), 0xffff);
+    }
+
+    #[test]
+    fn increment_wraps_at_each_width() {
+        let code = "+".repeat(256);
+        assert_eq!(run::<u8>(&code), 0);
+        assert_eq!(run::<u16>(&code), 256);
+        // ループで 65536 回足す
+        let code = "++++++++++++++++[>++++++++++++++++<-]>[>++++++++++++++++[>++++++++++++++++<-]<-]>>[<<<+>>>-]";
+        assert_eq!(run::<u16>(code), 0);
+        assert_eq!(run::<u32>(code), 65536);
+    }
+}
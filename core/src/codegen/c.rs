@@ -0,0 +1,235 @@
+use crate::{cell::Cell, codegen::CodegenInit, ir::ir::{IR, IROp}, vm::{program::EofPolicy, tape::TapeMode}};
+
+const HEADER: &str = "\
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+";
+
+const OOB: &str = "
+static inline void oob(ptrdiff_t i) {
+    fflush(stdout);
+    fprintf(stderr, \"RuntimeError: Out of bounds at cell %td\\n\", i);
+    exit(2);
+}
+";
+
+const ACCESS_FIXED: &str = "
+static inline cell_t *at(ptrdiff_t i) {
+    if (i < 0 || i >= tape_length) oob(i);
+    return &tape[i];
+}
+static inline cell_t get(ptrdiff_t i) {
+    return *at(i);
+}
+";
+
+const ACCESS_WRAPPING: &str = "
+static inline cell_t *at(ptrdiff_t i) {
+    if (tape_length == 0) oob(i);
+    i %= tape_length;
+    if (i < 0) i += tape_length;
+    return &tape[i];
+}
+static inline cell_t get(ptrdiff_t i) {
+    return *at(i);
+}
+";
+
+// インタプリタの Growable と同じく、左に伸ばすときは今の長さ以上、右に伸ばすときは倍にする
+const ACCESS_GROWABLE: &str = "
+#define GROWABLE_MAX_LENGTH ((ptrdiff_t)INT32_MAX)
+static ptrdiff_t origin = 0;
+static inline cell_t *at(ptrdiff_t i) {
+    ptrdiff_t j = i + origin;
+    if (j < 0) {
+        ptrdiff_t extra = -j > tape_length ? -j : tape_length;
+        if (tape_length + extra > GROWABLE_MAX_LENGTH) oob(i);
+        cell_t *grown = calloc(tape_length + extra, sizeof(cell_t));
+        if (!grown) oob(i);
+        memcpy(grown + extra, tape, tape_length * sizeof(cell_t));
+        free(tape);
+        tape = grown;
+        tape_length += extra;
+        origin += extra;
+        j += extra;
+    } else if (j >= tape_length) {
+        if (j >= GROWABLE_MAX_LENGTH) oob(i);
+        ptrdiff_t length = j + 1 > tape_length * 2 ? j + 1 : tape_length * 2;
+        if (length > GROWABLE_MAX_LENGTH) length = GROWABLE_MAX_LENGTH;
+        cell_t *grown = realloc(tape, length * sizeof(cell_t));
+        if (!grown) oob(i);
+        memset(grown + tape_length, 0, (length - tape_length) * sizeof(cell_t));
+        tape = grown;
+        tape_length = length;
+    }
+    return &tape[j];
+}
+static inline cell_t get(ptrdiff_t i) {
+    ptrdiff_t j = i + origin;
+    return (j < 0 || j >= tape_length) ? 0 : tape[j];
+}
+";
+
+fn cell_type<C: Cell>() -> &'static str {
+    match C::BITS {
+        8 => "uint8_t",
+        16 => "uint16_t",
+        _ => "uint32_t",
+    }
+}
+
+fn offset(delta: isize) -> String {
+    match delta {
+        0 => "p".to_owned(),
+        d if d < 0 => format!("p - {}", d.unsigned_abs()),
+        d => format!("p + {}", d),
+    }
+}
+
+/// Generates a C translation unit that behaves like the interpreter: same tape length and mode, cell width and EOF policy.
+/// Out of bounds accesses print to stderr and exit with status 2, like the CLI.
+pub fn ir_to_c<C: Cell>(ir_nodes: &[IR], init: &CodegenInit) -> String {
+    let mut str = String::new();
+
+    str += HEADER;
+    str += &format!("\ntypedef {} cell_t;\n", cell_type::<C>());
+    str += &format!("static cell_t *tape;\nstatic ptrdiff_t tape_length = {};\n", init.tape_length);
+    str += OOB;
+    str += match init.tape_mode {
+        TapeMode::Fixed => ACCESS_FIXED,
+        TapeMode::Growable => ACCESS_GROWABLE,
+        TapeMode::Wrapping => ACCESS_WRAPPING,
+    };
+    str += "\nstatic inline void input(ptrdiff_t i) {\n    int c = getchar();\n    if (c != EOF) {\n        *at(i) = (cell_t)c;\n        return;\n    }\n";
+    str += match init.eof {
+        EofPolicy::Zero => "    *at(i) = 0;\n",
+        EofPolicy::MinusOne => "    *at(i) = (cell_t)-1;\n",
+        EofPolicy::Unchanged => "    (void)get(i);\n",
+    };
+    str += "}\n";
+
+    str += "\nint main(void) {\n    tape = calloc(tape_length ? tape_length : 1, sizeof(cell_t));\n    if (!tape) return 1;\n    ptrdiff_t p = 0;\n    cell_t m;\n    (void)m;\n";
+
+    let mut lv: usize = 1;
+    let mut last_ptr = 0isize;
+    for node in ir_nodes {
+        let delta = node.pointer.wrapping_sub(last_ptr);
+        last_ptr = node.pointer;
+
+        if let IROp::LoopEnd(..) | IROp::LoopEndWithOffset(..) = node.opcode {
+            if delta != 0 {
+                str += &format!("{}p += {};\n", "    ".repeat(lv), delta);
+            }
+            lv -= 1;
+            str += &format!("{}}}\n", "    ".repeat(lv));
+            if let IROp::LoopEndWithOffset(_, offset) = node.opcode {
+                last_ptr -= offset;
+            }
+            continue;
+        }
+
+        let indent = "    ".repeat(lv);
+        if delta != 0 {
+            str += &format!("{}p += {};\n", indent, delta);
+        }
+        match &node.opcode {
+            IROp::Breakpoint => {
+                str += &format!("{}fprintf(stderr, \"PTR: %td\\n\", p);\n", indent);
+            }
+            IROp::Add(val) => {
+                str += &format!("{}*at(p) += {}u;\n", indent, C::truncate(*val));
+            }
+            IROp::Set(val) => {
+                str += &format!("{}*at(p) = {}u;\n", indent, C::truncate(*val));
+            }
+            IROp::Shift(step) => {
+                str += &format!("{}while (get(p)) p += {};\n", indent, step);
+            }
+            IROp::MulAndSetZero(dests) => {
+                str += &format!("{}m = get(p);\n{}if (m) {{\n", indent, indent);
+                str += &format!("{}    *at(p) = 0;\n", indent);
+                for (dest_ptr, dest_val) in dests {
+                    str += &format!("{}    *at({}) += (cell_t)((uint32_t)m * {}u);\n", indent, offset(dest_ptr - last_ptr), C::truncate(*dest_val));
+                }
+                str += &format!("{}}}\n", indent);
+            }
+            IROp::MovesAndSetZero(dests) => {
+                str += &format!("{}m = get(p);\n{}if (m) {{\n", indent, indent);
+                str += &format!("{}    *at(p) = 0;\n", indent);
+                for (dest_ptr, is_pos) in dests {
+                    str += &format!("{}    *at({}) {}= m;\n", indent, offset(dest_ptr - last_ptr), if *is_pos { "+" } else { "-" });
+                }
+                str += &format!("{}}}\n", indent);
+            }
+            IROp::In => {
+                str += &format!("{}input(p);\n", indent);
+            }
+            IROp::Out => {
+                str += &format!("{}putchar((unsigned char)get(p));\n", indent);
+            }
+            IROp::LoopStart(_) => {
+                str += &format!("{}while (get(p)) {{\n", indent);
+                lv += 1;
+            }
+            IROp::LoopEnd(..) | IROp::LoopEndWithOffset(..) => unreachable!(),
+            IROp::End => {}
+        }
+    }
+
+    str += "    free(tape);\n    return 0;\n}\n";
+    str
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::Path, process::{Command, Stdio}};
+
+    use crate::{cell::Cell, codegen::{CodegenInit, compile_to_c, tests::{EOF_POLICIES, PROGRAMS, TAPE_MODES, interpret}}, vm::program::EofPolicy};
+
+    /// Builds and runs the C for `code`, returning what it printed and whether it exited with the runtime error status.
+    fn run_c<C: Cell>(dir: &Path, code: &str, init: &CodegenInit, input: &[u8]) -> (Vec<u8>, bool) {
+        let source = dir.join("main.c");
+        let binary = dir.join("main");
+        fs::write(&source, compile_to_c::<C>(code, init).unwrap()).unwrap();
+        let status = Command::new("cc").arg("-O1").arg("-o").arg(&binary).arg(&source).status().unwrap();
+        assert!(status.success(), "cc failed for {code}");
+
+        let mut child = Command::new(&binary).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        (output.stdout, output.status.code() == Some(2))
+    }
+
+    fn check<C: Cell>(dir: &Path, code: &str, init: &CodegenInit, input: &[u8]) {
+        assert_eq!(run_c::<C>(dir, code, init, input), interpret::<C>(code, init, input), "{code} {init:?} {}-bit", C::BITS);
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        // cc がない環境では飛ばす
+        if Command::new("cc").arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_err() {
+            eprintln!("cc not found, skipping");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("brainrot-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (code, input) in PROGRAMS {
+            for tape_mode in TAPE_MODES {
+                let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode };
+                check::<u8>(&dir, code, &init, input);
+                check::<u16>(&dir, code, &init, input);
+                check::<u32>(&dir, code, &init, input);
+            }
+        }
+        for eof in EOF_POLICIES {
+            let init = CodegenInit { eof, tape_length: 64, tape_mode: TAPE_MODES[0] };
+            check::<u8>(&dir, ",.,.,.", &init, b"a");
+            check::<u16>(&dir, "+,.", &init, b"");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
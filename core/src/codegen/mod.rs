@@ -0,0 +1,70 @@
+use crate::{cell::Cell, error::BrainrotError, ir::{ir::parse_to_ir, range::generate_range_info}, vm::{program::EofPolicy, tape::TapeMode}};
+
+pub mod c;
+pub mod rust;
+pub mod wasm;
+
+/// Settings baked into generated code. They mean the same as the fields of the same name in `BrainrotInit`.
+#[derive(Clone, Copy, Debug)]
+pub struct CodegenInit {
+    pub eof: EofPolicy,
+    pub tape_length: usize,
+    pub tape_mode: TapeMode,
+}
+
+/// Compiles a Brainfuck program to a standalone C translation unit.
+pub fn compile_to_c<C: Cell>(code: &str, init: &CodegenInit) -> Result<String, BrainrotError> {
+    let ir = parse_to_ir(code)?;
+    Ok(c::ir_to_c::<C>(&ir, init))
+}
+
+/// Compiles a Brainfuck program to a binary WebAssembly module. See `wasm::ir_to_wasm` for the host interface.
+pub fn compile_to_wasm<C: Cell>(code: &str, init: &CodegenInit) -> Result<Vec<u8>, BrainrotError> {
+    let ir = parse_to_ir(code)?;
+    wasm::ir_to_wasm::<C>(&ir, init)
+}
+
+/// Compiles a Brainfuck program to Rust source, for use from a `build.rs`. See `rust::ir_to_rust` for the generated interface.
+pub fn compile_to_rust<C: Cell>(code: &str, init: &CodegenInit) -> Result<String, BrainrotError> {
+    let ir = parse_to_ir(code)?;
+    let range_info = generate_range_info(&ir, init.tape_length, init.tape_mode)?;
+    Ok(rust::ir_to_rust::<C>(&ir, &range_info, init))
+}
+
+/// Shared by the backends' tests, which run the generated code and compare it with the interpreter.
+#[cfg(test)]
+pub mod tests {
+    use crate::{brainrot::{Brainrot, BrainrotInit}, cell::Cell, codegen::CodegenInit, vm::{program::EofPolicy, tape::TapeMode}};
+
+    /// Programs that end on every tape mode, each with the input to give it.
+    pub const PROGRAMS: &[(&str, &[u8])] = &[
+        ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", b""),
+        (",[.,]", b"hello"),
+        (",.,.,.", b"a"),
+        ("++++++++[>++++++++<-]>[>+>++>---<<<-]>.>.>.", b""),
+        ("++++++[>+++++[>+>->+<<<-]<-]>>.>.>.", b""),
+        ("+>+>+>+>+[<]>.<<+.", b""),
+        ("-.>--.>---[>+<-]>.", b""),
+        ("+[>[-]+>+<<-]>>.>>>>>.", b""),
+    ];
+    pub const TAPE_MODES: [TapeMode; 3] = [TapeMode::Fixed, TapeMode::Growable, TapeMode::Wrapping];
+    pub const EOF_POLICIES: [EofPolicy; 3] = [EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Unchanged];
+
+    /// What the interpreter prints, and whether it stopped with a runtime error.
+    pub fn interpret<C: Cell>(code: &str, init: &CodegenInit, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut output = vec![];
+        let mut input = input.iter().copied();
+        let mut vm = Brainrot::<_, _, C>::new(code, BrainrotInit {
+            input: || input.next(),
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: None,
+            eof: init.eof,
+            tape_length: init.tape_length,
+            tape_mode: init.tape_mode,
+        }).unwrap();
+        let failed = vm.step().is_err();
+        drop(vm);
+        (output, failed)
+    }
+}
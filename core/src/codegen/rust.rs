@@ -0,0 +1,345 @@
+use crate::{cell::Cell, codegen::CodegenInit, ir::{ir::{IR, IROp}, range::{MidRange, RangeInfo}}, vm::{program::EofPolicy, tape::TapeMode}};
+
+const HEADER: &str = "\
+// Generated by brainrot. Do not edit.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfBounds(pub usize);
+impl std::fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, \"Out of bounds at cell {}\", self.0)
+    }
+}
+impl std::error::Error for OutOfBounds {}
+
+#[allow(dead_code)]
+struct Tape {
+    buffer: Vec<Cell>,
+    p: usize,
+}
+";
+
+const TAPE_COMMON: &str = "
+    fn step(&mut self, d: isize) {
+        self.p = self.p.wrapping_add_signed(d);
+    }
+    fn at(&mut self, d: isize) -> Result<&mut Cell, OutOfBounds> {
+        let i = self.index(d)?;
+        Ok(&mut self.buffer[i])
+    }
+    /// # Safety
+    /// The caller has checked, via the range analysis, that `p + d` is inside the buffer.
+    unsafe fn at_unchecked(&mut self, d: isize) -> &mut Cell {
+        let i = self.p.wrapping_add_signed(d);
+        debug_assert!(i < self.buffer.len());
+        unsafe { self.buffer.get_unchecked_mut(i) }
+    }
+    fn can_opt(&self, start: usize, end: usize) -> bool {
+        self.p < self.buffer.len() && start <= self.p && self.p < end
+    }
+";
+
+const TAPE_FIXED: &str = "
+    fn index(&mut self, d: isize) -> Result<usize, OutOfBounds> {
+        let i = self.p.wrapping_add_signed(d);
+        if i < self.buffer.len() { Ok(i) } else { Err(OutOfBounds(i)) }
+    }
+    fn get(&self, d: isize) -> Result<Cell, OutOfBounds> {
+        let i = self.p.wrapping_add_signed(d);
+        self.buffer.get(i).copied().ok_or(OutOfBounds(i))
+    }
+";
+
+const TAPE_WRAPPING: &str = "
+    fn index(&mut self, d: isize) -> Result<usize, OutOfBounds> {
+        let i = self.p.wrapping_add_signed(d);
+        if self.buffer.is_empty() {
+            return Err(OutOfBounds(i));
+        }
+        Ok((i as isize).rem_euclid(self.buffer.len() as isize) as usize)
+    }
+    fn get(&self, d: isize) -> Result<Cell, OutOfBounds> {
+        let i = self.p.wrapping_add_signed(d);
+        if self.buffer.is_empty() {
+            return Err(OutOfBounds(i));
+        }
+        Ok(self.buffer[(i as isize).rem_euclid(self.buffer.len() as isize) as usize])
+    }
+";
+
+const TAPE_GROWABLE: &str = "
+    fn index(&mut self, d: isize) -> Result<usize, OutOfBounds> {
+        const MAX_LENGTH: usize = i32::MAX as usize;
+        let i = self.p.wrapping_add_signed(d);
+        let len = self.buffer.len();
+        if i < len {
+            return Ok(i);
+        }
+        if (i as isize) < 0 {
+            let extra = (i as isize).unsigned_abs().max(len);
+            if len + extra > MAX_LENGTH {
+                return Err(OutOfBounds(i));
+            }
+            let mut buffer = vec![0; len + extra];
+            buffer[extra..].copy_from_slice(&self.buffer);
+            self.buffer = buffer;
+            self.p = self.p.wrapping_add(extra);
+            Ok(i.wrapping_add(extra))
+        } else {
+            if i >= MAX_LENGTH {
+                return Err(OutOfBounds(i));
+            }
+            self.buffer.resize((i + 1).max(len * 2).min(MAX_LENGTH), 0);
+            Ok(i)
+        }
+    }
+    fn get(&self, d: isize) -> Result<Cell, OutOfBounds> {
+        Ok(self.buffer.get(self.p.wrapping_add_signed(d)).copied().unwrap_or(0))
+    }
+";
+
+fn cell_type<C: Cell>() -> &'static str {
+    match C::BITS {
+        8 => "u8",
+        16 => "u16",
+        _ => "u32",
+    }
+}
+
+/// The `checked = ...` statement for a range check point, mirroring when the interpreter switches tiers.
+fn range_check(range: &MidRange) -> Option<String> {
+    match range {
+        MidRange::None => None,
+        MidRange::Negative(r) => Some(format!("checked = !tape.can_opt({}, usize::MAX);", r.start)),
+        MidRange::Positive(r) => Some(format!("checked = !tape.can_opt(0, {});", r.end)),
+        MidRange::Both(r) => Some(format!("checked = !tape.can_opt({}, {});", r.start, r.end)),
+    }
+}
+
+/// Generates a Rust module exposing `run(input: impl FnMut() -> Option<u8>, output: impl FnMut(u8)) -> Result<(), OutOfBounds>`,
+/// meant to be written from a `build.rs` and pulled in with `include!`. `input` returns `None` at EOF rather than a byte,
+/// so that the EOF policy applies, and an out of bounds access returns `Err` instead of panicking.
+/// It behaves like `Brainrot::step`: cells are only bounds checked where the interpreter would be in the deopt tier,
+/// which is tracked with the same `RangeInfo` conditions.
+pub fn ir_to_rust<C: Cell>(ir_nodes: &[IR], range_info: &RangeInfo, init: &CodegenInit) -> String {
+    let mut str = String::new();
+
+    str += &format!("pub type Cell = {};\npub const TAPE_LENGTH: usize = {};\n\n", cell_type::<C>(), init.tape_length);
+    str += HEADER;
+    str += "#[allow(dead_code)]\nimpl Tape {";
+    str += TAPE_COMMON;
+    str += match init.tape_mode {
+        TapeMode::Fixed => TAPE_FIXED,
+        TapeMode::Growable => TAPE_GROWABLE,
+        TapeMode::Wrapping => TAPE_WRAPPING,
+    };
+    str += "}\n\n";
+
+    // 循環テープはどのアクセスも剰余を取る必要があるので、常にチェックありの経路を使う
+    let always_checked = init.tape_mode == TapeMode::Wrapping;
+    let checked_init = always_checked || !range_info.do_opt_first;
+
+    str += "#[allow(unused_mut, unused_variables, unused_assignments, unused_macros, unused_unsafe, clippy::all)]\n";
+    str += "pub fn run(mut input: impl FnMut() -> Option<u8>, mut output: impl FnMut(u8)) -> Result<(), OutOfBounds> {\n";
+    str += "    let mut tape = Tape { buffer: vec![0; TAPE_LENGTH], p: 0 };\n";
+    str += &format!("    let mut checked = {};\n", checked_init);
+    str += "    let mut m: Cell = 0;\n";
+    str += "    macro_rules! cell {\n        ($d:expr) => {\n            *(if checked { tape.at($d)? } else { unsafe { tape.at_unchecked($d) } })\n        };\n    }\n";
+    str += "    macro_rules! get {\n        ($d:expr) => {\n            if checked { tape.get($d)? } else { unsafe { *tape.at_unchecked($d) } }\n        };\n    }\n";
+
+    let mut lv: usize = 1;
+    let mut last_ptr = 0isize;
+    for (i, node) in ir_nodes.iter().enumerate() {
+        let delta = node.pointer.wrapping_sub(last_ptr);
+        last_ptr = node.pointer;
+
+        let check = if always_checked { None } else { range_info.map.get(&i).and_then(range_check) };
+
+        if let IROp::LoopEnd(..) | IROp::LoopEndWithOffset(..) = node.opcode {
+            let indent = "    ".repeat(lv);
+            if delta != 0 {
+                str += &format!("{}tape.step({});\n", indent, delta);
+            }
+            if let Some(check) = check {
+                str += &format!("{}{}\n", indent, check);
+            }
+            lv -= 1;
+            str += &format!("{}}}\n", "    ".repeat(lv));
+            if let IROp::LoopEndWithOffset(_, offset) = node.opcode {
+                last_ptr -= offset;
+            }
+            continue;
+        }
+
+        let indent = "    ".repeat(lv);
+        if delta != 0 {
+            str += &format!("{}tape.step({});\n", indent, delta);
+        }
+        match &node.opcode {
+            IROp::Breakpoint => {
+                str += &format!("{}eprintln!(\"PTR: {{}}\", tape.p);\n", indent);
+            }
+            IROp::Add(val) => {
+                str += &format!("{}cell!(0) = cell!(0).wrapping_add({});\n", indent, C::truncate(*val));
+            }
+            IROp::Set(val) => {
+                str += &format!("{}cell!(0) = {};\n", indent, C::truncate(*val));
+            }
+            IROp::Shift(step) => {
+                // スキャンは opt でも常にチェックする。Growable で確保済みの範囲を出たら deopt と同じくチェックありに戻す
+                str += &format!("{}while tape.get(0)? != 0 {{\n{}    tape.step({});\n{}}}\n", indent, indent, step, indent);
+                if !always_checked {
+                    str += &format!("{}if tape.p >= tape.buffer.len() {{\n{}    checked = true;\n{}}}\n", indent, indent, indent);
+                }
+                if let Some(check) = check {
+                    str += &format!("{}{}\n", indent, check);
+                }
+            }
+            IROp::MulAndSetZero(dests) => {
+                str += &format!("{}m = get!(0);\n{}if m != 0 {{\n", indent, indent);
+                str += &format!("{}    cell!(0) = 0;\n", indent);
+                for (dest_ptr, dest_val) in dests {
+                    let d = dest_ptr - last_ptr;
+                    str += &format!("{}    cell!({}) = cell!({}).wrapping_add(m.wrapping_mul({}));\n", indent, d, d, C::truncate(*dest_val));
+                }
+                str += &format!("{}}}\n", indent);
+            }
+            IROp::MovesAndSetZero(dests) => {
+                str += &format!("{}m = get!(0);\n{}if m != 0 {{\n", indent, indent);
+                str += &format!("{}    cell!(0) = 0;\n", indent);
+                for (dest_ptr, is_pos) in dests {
+                    let d = dest_ptr - last_ptr;
+                    str += &format!("{}    cell!({}) = cell!({}).{}(m);\n", indent, d, d, if *is_pos { "wrapping_add" } else { "wrapping_sub" });
+                }
+                str += &format!("{}}}\n", indent);
+            }
+            IROp::In => {
+                str += &format!("{}match input() {{\n", indent);
+                str += &format!("{}    Some(v) => cell!(0) = v as Cell,\n", indent);
+                str += &format!("{}    None => {}\n", indent, match init.eof {
+                    EofPolicy::Zero => "cell!(0) = 0,",
+                    EofPolicy::MinusOne => "cell!(0) = Cell::MAX,",
+                    EofPolicy::Unchanged => "{ get!(0); }",
+                });
+                str += &format!("{}}}\n", indent);
+            }
+            IROp::Out => {
+                str += &format!("{}output(get!(0) as u8);\n", indent);
+            }
+            IROp::LoopStart(_) => {
+                str += &format!("{}while get!(0) != 0 {{\n", indent);
+                lv += 1;
+            }
+            IROp::LoopEnd(..) | IROp::LoopEndWithOffset(..) => unreachable!(),
+            IROp::End => {}
+        }
+    }
+
+    str += "    Ok(())\n}\n";
+    str
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::Path, process::{Command, Stdio}};
+
+    use crate::{cell::Cell, codegen::{CodegenInit, compile_to_rust, tests::{EOF_POLICIES, PROGRAMS, TAPE_MODES, interpret}}, vm::{program::EofPolicy, tape::TapeMode}};
+
+    /// Calls `run` of the module picked by the first argument, and exits with 2 when it returns `OutOfBounds`.
+    const MAIN: &str = "
+fn main() {
+    use std::io::{Read, Write};
+    let mut input = vec![];
+    std::io::stdin().read_to_end(&mut input).unwrap();
+    let mut input = input.into_iter();
+    let mut output = vec![];
+    let run = RUNS[std::env::args().nth(1).unwrap().parse::<usize>().unwrap()];
+    let result = run(&mut || input.next(), &mut |value| output.push(value));
+    std::io::stdout().write_all(&output).unwrap();
+    std::process::exit(if result.is_err() { 2 } else { 0 });
+}
+";
+
+    struct Case {
+        label: String,
+        source: String,
+        input: &'static [u8],
+        expected: (Vec<u8>, bool),
+    }
+    fn case<C: Cell>(code: &str, init: CodegenInit, input: &'static [u8]) -> Case {
+        Case {
+            label: format!("{code} {init:?} {}-bit", C::BITS),
+            source: compile_to_rust::<C>(code, &init).unwrap(),
+            input,
+            expected: interpret::<C>(code, &init, input),
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        // rustc がない環境では飛ばす
+        if Command::new("rustc").arg("--version").stdout(Stdio::null()).status().is_err() {
+            eprintln!("rustc not found, skipping");
+            return;
+        }
+
+        let mut cases = vec![];
+        for (code, input) in PROGRAMS {
+            for tape_mode in TAPE_MODES {
+                let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode };
+                cases.push(case::<u8>(code, init, input));
+                cases.push(case::<u16>(code, init, input));
+                cases.push(case::<u32>(code, init, input));
+            }
+        }
+        // チェックなしの経路のまま端まで進んで、範囲チェックで戻るもの
+        for code in ["+[>+]", "+[>+>+<]", "+[>>+<]>>>>[<]"] {
+            let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode: TapeMode::Fixed };
+            cases.push(case::<u8>(code, init, b""));
+            cases.push(case::<u32>(code, init, b""));
+        }
+        for eof in EOF_POLICIES {
+            let init = CodegenInit { eof, tape_length: 64, tape_mode: TapeMode::Fixed };
+            cases.push(case::<u8>(",.,.,.", init, b"a"));
+            cases.push(case::<u16>("+,.", init, b""));
+        }
+
+        // 1 回の rustc で済むように、全部のモジュールを 1 つのバイナリに入れる
+        let mut source = String::new();
+        for (i, case) in cases.iter().enumerate() {
+            source += &format!("mod case{} {{\n{}}}\n", i, case.source);
+        }
+        source += "type Run = fn(&mut dyn FnMut() -> Option<u8>, &mut dyn FnMut(u8)) -> Result<(), ()>;\nconst RUNS: &[Run] = &[\n";
+        for i in 0..cases.len() {
+            source += &format!("    |input, output| case{}::run(input, output).map_err(|_| ()),\n", i);
+        }
+        source += "];\n";
+        source += MAIN;
+
+        let dir = std::env::temp_dir().join(format!("brainrot-rust-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let binary = build(&dir, &source);
+        for (i, case) in cases.iter().enumerate() {
+            let mut child = Command::new(&binary).arg(i.to_string()).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+            child.stdin.take().unwrap().write_all(case.input).unwrap();
+            let output = child.wait_with_output().unwrap();
+            // debug_assert に引っかかると 101 で落ちる
+            assert!(matches!(output.status.code(), Some(0 | 2)), "{}: {}", case.label, String::from_utf8_lossy(&output.stderr));
+            assert_eq!((output.stdout, output.status.code() == Some(2)), case.expected, "{}", case.label);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Builds with debug assertions on, so that `at_unchecked` catches accesses the range analysis should have ruled out.
+    fn build(dir: &Path, source: &str) -> std::path::PathBuf {
+        let main = dir.join("main.rs");
+        let binary = dir.join("main");
+        fs::write(&main, source).unwrap();
+        let output = Command::new("rustc")
+            .args(["--edition", "2021", "-C", "debug-assertions=on", "-C", "opt-level=1", "-o"])
+            .arg(&binary).arg(&main)
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        binary
+    }
+}
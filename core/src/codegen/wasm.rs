@@ -0,0 +1,428 @@
+use crate::{cell::Cell, codegen::CodegenInit, error::BrainrotError, ir::{error::RangeError, ir::{IR, IROp}}, vm::{program::EofPolicy, tape::TapeMode}};
+
+// 生成するモジュールの形:
+//   (import "env" "input" (func (result i32)))    ; EOF なら -1
+//   (import "env" "output" (func (param i32)))
+//   (memory (export "memory") N)                  ; テープは 0 番地から
+//   (func $addr (param i32) (result i32))         ; セル番号 -> バイトアドレス。範囲外なら unreachable
+//   (func (export "run"))
+
+const I32: u8 = 0x7F;
+
+const FUNC_INPUT: u32 = 0;
+const FUNC_OUTPUT: u32 = 1;
+const FUNC_ADDR: u32 = 2;
+
+const LOCAL_P: u32 = 0;
+const LOCAL_M: u32 = 1;
+const LOCAL_A: u32 = 2;
+const LOCAL_C: u32 = 3;
+
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0B;
+    pub const BR: u8 = 0x0C;
+    pub const BR_IF: u8 = 0x0D;
+    pub const CALL: u8 = 0x10;
+    pub const DROP: u8 = 0x1A;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_EQ: u8 = 0x46;
+    pub const I32_LT_S: u8 = 0x48;
+    pub const I32_GE_U: u8 = 0x4F;
+    pub const I32_ADD: u8 = 0x6A;
+    pub const I32_SUB: u8 = 0x6B;
+    pub const I32_MUL: u8 = 0x6C;
+    pub const I32_REM_S: u8 = 0x6F;
+    pub const I32_AND: u8 = 0x71;
+    pub const VOID: u8 = 0x40;
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+fn section(out: &mut Vec<u8>, id: u8, count: u32, contents: &[u8]) {
+    let mut body = vec![];
+    uleb(&mut body, count as u64);
+    body.extend_from_slice(contents);
+    out.push(id);
+    uleb(out, body.len() as u64);
+    out.extend_from_slice(&body);
+}
+
+/// Instruction emitter for one function body.
+struct Body<C: Cell> {
+    code: Vec<u8>,
+    _cell: std::marker::PhantomData<C>,
+}
+impl<C: Cell> Body<C> {
+    fn new() -> Body<C> {
+        Body { code: vec![], _cell: std::marker::PhantomData }
+    }
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+    fn local(&mut self, op: u8, index: u32) {
+        self.code.push(op);
+        uleb(&mut self.code, index as u64);
+    }
+    fn i32_const(&mut self, value: i64) {
+        self.code.push(op::I32_CONST);
+        // i32 として解釈されるので、u32 の値は符号付きに直してから書く
+        sleb(&mut self.code, value as i32 as i64);
+    }
+    fn call(&mut self, func: u32) {
+        self.local(op::CALL, func);
+    }
+    fn align(&self) -> u8 {
+        (C::BITS / 8).trailing_zeros() as u8
+    }
+    fn load(&mut self) {
+        self.code.push(match C::BITS { 8 => 0x2D, 16 => 0x2F, _ => 0x28 });
+        self.code.extend_from_slice(&[self.align(), 0]);
+    }
+    fn store(&mut self) {
+        self.code.push(match C::BITS { 8 => 0x3A, 16 => 0x3B, _ => 0x36 });
+        self.code.extend_from_slice(&[self.align(), 0]);
+    }
+
+    fn step(&mut self, delta: isize) {
+        if delta != 0 {
+            self.local(op::LOCAL_GET, LOCAL_P);
+            self.i32_const(delta as i64);
+            self.op(op::I32_ADD);
+            self.local(op::LOCAL_SET, LOCAL_P);
+        }
+    }
+    /// Pushes the byte address of the cell at `p + delta`.
+    fn addr(&mut self, delta: isize) {
+        self.local(op::LOCAL_GET, LOCAL_P);
+        if delta != 0 {
+            self.i32_const(delta as i64);
+            self.op(op::I32_ADD);
+        }
+        self.call(FUNC_ADDR);
+    }
+    /// Pushes the value of the cell at `p`.
+    fn get(&mut self) {
+        self.addr(0);
+        self.load();
+    }
+    fn set(&mut self, delta: isize, value: u32) {
+        self.addr(delta);
+        self.i32_const(value as i64);
+        self.store();
+    }
+    /// `cell[p + delta] (+|-)= <value pushed by f>`
+    fn modify(&mut self, delta: isize, negate: bool, f: impl FnOnce(&mut Self)) {
+        self.addr(delta);
+        self.local(op::LOCAL_TEE, LOCAL_A);
+        self.local(op::LOCAL_GET, LOCAL_A);
+        self.load();
+        f(self);
+        self.op(if negate { op::I32_SUB } else { op::I32_ADD });
+        self.store();
+    }
+    /// `block loop (br_if 1 (cell[p] == 0)) ...`
+    fn loop_head(&mut self) {
+        self.code.extend_from_slice(&[op::BLOCK, op::VOID, op::LOOP, op::VOID]);
+        self.get();
+        self.op(op::I32_EQZ);
+        self.code.extend_from_slice(&[op::BR_IF, 1]);
+    }
+    fn loop_tail(&mut self) {
+        self.code.extend_from_slice(&[op::BR, 0, op::END, op::END]);
+    }
+    fn finish(mut self, locals: &[(u32, u8)]) -> Vec<u8> {
+        self.op(op::END);
+        let mut out = vec![];
+        uleb(&mut out, locals.len() as u64);
+        for (count, ty) in locals {
+            uleb(&mut out, *count as u64);
+            out.push(*ty);
+        }
+        out.extend_from_slice(&self.code);
+        let mut sized = vec![];
+        uleb(&mut sized, out.len() as u64);
+        sized.extend_from_slice(&out);
+        sized
+    }
+}
+
+fn addr_func<C: Cell>(init: &CodegenInit) -> Vec<u8> {
+    let mut body = Body::<C>::new();
+    let length = init.tape_length as i64;
+    match init.tape_mode {
+        TapeMode::Wrapping if length != 0 => {
+            body.local(op::LOCAL_GET, 0);
+            body.i32_const(length);
+            body.op(op::I32_REM_S);
+            body.local(op::LOCAL_TEE, 0);
+            body.i32_const(0);
+            body.op(op::I32_LT_S);
+            body.code.extend_from_slice(&[op::IF, op::VOID]);
+            body.local(op::LOCAL_GET, 0);
+            body.i32_const(length);
+            body.op(op::I32_ADD);
+            body.local(op::LOCAL_SET, 0);
+            body.op(op::END);
+        }
+        _ => {
+            // 負のセル番号も符号なしで比べれば範囲外になる
+            body.local(op::LOCAL_GET, 0);
+            body.i32_const(length);
+            body.op(op::I32_GE_U);
+            body.code.extend_from_slice(&[op::IF, op::VOID, op::UNREACHABLE, op::END]);
+        }
+    }
+    body.local(op::LOCAL_GET, 0);
+    body.i32_const((C::BITS / 8) as i64);
+    body.op(op::I32_MUL);
+    body.finish(&[])
+}
+
+fn run_func<C: Cell>(ir_nodes: &[IR], init: &CodegenInit) -> Vec<u8> {
+    let mut body = Body::<C>::new();
+    let mut last_ptr = 0isize;
+
+    for node in ir_nodes {
+        let delta = node.pointer.wrapping_sub(last_ptr);
+        last_ptr = node.pointer;
+        body.step(delta);
+
+        match &node.opcode {
+            IROp::Breakpoint => {}
+            IROp::Add(val) => {
+                body.modify(0, false, |b| b.i32_const(C::truncate(*val).to_u32() as i64));
+            }
+            IROp::Set(val) => {
+                body.set(0, C::truncate(*val).to_u32());
+            }
+            IROp::Shift(step) => {
+                body.loop_head();
+                body.step(*step);
+                body.loop_tail();
+            }
+            IROp::MulAndSetZero(dests) => {
+                body.get();
+                body.local(op::LOCAL_TEE, LOCAL_M);
+                body.code.extend_from_slice(&[op::IF, op::VOID]);
+                body.set(0, 0);
+                for (dest_ptr, dest_val) in dests {
+                    body.modify(dest_ptr - last_ptr, false, |b| {
+                        b.local(op::LOCAL_GET, LOCAL_M);
+                        b.i32_const(C::truncate(*dest_val).to_u32() as i64);
+                        b.op(op::I32_MUL);
+                    });
+                }
+                body.op(op::END);
+            }
+            IROp::MovesAndSetZero(dests) => {
+                body.get();
+                body.local(op::LOCAL_TEE, LOCAL_M);
+                body.code.extend_from_slice(&[op::IF, op::VOID]);
+                body.set(0, 0);
+                for (dest_ptr, is_pos) in dests {
+                    body.modify(dest_ptr - last_ptr, !*is_pos, |b| b.local(op::LOCAL_GET, LOCAL_M));
+                }
+                body.op(op::END);
+            }
+            IROp::In => {
+                body.call(FUNC_INPUT);
+                body.local(op::LOCAL_TEE, LOCAL_C);
+                body.i32_const(-1);
+                body.op(op::I32_EQ);
+                body.code.extend_from_slice(&[op::IF, op::VOID]);
+                match init.eof {
+                    EofPolicy::Zero => body.set(0, 0),
+                    EofPolicy::MinusOne => body.set(0, C::MAX.to_u32()),
+                    EofPolicy::Unchanged => {
+                        body.addr(0);
+                        body.op(op::DROP);
+                    }
+                }
+                body.op(op::ELSE);
+                body.addr(0);
+                body.local(op::LOCAL_GET, LOCAL_C);
+                body.store();
+                body.op(op::END);
+            }
+            IROp::Out => {
+                body.get();
+                body.i32_const(0xFF);
+                body.op(op::I32_AND);
+                body.call(FUNC_OUTPUT);
+            }
+            IROp::LoopStart(_) => {
+                body.loop_head();
+            }
+            IROp::LoopEnd(_) => {
+                body.loop_tail();
+            }
+            IROp::LoopEndWithOffset(_, offset) => {
+                body.loop_tail();
+                last_ptr -= offset;
+            }
+            IROp::End => {}
+        }
+    }
+
+    body.finish(&[(4, I32)])
+}
+
+/// Generates a binary WebAssembly module. The host provides `env.input` (returning -1 on EOF) and `env.output`,
+/// and calls the exported `run`. The tape lives at address 0 of the exported `memory`; an out of bounds access traps.
+/// Growable tapes are not supported, since the tape can only grow to the right in linear memory.
+pub fn ir_to_wasm<C: Cell>(ir_nodes: &[IR], init: &CodegenInit) -> Result<Vec<u8>, BrainrotError> {
+    if init.tape_mode == TapeMode::Growable {
+        return Err(BrainrotError::FetureError("Growable tapes are not supported by the wasm backend".to_owned()));
+    }
+    let bytes = init.tape_length as u64 * (C::BITS / 8) as u64;
+    let pages = bytes.div_ceil(65536).max(1);
+    if init.tape_length > i32::MAX as usize || pages > 65536 {
+        return Err(RangeError::TapeLength(init.tape_length).into());
+    }
+
+    let mut module = vec![0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+
+    // 0: () -> i32, 1: (i32) -> (), 2: () -> (), 3: (i32) -> i32
+    section(&mut module, 1, 4, &[
+        0x60, 0, 1, I32,
+        0x60, 1, I32, 0,
+        0x60, 0, 0,
+        0x60, 1, I32, 1, I32,
+    ]);
+
+    let mut imports = vec![];
+    name(&mut imports, "env");
+    name(&mut imports, "input");
+    imports.extend_from_slice(&[0x00, 0]);
+    name(&mut imports, "env");
+    name(&mut imports, "output");
+    imports.extend_from_slice(&[0x00, 1]);
+    section(&mut module, 2, 2, &imports);
+
+    section(&mut module, 3, 2, &[3, 2]);
+
+    let mut memory = vec![0x00];
+    uleb(&mut memory, pages);
+    section(&mut module, 5, 1, &memory);
+
+    let mut exports = vec![];
+    name(&mut exports, "run");
+    exports.extend_from_slice(&[0x00, 3]);
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0]);
+    section(&mut module, 7, 2, &exports);
+
+    let mut code = addr_func::<C>(init);
+    code.extend_from_slice(&run_func::<C>(ir_nodes, init));
+    section(&mut module, 10, 2, &code);
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use wasmi::{Caller, Engine, Linker, Module, Store};
+
+    use crate::{cell::Cell, codegen::{CodegenInit, compile_to_wasm, tests::{EOF_POLICIES, PROGRAMS, interpret}}, vm::{program::EofPolicy, tape::TapeMode}};
+
+    struct Host {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    /// Instantiates the module for `code` and calls `run`, returning what it printed and whether it trapped.
+    fn run_wasm<C: Cell>(code: &str, init: &CodegenInit, input: &[u8]) -> (Vec<u8>, bool) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &compile_to_wasm::<C>(code, init).unwrap()).unwrap();
+        let mut store = Store::new(&engine, Host { input: input.iter().copied().collect(), output: vec![] });
+        let mut linker = Linker::<Host>::new(&engine);
+        linker.func_wrap("env", "input", |mut caller: Caller<Host>| -> i32 {
+            caller.data_mut().input.pop_front().map_or(-1, i32::from)
+        }).unwrap();
+        linker.func_wrap("env", "output", |mut caller: Caller<Host>, value: i32| {
+            caller.data_mut().output.push(value as u8);
+        }).unwrap();
+
+        let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+        let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+        let trapped = run.call(&mut store, ()).is_err();
+        (store.into_data().output, trapped)
+    }
+
+    fn check<C: Cell>(code: &str, init: &CodegenInit, input: &[u8]) {
+        assert_eq!(run_wasm::<C>(code, init, input), interpret::<C>(code, init, input), "{code} {init:?} {}-bit", C::BITS);
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        for (code, input) in PROGRAMS {
+            for tape_mode in [TapeMode::Fixed, TapeMode::Wrapping] {
+                let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode };
+                check::<u8>(code, &init, input);
+                check::<u16>(code, &init, input);
+                check::<u32>(code, &init, input);
+            }
+        }
+        for eof in EOF_POLICIES {
+            let init = CodegenInit { eof, tape_length: 64, tape_mode: TapeMode::Fixed };
+            check::<u8>(",.,.,.", &init, b"a");
+            check::<u16>("+,.", &init, b"");
+            check::<u32>("+,.", &init, b"");
+        }
+    }
+
+    #[test]
+    fn rejects_growable() {
+        let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode: TapeMode::Growable };
+        assert!(compile_to_wasm::<u8>("+", &init).is_err());
+    }
+
+    #[test]
+    fn folded_loops_have_no_wasm_loop() {
+        // block loop の並びを数える
+        let loops = |code: &str| {
+            let init = CodegenInit { eof: EofPolicy::Zero, tape_length: 64, tape_mode: TapeMode::Fixed };
+            let module = compile_to_wasm::<u8>(code, &init).unwrap();
+            module.windows(4).filter(|w| *w == [super::op::BLOCK, super::op::VOID, super::op::LOOP, super::op::VOID]).count()
+        };
+        assert_eq!(loops("+[->+<]"), 0);
+        assert_eq!(loops("+[->++>---<<]"), 0);
+        assert_eq!(loops("+[->+>-<<]"), 0);
+        assert_eq!(loops("+[->+<.]"), 1);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, io::{Read, Write}, sync::Arc};
+
+use crate::{bytecode::{bytecode::{Bytecode, BytecodeOrigin, ir_to_bytecodes_with_origins}, format::{BytecodeImage, decode_image, encode_image}, verify::VerifiedBytecode}, cell::Cell, error::BrainrotError, ir::{ir::{IR, parse_to_ir}, range::{RangeInfo, generate_range_info}}, vm::tape::TapeMode};
+
+/// A parsed, analysed and lowered program. It is immutable and `Send + Sync`, so one `Arc<CompiledProgram>`
+/// can back any number of `Execution`s, on any thread.
+pub struct CompiledProgram<C: Cell = u8> {
+    ir: Vec<IR>,
+    range: RangeInfo,
+    code: VerifiedBytecode<C>,
+    origins: Arc<[BytecodeOrigin]>,
+}
+
+// 複数スレッドから同じ `Arc<CompiledProgram>` を使えることをコンパイル時に確かめる
+const _: fn() = || {
+    fn assert<T: Send + Sync>() {}
+    assert::<CompiledProgram<u8>>();
+    assert::<CompiledProgram<u16>>();
+    assert::<CompiledProgram<u32>>();
+};
+
+impl<C: Cell> CompiledProgram<C> {
+    /// The range analysis depends on the tape, so its length and mode are fixed here rather than per execution.
+    pub fn new(code: &str, tape_length: usize, tape_mode: TapeMode) -> Result<CompiledProgram<C>, BrainrotError> {
+        let ir = parse_to_ir(code)?;
+        let range = generate_range_info(&ir, tape_length, tape_mode)?;
+        let (insts, origins) = ir_to_bytecodes_with_origins::<C>(&ir, &range)?;
+        let code = VerifiedBytecode::verify(insts, tape_length, tape_mode, range.do_opt_first)?;
+
+        Ok(CompiledProgram {
+            ir, range, code,
+            origins: origins.into(),
+        })
+    }
+    /// Writes the bytecode, origins and tape settings in the versioned `.bfc` format, so that `load` can skip
+    /// parsing and lowering. The IR is not saved.
+    pub fn save(&self, mut writer: impl Write) -> Result<(), BrainrotError> {
+        let bytes = encode_image(&BytecodeImage {
+            insts: self.code.insts().to_vec(),
+            origins: self.origins.to_vec(),
+            tape_length: self.code.tape_length(),
+            tape_mode: self.code.tape_mode(),
+            do_opt_first: self.code.opt_first(),
+        })?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+    /// Reads a program written by `save`. Files with a different cell width, or whose bytecode fails `VerifiedBytecode::verify`, are rejected.
+    /// The result has no IR and an empty range map.
+    pub fn load(mut reader: impl Read) -> Result<CompiledProgram<C>, BrainrotError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let image = decode_image::<C>(&bytes)?;
+        let code = VerifiedBytecode::verify(image.insts, image.tape_length, image.tape_mode, image.do_opt_first)?;
+
+        Ok(CompiledProgram {
+            ir: vec![],
+            range: RangeInfo { map: HashMap::new(), do_opt_first: image.do_opt_first },
+            code,
+            origins: image.origins.into(),
+        })
+    }
+    pub fn ir(&self) -> &[IR] {
+        &self.ir
+    }
+    pub fn range_info(&self) -> &RangeInfo {
+        &self.range
+    }
+    pub fn bytecodes(&self) -> &Arc<[Bytecode<C>]> {
+        self.code.insts()
+    }
+    pub fn verified(&self) -> &VerifiedBytecode<C> {
+        &self.code
+    }
+    /// Which IR nodes and source range each bytecode came from, indexed by pc.
+    pub fn origins(&self) -> &Arc<[BytecodeOrigin]> {
+        &self.origins
+    }
+    pub fn tape_length(&self) -> usize {
+        self.code.tape_length()
+    }
+    pub fn tape_mode(&self) -> TapeMode {
+        self.code.tape_mode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{brainrot::{Brainrot, ExecutionInit}, compiled::CompiledProgram, vm::{program::EofPolicy, tape::TapeMode}};
+
+    fn run(compiled: Arc<CompiledProgram>, input: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let mut input = input.iter().copied();
+        let mut vm = Brainrot::from_compiled(compiled, ExecutionInit {
+            input: || input.next(),
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+        });
+        vm.step().unwrap();
+        drop(vm);
+        output
+    }
+
+    #[test]
+    fn runs_on_many_threads() {
+        // 入力を逆順に出力する。ループは JIT に乗るくらい回す
+        let code = "++++++++[>++++++++[>+>+<<-]<-]>>[-]>[-]<<,[>,]<[.<]";
+        let compiled = Arc::new(CompiledProgram::new(code, 256, TapeMode::Fixed).unwrap());
+        let threads = (0..8).map(|i| {
+            let compiled = compiled.clone();
+            thread::spawn(move || {
+                let input = format!("thread {i}").into_bytes();
+                (run(compiled, &input), input)
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            let (output, mut input) = thread.join().unwrap();
+            input.reverse();
+            assert_eq!(output, input);
+        }
+        assert_eq!(Arc::strong_count(&compiled), 1);
+    }
+}
@@ -1,8 +1,8 @@
-use std::io;
+use std::{io, ops::RangeInclusive};
 
 use thiserror::Error;
 
-use crate::{bytecode::error::OptimizationError, ir::error::{SyntaxError, RangeError}};
+use crate::{bytecode::error::{FormatError, OptimizationError, VerifyError}, ir::error::{SyntaxError, RangeError}, source::render_snippet};
 
 #[derive(Error, Debug)]
 pub enum RuntimeError {
@@ -10,19 +10,24 @@ pub enum RuntimeError {
     OOBGet(usize),
     
     #[error("Out of bounds while setting {1} to cell {0}")]
-    OOBSet(usize, u8),
+    OOBSet(usize, u32),
     
     #[error("Out of bounds while adding {1} to cell {0}")]
-    OOBAdd(usize, u8),
+    OOBAdd(usize, u32),
     
     #[error("Out of bounds while subtracting {1} to cell {0}")]
-    OOBSub(usize, u8),
+    OOBSub(usize, u32),
 
     #[error("{0}")]
     IOError(#[from] io::Error),
 
+    /// Raised inside the tiers when fuel runs out. `run` turns it into `BrainrotResult::OutOfFuel`.
     #[error("Timeouted")]
     TimeoutError,
+
+    /// Raised inside the tiers when an `InterruptHandle` fires. `run` turns it into `BrainrotResult::Interrupted`.
+    #[error("Interrupted")]
+    Interrupted,
 }
 
 #[derive(Error, Debug)]
@@ -39,6 +44,8 @@ pub enum BrainrotError {
         err: RuntimeError,
         pc: usize,
         pointer: usize,
+        /// Byte range of the source the failing bytecode was lowered from.
+        source_range: Option<RangeInclusive<usize>>,
     },
 
     #[error("RangeError: {0}")]
@@ -47,6 +54,30 @@ pub enum BrainrotError {
     #[error("IOError: {0}")]
     IOError(#[from] io::Error),
 
+    #[error("FormatError: {0}")]
+    FormatError(#[from] FormatError),
+
+    #[error("VerifyError: {0}")]
+    VerifyError(#[from] VerifyError),
+
     #[error("FeatureError: {0}")]
     FetureError(String),
 }
+
+impl BrainrotError {
+    /// Byte range of the source this error points at, if any.
+    pub fn source_range(&self) -> Option<RangeInclusive<usize>> {
+        match self {
+            BrainrotError::SyntaxError(SyntaxError::UnmatchedOpeningBracket(at) | SyntaxError::UnmatchedClosingBracket(at)) => Some(*at..=*at),
+            BrainrotError::RuntimeError { source_range, .. } => source_range.clone(),
+            _ => None,
+        }
+    }
+    /// Formats the error followed by its line, column and a caret-annotated snippet of `code`, when it points at the source.
+    pub fn render(&self, code: &str) -> String {
+        match self.source_range() {
+            Some(range) if *range.start() <= code.len() => format!("{}\n{}", self, render_snippet(code, &range)),
+            _ => format!("{}\n", self),
+        }
+    }
+}
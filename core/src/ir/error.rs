@@ -5,9 +5,9 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum SyntaxError {
     #[error("Unmatched opening bracket")]
-    UnmatchedOpeningBracket,
+    UnmatchedOpeningBracket(usize), // byte offset
     #[error("Unmatched closing bracket")]
-    UnmatchedClosingBracket,
+    UnmatchedClosingBracket(usize), // byte offset
 }
 
 #[derive(Error, Debug)]
@@ -17,4 +17,7 @@ pub enum RangeError {
     
     #[error("End range overflow")]
     EndOverflow(TryFromIntError, isize),
+
+    #[error("Tape length {0} is too large")]
+    TapeLength(usize),
 }
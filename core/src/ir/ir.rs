@@ -1,4 +1,4 @@
-use std::ops::RangeInclusive;
+use std::{fmt, ops::RangeInclusive};
 
 use crate::ir::{error::SyntaxError, range::extend_ri_pointer};
 
@@ -18,11 +18,11 @@ impl PartialEq for IR {
 pub enum IROp {
     Breakpoint,
 
-    Add(u8),
-    Set(u8),
+    Add(u32),
+    Set(u32),
 
     Shift(isize),
-    MulAndSetZero(Box<[(isize, u8)]>),
+    MulAndSetZero(Box<[(isize, u32)]>),
     MovesAndSetZero(Box<[(isize, bool /* is_positive */)]>),
 
     In,
@@ -35,6 +35,32 @@ pub enum IROp {
     End,
 }
 
+/// 値は符号付きで、移動先は自分のセルからの相対位置で出す。`Add(4294967295)` より `Add(-1)` の方が読める
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.opcode {
+            IROp::Breakpoint => write!(f, "Breakpoint"),
+            IROp::Add(value) => write!(f, "Add({})", *value as i32),
+            IROp::Set(value) => write!(f, "Set({})", *value as i32),
+            IROp::Shift(step) => write!(f, "Shift({:+})", step),
+            IROp::MulAndSetZero(dests) => {
+                let dests = dests.iter().map(|(dest, value)| format!("({:+}, {})", dest - self.pointer, *value as i32)).collect::<Vec<_>>();
+                write!(f, "MulAndSetZero[{}]", dests.join(", "))
+            }
+            IROp::MovesAndSetZero(dests) => {
+                let dests = dests.iter().map(|(dest, is_positive)| format!("({:+}, {})", dest - self.pointer, if *is_positive { '+' } else { '-' })).collect::<Vec<_>>();
+                write!(f, "MovesAndSetZero[{}]", dests.join(", "))
+            }
+            IROp::In => write!(f, "In"),
+            IROp::Out => write!(f, "Out"),
+            IROp::LoopStart(end) => write!(f, "LoopStart({})", end),
+            IROp::LoopEnd(start) => write!(f, "LoopEnd({})", start),
+            IROp::LoopEndWithOffset(start, offset) => write!(f, "LoopEndWithOffset({}, {:+})", start, offset),
+            IROp::End => write!(f, "End"),
+        }
+    }
+}
+
 impl IR {
     pub fn get_range(&self) -> RangeInclusive<isize> {
         let mut range = self.pointer..=self.pointer;
@@ -62,13 +88,17 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
 
     let mut is_flat: bool = true; // LoopEnd時に確定します
 
-    for (i, char) in code.chars().enumerate() {
+    // source_range はバイトオフセットの閉区間。BF の命令はすべて 1 バイトなので i..=i が 1 命令になる
+    for (i, char) in code.char_indices() {
         macro_rules! push_inst {
             ($opcode:expr) => {
+                push_inst!($opcode, i)
+            };
+            ($opcode:expr, $from:expr) => {
                 insts.push(IR {
                     pointer,
                     opcode: $opcode,
-                    source_range: Some(i..=(i+1)),
+                    source_range: Some($from..=i),
                 })
             };
         }
@@ -89,13 +119,13 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                         if let IROp::Add(val) = opcode {
                             *val = val.wrapping_add(1);
                             if let Some(r) = source_range {
-                                *r = (*r.start())..=(i+1);
+                                *r = (*r.start())..=i;
                             }
                             continue;
                         } else if let IROp::Set(val) = opcode {
                             *val = val.wrapping_add(1);
                             if let Some(r) = source_range {
-                                *r = (*r.start())..=(i+1);
+                                *r = (*r.start())..=i;
                             }
                             continue;
                         }
@@ -109,19 +139,19 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                         if let IROp::Add(val) = opcode {
                             *val = val.wrapping_sub(1);
                             if let Some(r) = source_range {
-                                *r = (*r.start())..=(i+1);
+                                *r = (*r.start())..=i;
                             }
                             continue;
                         } else if let IROp::Set(val) = opcode {
                             *val = val.wrapping_sub(1);
                             if let Some(r) = source_range {
-                                *r = (*r.start())..=(i+1);
+                                *r = (*r.start())..=i;
                             }
                             continue;
                         }
                     }
                 }
-                push_inst!(IROp::Add(255));
+                push_inst!(IROp::Add(u32::MAX));
             }
             '>' => {
                 pointer += 1;
@@ -141,8 +171,10 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                 push_inst!(IROp::LoopStart(usize::MAX));
             }
             ']' => {
-                let start = loop_stack.pop().ok_or_else(|| SyntaxError::UnmatchedClosingBracket)?;
+                let start = loop_stack.pop().ok_or_else(|| SyntaxError::UnmatchedClosingBracket(i))?;
                 let start_ptr = insts[start].pointer;
+                // 畳み込んだ命令は [ から ] までを指す
+                let from = insts[start].source_range.as_ref().map_or(i, |r| *r.start());
                 let end = insts.len();
                 let end_ptr = pointer;
                 let is_ptr_stable = start_ptr == end_ptr;
@@ -153,17 +185,17 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                     pointer = start_ptr;
                     if children.len() == 0 {
                         insts.truncate(start);
-                        push_inst!(IROp::Shift(end_ptr - start_ptr));
+                        push_inst!(IROp::Shift(end_ptr - start_ptr), from);
                         continue;
                     }
                 } else if is_flat {
-                    if children == [IR { opcode: IROp::Add(255), pointer, source_range: None }] {
+                    if children == [IR { opcode: IROp::Add(u32::MAX), pointer, source_range: None }] {
                         insts.truncate(start);
-                        push_inst!(IROp::Set(0));
+                        push_inst!(IROp::Set(0), from);
                         continue;
                     }
 
-                    let mut dests_res: Result<Vec<(isize, u8)>, ()> = children.iter().map(|dest| {
+                    let mut dests_res: Result<Vec<(isize, u32)>, ()> = children.iter().map(|dest| {
                         if let IR { pointer, opcode: IROp::Add(val), .. } = dest {
                             Ok((*pointer, *val))
                         } else {
@@ -171,12 +203,12 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                         }
                     }).collect();
                     if let Ok(dests) = dests_res.as_mut() {
-                        if let Some(decrement_pos) = dests.iter().position(|&dest| dest == (pointer, 255)) {
+                        if let Some(decrement_pos) = dests.iter().position(|&dest| dest == (pointer, u32::MAX)) {
                             dests.remove(decrement_pos);
                             if dests.iter().all(|&(ptr, _)| ptr != pointer) {
                                 insts.truncate(start);
 
-                                if dests.iter().all(|&(_, val)| val == 1 || val == 255) {
+                                if dests.iter().all(|&(_, val)| val == 1 || val == u32::MAX) {
                                     let moves = dests.iter().map(
                                         |&(ptr, val)| {
                                             if val == 1 {
@@ -187,11 +219,11 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
                                         }
                                     ).collect::<Vec<(isize, bool)>>();
 
-                                    push_inst!(IROp::MovesAndSetZero(moves.into_boxed_slice()));
+                                    push_inst!(IROp::MovesAndSetZero(moves.into_boxed_slice()), from);
                                     continue;
                                 }
 
-                                push_inst!(IROp::MulAndSetZero(dests.clone().into_boxed_slice()));
+                                push_inst!(IROp::MulAndSetZero(dests.clone().into_boxed_slice()), from);
                                 continue;
                             }
                         }
@@ -202,9 +234,9 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
 
                 insts[start].opcode = IROp::LoopStart(end);
                 if is_ptr_stable {
-                    insts.push(IR { pointer: end_ptr, opcode: IROp::LoopEnd(start), source_range: Some(i..=(i+1)) });
+                    insts.push(IR { pointer: end_ptr, opcode: IROp::LoopEnd(start), source_range: Some(i..=i) });
                 } else {
-                    insts.push(IR { pointer: end_ptr, opcode: IROp::LoopEndWithOffset(start, end_ptr - start_ptr), source_range: Some(i..=(i+1)) });
+                    insts.push(IR { pointer: end_ptr, opcode: IROp::LoopEndWithOffset(start, end_ptr - start_ptr), source_range: Some(i..=i) });
                 }
             }
             _ => {}
@@ -213,8 +245,8 @@ pub fn parse_to_ir(code: &str) -> Result<Vec<IR>, SyntaxError> {
 
     insts.push(IR { pointer, opcode: IROp::End, source_range: Some(code.len()..=code.len()) });
 
-    if loop_stack.len() != 0 {
-        return Err(SyntaxError::UnmatchedOpeningBracket);
+    if let Some(&start) = loop_stack.last() {
+        return Err(SyntaxError::UnmatchedOpeningBracket(insts[start].source_range.as_ref().map_or(0, |r| *r.start())));
     }
 
     Ok(insts)
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, ops::{Range, RangeFrom, RangeInclusive, RangeTo}};
 
-use crate::{TAPE_LENGTH, ir::{error::RangeError, ir::{IR, IROp}}};
+use crate::{ir::{error::RangeError, ir::{IR, IROp}}, vm::tape::TapeMode};
 
 pub fn extend_ri_pointer(range: &RangeInclusive<isize>, pointer: isize) -> RangeInclusive<isize> {
     return (*range.start()).min(pointer)..=(*range.end()).max(pointer);
@@ -53,31 +53,35 @@ impl InternalRangeState {
 
 pub enum MidRange {
     None,
-    Negative(RangeFrom<u16>), // deopt when ptr < X
-    Positive(RangeTo<u16>), // deopt when ptr >= X
-    Both(Range<u16>),
+    Negative(RangeFrom<u32>), // deopt when ptr < X
+    Positive(RangeTo<u32>), // deopt when ptr >= X
+    Both(Range<u32>),
 }
 pub struct RangeInfo {
     pub map: HashMap<usize, MidRange>,
     pub do_opt_first: bool,
 }
 impl RangeInfo {
-    fn from(internal_ri: &InternalRangeState) -> Result<RangeInfo, RangeError> {
+    fn from(internal_ri: &InternalRangeState, tape_length: usize) -> Result<RangeInfo, RangeError> {
+        let length = tape_length as isize;
         let map_arr: Result<Vec<(usize, MidRange)>, RangeError> = internal_ri.map.iter().map(|(&ir_at, &RSMapElement { pointer, range: ref range_raw })| {
-            let range = (-(range_raw.start() - pointer))..((TAPE_LENGTH as isize) - (range_raw.end() - pointer));
+            let range = (-(range_raw.start() - pointer))..(length - (range_raw.end() - pointer));
+            // 範囲がテープより広い場合は end が負になるので、空の範囲にして常に deopt させる
+            let overflowed = range.end < 0;
+            let range = range.start..range.end.max(0);
 
-            match (range.start == 0, range.end == (TAPE_LENGTH as isize)) {
+            match (range.start == 0, range.end == length && !overflowed) {
                 (false, false) => {
-                    let start: u16 = range.start.try_into().map_err(|e| RangeError::StartOverflow(e, range.start))?;
-                    let end: u16 = range.end.try_into().map_err(|e| RangeError::EndOverflow(e, range.end))?;
+                    let start: u32 = range.start.try_into().map_err(|e| RangeError::StartOverflow(e, range.start))?;
+                    let end: u32 = range.end.try_into().map_err(|e| RangeError::EndOverflow(e, range.end))?;
                     Ok((ir_at, MidRange::Both(start..end)))
                 }
                 (false, true) => {
-                    let start: u16 = range.start.try_into().map_err(|e| RangeError::StartOverflow(e, range.start))?;
+                    let start: u32 = range.start.try_into().map_err(|e| RangeError::StartOverflow(e, range.start))?;
                     Ok((ir_at, MidRange::Negative(start..)))
                 }
                 (true, false) => {
-                    let end: u16 = range.end.try_into().map_err(|e| RangeError::EndOverflow(e, range.end))?;
+                    let end: u32 = range.end.try_into().map_err(|e| RangeError::EndOverflow(e, range.end))?;
                     Ok((ir_at, MidRange::Positive(..end)))
                 }
                 (true, true) => {
@@ -87,12 +91,17 @@ impl RangeInfo {
         }).collect();
         Ok(RangeInfo {
             map: HashMap::from_iter(map_arr?),
-            do_opt_first: !(*internal_ri.curr.start() < 0) && !(*internal_ri.curr.end() >= (TAPE_LENGTH as isize)),
+            do_opt_first: !(*internal_ri.curr.start() < 0) && !(*internal_ri.curr.end() >= length),
         })
     }
 }
 
-pub fn generate_range_info(ir_nodes: &[IR]) -> Result<RangeInfo, RangeError> {
+pub fn generate_range_info(ir_nodes: &[IR], tape_length: usize, tape_mode: TapeMode) -> Result<RangeInfo, RangeError> {
+    // レンジチェックのオペランドは u32 なので、ポインタが多少はみ出しても切り詰めで誤判定しない長さに制限する
+    if tape_length > (i32::MAX as usize) {
+        return Err(RangeError::TapeLength(tape_length));
+    }
+
     let mut internal_ri = InternalRangeState::new();
 
     for (i, op) in ir_nodes.iter().enumerate().rev() {
@@ -121,5 +130,42 @@ pub fn generate_range_info(ir_nodes: &[IR]) -> Result<RangeInfo, RangeError> {
         }
     }
 
-    Ok(RangeInfo::from(&internal_ri)?)
+    if tape_mode == TapeMode::Wrapping && tape_length != 0 {
+        // 循環テープでは範囲外アクセスが起こらないので、チェックなしで最初から opt で走らせる
+        return Ok(RangeInfo {
+            map: internal_ri.map.keys().map(|&ir_at| (ir_at, MidRange::None)).collect(),
+            do_opt_first: true,
+        });
+    }
+
+    Ok(RangeInfo::from(&internal_ri, tape_length)?)
+}
+
+/// The first access that goes out of bounds on every run, as an IR index and the cell it touches.
+/// Only the IR before the first loop is looked at, since that is where the pointer is known exactly.
+pub fn certain_oob(ir_nodes: &[IR], tape_length: usize, tape_mode: TapeMode) -> Option<(usize, isize)> {
+    let length = tape_length as isize;
+    let outside = |cell: isize| match tape_mode {
+        TapeMode::Fixed => cell < 0 || cell >= length,
+        // Growable はどちらにも伸びる
+        TapeMode::Growable => false,
+        TapeMode::Wrapping => tape_length == 0,
+    };
+
+    for (i, ir) in ir_nodes.iter().enumerate() {
+        match ir.opcode {
+            IROp::Breakpoint => continue,
+            IROp::End => return None,
+            _ => {}
+        }
+        // どの命令もまず自分のセルに触る。Mul の移動先はセルが 0 でないときしか触らない
+        if outside(ir.pointer) {
+            return Some((i, ir.pointer));
+        }
+        // ここから先はループが何回回るか分からない
+        if let IROp::LoopStart(_) | IROp::Shift(_) = ir.opcode {
+            return None;
+        }
+    }
+    None
 }
@@ -1,18 +1,64 @@
-const TAPE_LENGTH: usize = 65536;
+pub const DEFAULT_TAPE_LENGTH: usize = 65536;
 
 pub mod error;
+pub mod source;
+mod cell;
 mod ir;
 mod bytecode;
 mod vm;
 mod trace;
+mod codegen;
 
 mod brainrot;
+mod compiled;
 
-pub use crate::brainrot::{Brainrot, BrainrotInit};
+pub use crate::{brainrot::{Brainrot, BrainrotInit, Execution, ExecutionInit}, cell::Cell, compiled::CompiledProgram, codegen::{CodegenInit, compile_to_c, compile_to_rust, compile_to_wasm}, vm::{io, journal::{CellChange, JournalConfig}, program::{EofPolicy, InterruptHandle}, tape::TapeMode, tier::BrainrotResult, watch::WatchKind}};
 
 pub mod advance {
     pub use crate::ir::*;
     pub use crate::bytecode::*;
     pub use crate::vm::*;
     pub use crate::trace::*;
+    pub use crate::codegen::*;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Brainrot, BrainrotInit, DEFAULT_TAPE_LENGTH, EofPolicy, error::BrainrotError};
+
+    /// Moves to cell `cell` and writes it.
+    fn touch(cell: usize, tape_length: usize) -> Result<(), BrainrotError> {
+        // bytecode の delta は i16 なので、`[-]` で区切って少しずつ進む
+        let code = vec![">".repeat(1000); cell / 1000].join("[-]") + &">".repeat(cell % 1000) + "+";
+        let mut vm = Brainrot::<_, _, u8>::new(&code, BrainrotInit {
+            input: || None,
+            output: |_| {},
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+            tape_length,
+            tape_mode: Default::default(),
+        })?;
+        vm.step()?;
+        Ok(())
+    }
+
+    #[test]
+    fn default_tape_length() {
+        assert!(touch(DEFAULT_TAPE_LENGTH - 1, DEFAULT_TAPE_LENGTH).is_ok());
+        assert!(matches!(touch(DEFAULT_TAPE_LENGTH, DEFAULT_TAPE_LENGTH), Err(BrainrotError::RuntimeError { .. })));
+    }
+
+    #[test]
+    fn other_tape_lengths() {
+        assert!(touch(9, 10).is_ok());
+        assert!(matches!(touch(10, 10), Err(BrainrotError::RuntimeError { .. })));
+        assert!(touch(DEFAULT_TAPE_LENGTH, DEFAULT_TAPE_LENGTH + 1).is_ok());
+        assert!(matches!(touch(0, 0), Err(BrainrotError::RuntimeError { .. })));
+    }
+
+    #[test]
+    fn rejects_huge_tapes() {
+        assert!(matches!(touch(0, i32::MAX as usize + 1), Err(BrainrotError::RangeError(_))));
+    }
 }
@@ -0,0 +1,83 @@
+use std::ops::RangeInclusive;
+
+/// A 1-based line and column. Columns count chars, not bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Finds the line and column of a byte offset. Offsets past the end point just after the last char.
+pub fn locate(code: &str, offset: usize) -> Location {
+    let offset = floor_char_boundary(code, offset);
+    let before = &code[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Location {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+/// The byte offset of a line and column, the inverse of `locate`. `None` when the line or column does not exist.
+pub fn offset_of(code: &str, location: Location) -> Option<usize> {
+    let line_start = match location.line {
+        1 => 0,
+        line => code.match_indices('\n').nth(line.checked_sub(2)?)?.0 + 1,
+    };
+    let line = code[line_start..].split('\n').next().unwrap_or_default();
+    // 行末 (改行の位置) も locate が返すので含める
+    line.char_indices().map(|(i, _)| i).chain([line.len()]).nth(location.column.checked_sub(1)?).map(|i| line_start + i)
+}
+
+fn floor_char_boundary(code: &str, offset: usize) -> usize {
+    let mut offset = offset.min(code.len());
+    while !code.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// Renders the line containing the start of `range`, with carets under the part covered by `range`.
+/// Ranges spanning several lines are underlined up to the end of the first line.
+pub fn render_snippet(code: &str, range: &RangeInclusive<usize>) -> String {
+    let start = floor_char_boundary(code, *range.start());
+    let line_start = code[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = code[start..].find('\n').map_or(code.len(), |i| start + i);
+    let line = code[line_start..line_end].trim_end_matches('\r');
+    let end = floor_char_boundary(code, range.end().saturating_add(1)).clamp(start, line_start + line.len());
+
+    let location = locate(code, start);
+    let gutter = " ".repeat(location.line.to_string().len());
+    let pad = code[line_start..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+    let carets = "^".repeat(code[start..end].chars().count().max(1));
+
+    format!("{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}\n", gutter, location.line, location.column, gutter, location.line, line, gutter, pad, carets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_counts_chars() {
+        // ね と こ は 3 バイト、é は 2 バイト
+        let code = "ねこ+\n é-";
+        assert_eq!(locate(code, 6), Location { line: 1, column: 3 });
+        assert_eq!(locate(code, 7), Location { line: 1, column: 4 });
+        assert_eq!(locate(code, 8), Location { line: 2, column: 1 });
+        assert_eq!(locate(code, 11), Location { line: 2, column: 3 });
+        // 文字の途中はその文字の先頭に丸める
+        assert_eq!(locate(code, 10), Location { line: 2, column: 2 });
+        assert_eq!(locate(code, 100), Location { line: 2, column: 4 });
+    }
+
+    #[test]
+    fn snippet_caret() {
+        assert_eq!(render_snippet("ねこ +-\n", &(7..=8)), " --> 1:4\n  |\n1 | ねこ +-\n  |    ^^\n");
+        assert_eq!(render_snippet("+\n\t[é", &(3..=3)), " --> 2:2\n  |\n2 | \t[é\n  | \t^\n");
+        // 複数行にまたがる範囲は 1 行目の終わりまで
+        assert_eq!(render_snippet("[\r\n]", &(0..=3)), " --> 1:1\n  |\n1 | [\n  | ^\n");
+        // 末尾を指す範囲にも 1 つは ^ を付ける
+        assert_eq!(render_snippet("+-", &(2..=2)), " --> 1:3\n  |\n1 | +-\n  |   ^\n");
+    }
+}
@@ -13,7 +13,7 @@ impl OperationCountMap {
 
 use std::ops::RangeInclusive;
 
-use crate::{bytecode::bytecode::Bytecode, ir::{ir::{IR, IROp}, range::{MidRange, RangeInfo}}, vm::program::Program};
+use crate::{bytecode::bytecode::Bytecode, cell::Cell, ir::{ir::{IR, IROp}, range::{MidRange, RangeInfo}}, vm::{io::{InputSource, OutputSink}, program::Program}};
 
 fn range_to_string(range: &Option<RangeInclusive<usize>>) -> String {
     match range {
@@ -64,7 +64,7 @@ pub fn generate_ir_trace(ir_nodes: &[IR], range: &RangeInfo) -> String {
 }
 
 
-pub fn generate_bytecode_trace<I: FnMut() -> u8, O: FnMut(u8) -> ()>(program: &Program<I, O>) -> String {
+pub fn generate_bytecode_trace<I: InputSource, O: OutputSink, C: Cell>(program: &Program<I, O, C>) -> String {
     let mut str = String::new();
     let mut lv: usize = 0;
 
@@ -76,7 +76,7 @@ pub fn generate_bytecode_trace<I: FnMut() -> u8, O: FnMut(u8) -> ()>(program: &P
             Bytecode::BothRangeCheckJNZ { .. } => lv -= 1,
             _ => {}
         }
-        str += &format!("{}\t{}\t{}{:?}\n", (program.ocm.deopt[i].wrapping_add(1) as f64).log2().floor(), (program.ocm.opt[i].wrapping_add(1) as f64).log2().floor(), "    ".repeat(lv), b);
+        str += &format!("{} {}\t{}\t{}{:?}\n", range_to_string(&program.source_range(i)), (program.ocm.deopt[i].wrapping_add(1) as f64).log2().floor(), (program.ocm.opt[i].wrapping_add(1) as f64).log2().floor(), "    ".repeat(lv), b);
         match b {
             Bytecode::JmpIfZero { .. } => lv += 1,
             _ => {}
@@ -0,0 +1,126 @@
+use std::{collections::VecDeque, io::{self, BufReader, BufWriter, ErrorKind, Read, Write}};
+
+/// One read from an `InputSource`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input<T = u8> {
+    Byte(T),
+    Eof,
+    /// Nothing yet. `step` returns `BrainrotResult::NeedInput` and retries the same `,` next time.
+    Pending,
+}
+
+/// Where `,` reads from. Closures `FnMut() -> Option<u8>` are sources that never return `Pending`.
+/// Errors surface as `RuntimeError::IOError`.
+pub trait InputSource {
+    fn read(&mut self) -> io::Result<Input>;
+}
+impl<F: FnMut() -> Option<u8>> InputSource for F {
+    fn read(&mut self) -> io::Result<Input> {
+        Ok(match self() {
+            Some(value) => Input::Byte(value),
+            None => Input::Eof,
+        })
+    }
+}
+
+/// Where `.` writes to. Closures `FnMut(u8)` are sinks that never stop execution. Errors surface as `RuntimeError::IOError`.
+pub trait OutputSink {
+    /// Returns `true` to make `step` return `BrainrotResult::Output(value)` right after this byte.
+    fn write(&mut self, value: u8) -> io::Result<bool>;
+    /// Called whenever `step` returns, including on errors, and before each `,` reads.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl<F: FnMut(u8)> OutputSink for F {
+    fn write(&mut self, value: u8) -> io::Result<bool> {
+        self(value);
+        Ok(false)
+    }
+}
+
+/// Input pushed by the host with `Brainrot::feed_input`. Reads return `Pending` when it runs dry, until `close` is called.
+#[derive(Clone, Debug, Default)]
+pub struct PushInput {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+impl PushInput {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes);
+    }
+    /// Marks the end of input. Once the buffer is drained, reads return `Eof`.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+impl InputSource for PushInput {
+    fn read(&mut self) -> io::Result<Input> {
+        Ok(match self.buffer.pop_front() {
+            Some(value) => Input::Byte(value),
+            None if self.closed => Input::Eof,
+            None => Input::Pending,
+        })
+    }
+}
+
+/// Output pulled by the host: every `.` stops `step` with `BrainrotResult::Output`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PullOutput;
+impl OutputSink for PullOutput {
+    fn write(&mut self, _value: u8) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Buffered input from any `Read`. A `WouldBlock` error from a non-blocking reader counts as `Pending`.
+pub struct ReadInput<R: Read> {
+    reader: BufReader<R>,
+}
+impl<R: Read> ReadInput<R> {
+    pub fn new(reader: R) -> ReadInput<R> {
+        ReadInput { reader: BufReader::new(reader) }
+    }
+}
+impl<R: Read> InputSource for ReadInput<R> {
+    fn read(&mut self) -> io::Result<Input> {
+        let mut byte = [0u8; 1];
+        loop {
+            return match self.reader.read(&mut byte) {
+                Ok(0) => Ok(Input::Eof),
+                Ok(_) => Ok(Input::Byte(byte[0])),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(Input::Pending),
+                Err(err) => Err(err),
+            };
+        }
+    }
+}
+
+/// Buffered output to any `Write`.
+pub struct WriteOutput<W: Write> {
+    writer: BufWriter<W>,
+    flush_each: bool,
+}
+impl<W: Write> WriteOutput<W> {
+    pub fn new(writer: W) -> WriteOutput<W> {
+        WriteOutput { writer: BufWriter::new(writer), flush_each: false }
+    }
+    /// Flushes after every byte instead of only when `step` returns.
+    pub fn flush_each(mut self, value: bool) -> WriteOutput<W> {
+        self.flush_each = value;
+        self
+    }
+}
+impl<W: Write> OutputSink for WriteOutput<W> {
+    fn write(&mut self, value: u8) -> io::Result<bool> {
+        self.writer.write_all(&[value])?;
+        if self.flush_each {
+            self.writer.flush()?;
+        }
+        Ok(false)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,380 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use crate::{cell::Cell, vm::tape::Tape};
+
+/// Settings for `Brainrot::start_recording`.
+#[derive(Clone, Copy, Debug)]
+pub struct JournalConfig {
+    /// How many bytecodes run between tape checkpoints. Going back costs up to this many replayed bytecodes.
+    pub checkpoint_interval: usize,
+    /// Roughly how many bytes the journal may use. The oldest history is dropped, a checkpoint at a time, to stay under it.
+    pub memory_cap: usize,
+}
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            checkpoint_interval: 4096,
+            memory_cap: 64 << 20,
+        }
+    }
+}
+
+/// The last time a cell changed, from `Brainrot::last_change`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CellChange<C: Cell> {
+    /// How many bytecodes had run since recording started when the change was made.
+    pub step: u64,
+    /// The bytecode that made it.
+    pub pc: usize,
+    pub old: C,
+    pub new: C,
+}
+
+/// What one bytecode did: the pc and pointer it left behind, and its writes up to `writes_end`.
+struct Entry {
+    pc: usize,
+    pointer: usize,
+    writes_end: u64,
+}
+
+struct Write<C: Cell> {
+    /// `index - origin` of the tape, see `Tape::origin`.
+    cell: isize,
+    old: C,
+    new: C,
+}
+
+struct Checkpoint<C: Cell> {
+    step: u64,
+    pc: usize,
+    pointer: usize,
+    origin: usize,
+    tape: Box<[C]>,
+}
+
+/// A record of every bytecode the deopt tier ran, for going back in time.
+/// Steps are numbered from when recording started. Step `n` is the state after `n` bytecodes.
+pub struct Journal<C: Cell> {
+    config: JournalConfig,
+    /// 古い順。先頭のチェックポイントより前の履歴は持たない
+    checkpoints: VecDeque<Checkpoint<C>>,
+    /// `entries[i]` takes step `checkpoints[0].step + i` to the next one.
+    entries: VecDeque<Entry>,
+    writes: VecDeque<Write<C>>,
+    /// 捨てた書き込みの数。`Entry::writes_end` は通し番号なので、これを引いて添字にする
+    writes_base: u64,
+    position: u64,
+    /// The state `record` last saw, to tell whether a bytecode did anything.
+    pc: usize,
+    pointer: usize,
+    tape_len: usize,
+}
+
+impl<C: Cell> Journal<C> {
+    /// Starts at step 0 with the current tape, and turns on its write log.
+    pub fn new(config: JournalConfig, tape: &mut Tape<C>, pc: usize) -> Journal<C> {
+        tape.writes = Some(vec![]);
+        let mut journal = Journal {
+            config,
+            checkpoints: VecDeque::new(),
+            entries: VecDeque::new(),
+            writes: VecDeque::new(),
+            writes_base: 0,
+            position: 0,
+            pc,
+            pointer: tape.data_pointer,
+            tape_len: tape.buffer.len(),
+        };
+        journal.checkpoint(tape, pc);
+        journal
+    }
+
+    /// The oldest step that can still be gone back to.
+    pub fn oldest(&self) -> u64 {
+        self.checkpoints[0].step
+    }
+    /// The newest recorded step, where the program really is.
+    pub fn newest(&self) -> u64 {
+        self.oldest() + self.entries.len() as u64
+    }
+    /// The step the tape and pc show right now. Less than `newest` after going back.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    pub fn is_live(&self) -> bool {
+        self.position == self.newest()
+    }
+    /// The pc at `step`, which has to be between `oldest` and `newest`.
+    pub fn pc_at(&self, step: u64) -> usize {
+        match step - self.oldest() {
+            0 => self.checkpoints[0].pc,
+            i => self.entries[i as usize - 1].pc,
+        }
+    }
+    /// Bytes used by the entries, writes and checkpoints.
+    pub fn memory(&self) -> usize {
+        self.entries.len() * size_of::<Entry>()
+            + self.writes.len() * size_of::<Write<C>>()
+            + self.checkpoints.iter().map(|c| size_of::<Checkpoint<C>>() + c.tape.len() * size_of::<C>()).sum::<usize>()
+    }
+
+    /// Called before each bytecode and after the deopt tier returns. Records what happened since the last call, if anything.
+    pub fn record(&mut self, tape: &mut Tape<C>, pc: usize) {
+        let writes = tape.writes.as_mut().map(std::mem::take).unwrap_or_default();
+        let resized = tape.buffer.len() != self.tape_len;
+        if writes.is_empty() && pc == self.pc && tape.data_pointer == self.pointer && !resized {
+            return;
+        }
+
+        self.writes.extend(writes.into_iter().map(|(cell, old, new)| Write { cell, old, new }));
+        self.entries.push_back(Entry { pc, pointer: tape.data_pointer, writes_end: self.writes_base + self.writes.len() as u64 });
+        self.position += 1;
+        self.pc = pc;
+        self.pointer = tape.data_pointer;
+        self.tape_len = tape.buffer.len();
+
+        // 伸びたテープでは添字がずれるので、その直後から始め直せるようにする
+        let since = self.position - self.checkpoints.back().map_or(0, |c| c.step);
+        if resized || since >= self.config.checkpoint_interval as u64 {
+            self.checkpoint(tape, pc);
+        }
+        self.trim(tape, pc);
+    }
+
+    /// Puts back the writes and pointer move that `record` hasn't seen yet.
+    pub fn discard(&mut self, tape: &mut Tape<C>) {
+        if let Some(writes) = &mut tape.writes {
+            for (cell, old, _) in writes.drain(..).rev() {
+                tape.buffer[(cell + tape.origin as isize) as usize] = old;
+            }
+        }
+        tape.data_pointer = self.pointer;
+    }
+
+    fn checkpoint(&mut self, tape: &Tape<C>, pc: usize) {
+        self.checkpoints.push_back(Checkpoint {
+            step: self.position,
+            pc,
+            pointer: tape.data_pointer,
+            origin: tape.origin,
+            tape: tape.buffer.clone(),
+        });
+    }
+
+    /// Drops the oldest checkpoint and everything up to the next one until the journal fits in `memory_cap`.
+    fn trim(&mut self, tape: &Tape<C>, pc: usize) {
+        while self.memory() > self.config.memory_cap {
+            if self.checkpoints.len() == 1 {
+                if self.checkpoints[0].step == self.position {
+                    // 今のチェックポイントだけで上限を超えているなら、それ以上は減らせない
+                    return;
+                }
+                self.checkpoint(tape, pc);
+            }
+            self.checkpoints.pop_front();
+            let dropped = self.checkpoints[0].step - self.oldest_entry_step();
+            let writes_end = self.entries.drain(..dropped as usize).next_back().map_or(self.writes_base, |e| e.writes_end);
+            self.writes.drain(..(writes_end - self.writes_base) as usize);
+            self.writes_base = writes_end;
+        }
+    }
+    /// The step `entries[0]` starts from. The same as `oldest` except in the middle of `trim`.
+    fn oldest_entry_step(&self) -> u64 {
+        self.position - self.entries.len() as u64
+    }
+
+    /// Puts the tape back to how it was at `step`, which has to be between `oldest` and `newest`, and returns the pc there.
+    pub fn seek(&mut self, step: u64, tape: &mut Tape<C>) -> usize {
+        let at = self.checkpoints.partition_point(|c| c.step <= step) - 1;
+        let mut pc = self.restore(at, tape);
+        while self.position < step {
+            pc = self.advance(tape);
+        }
+        pc
+    }
+    fn restore(&mut self, at: usize, tape: &mut Tape<C>) -> usize {
+        let checkpoint = &self.checkpoints[at];
+        tape.buffer.clone_from(&checkpoint.tape);
+        tape.data_pointer = checkpoint.pointer;
+        tape.origin = checkpoint.origin;
+        self.position = checkpoint.step;
+        checkpoint.pc
+    }
+
+    /// Replays the next recorded bytecode, after going back. Returns the pc it left behind.
+    pub fn advance(&mut self, tape: &mut Tape<C>) -> usize {
+        // テープが伸びた bytecode の後には必ずチェックポイントがあるので、なぞらずにそこへ飛ぶ
+        let next = self.checkpoints.partition_point(|c| c.step <= self.position);
+        if self.checkpoints.get(next).is_some_and(|c| c.step == self.position + 1 && c.tape.len() != tape.buffer.len()) {
+            return self.restore(next, tape);
+        }
+        let i = (self.position - self.oldest()) as usize;
+        let start = match i {
+            0 => self.writes_base,
+            i => self.entries[i - 1].writes_end,
+        };
+        let entry = &self.entries[i];
+        for write in self.writes.range((start - self.writes_base) as usize..(entry.writes_end - self.writes_base) as usize) {
+            tape.buffer[(write.cell + tape.origin as isize) as usize] = write.new;
+        }
+        tape.data_pointer = entry.pointer;
+        self.position += 1;
+        entry.pc
+    }
+
+    /// The last time before the current step that the cell at `index` took a new value.
+    pub fn last_change(&self, tape: &Tape<C>, index: usize) -> Option<CellChange<C>> {
+        let cell = index as isize - tape.origin as isize;
+        let i = (self.position - self.oldest()) as usize;
+        let end = match i {
+            0 => self.writes_base,
+            i => self.entries[i - 1].writes_end,
+        };
+        let at = (0..(end - self.writes_base) as usize).rev()
+            .find(|&w| self.writes[w].cell == cell && self.writes[w].old != self.writes[w].new)?;
+        // どの bytecode の書き込みか
+        let entry = self.entries.partition_point(|e| e.writes_end <= self.writes_base + at as u64);
+        let step = self.oldest() + entry as u64;
+        Some(CellChange {
+            step,
+            pc: self.pc_at(step),
+            old: self.writes[at].old,
+            new: self.writes[at].new,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{brainrot::{Brainrot, BrainrotInit}, codegen::tests::PROGRAMS, error::BrainrotError, vm::{journal::JournalConfig, program::EofPolicy, tape::TapeMode}};
+
+    type Input<'a> = Box<dyn FnMut() -> Option<u8> + 'a>;
+    type Output<'a> = Box<dyn FnMut(u8) + 'a>;
+    type Vm<'a> = Brainrot<Input<'a>, Output<'a>, u8>;
+
+    fn vm<'a>(code: &str, tape_length: usize, tape_mode: TapeMode, input: &'a [u8], output: &'a mut Vec<u8>) -> Vm<'a> {
+        let mut input = input.iter().copied();
+        let input: Input<'a> = Box::new(move || input.next());
+        let output: Output<'a> = Box::new(|value| output.push(value));
+        Brainrot::new(code, BrainrotInit {
+            input,
+            output,
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+            tape_length,
+            tape_mode,
+        }).unwrap()
+    }
+
+    /// The pc, pointer and whole tape.
+    fn state(vm: &Vm) -> (usize, usize, Vec<u8>) {
+        (vm.pc(), vm.pointer(), (0..vm.tape_len()).map(|i| *vm.get_tape(i).unwrap()).collect())
+    }
+
+    /// Records a run a bytecode at a time until it ends or fails, and returns the state after each step, starting from step 0.
+    fn record(vm: &mut Vm, config: JournalConfig) -> Vec<(usize, usize, Vec<u8>)> {
+        vm.start_recording(config);
+        let mut states = vec![state(vm)];
+        while vm.journal().unwrap().newest() < 100_000 {
+            if vm.step_bytecode().is_err() || vm.journal().unwrap().newest() == states.len() as u64 - 1 {
+                break;
+            }
+            states.push(state(vm));
+        }
+        states
+    }
+
+    #[test]
+    fn reverse_step_and_replay() {
+        for (code, input) in PROGRAMS {
+            for mode in [TapeMode::Fixed, TapeMode::Wrapping] {
+                let mut expected = vec![];
+                let failed = vm(code, 16, mode, input, &mut expected).step().is_err();
+
+                let mut output = vec![];
+                let mut vm = vm(code, 16, mode, input, &mut output);
+                let states = record(&mut vm, JournalConfig { checkpoint_interval: 7, ..JournalConfig::default() });
+
+                for step in (0..states.len() - 1).rev() {
+                    assert!(vm.reverse_step_bytecode());
+                    assert_eq!(state(&vm), states[step], "{code} back to {step}");
+                }
+                assert!(!vm.reverse_step_bytecode());
+                // なぞり直しても入出力はやり直さない
+                for (step, expected) in states.iter().enumerate().skip(1) {
+                    vm.step_bytecode().unwrap();
+                    assert_eq!(&state(&vm), expected, "{code} forward to {step}");
+                }
+                assert!(vm.journal().unwrap().is_live());
+                assert_eq!(vm.step().is_err(), failed, "{code}");
+                drop(vm);
+                assert_eq!(output, expected, "{code}");
+            }
+        }
+    }
+
+    #[test]
+    fn trims_checkpoints_under_memory_cap() {
+        let (code, input) = PROGRAMS[0];
+        let mut output = vec![];
+        let mut full = vm(code, 16, TapeMode::Fixed, input, &mut output);
+        let states = record(&mut full, JournalConfig::default());
+
+        let mut output = vec![];
+        let mut vm = vm(code, 16, TapeMode::Fixed, input, &mut output);
+        let config = JournalConfig { checkpoint_interval: 16, memory_cap: 2048 };
+        record(&mut vm, config);
+        let journal = vm.journal().unwrap();
+        assert!(journal.memory() <= config.memory_cap, "{}", journal.memory());
+        assert!(journal.oldest() > 0);
+        assert_eq!(journal.newest(), states.len() as u64 - 1);
+
+        let oldest = journal.oldest() as usize;
+        for step in (oldest..states.len() - 1).rev() {
+            assert!(vm.reverse_step_bytecode());
+            assert_eq!(state(&vm), states[step], "back to {step}");
+        }
+        assert!(!vm.reverse_step_bytecode());
+        assert_eq!(vm.journal().unwrap().position(), oldest as u64);
+    }
+
+    #[test]
+    fn seeks_across_a_left_grow() {
+        // 左端を越えた `<` でテープが左に伸び、添字がずれる
+        let code = "++>+++<<+<<-->+[>]<.";
+        let mut output = vec![];
+        let mut vm = vm(code, 4, TapeMode::Growable, b"", &mut output);
+        let states = record(&mut vm, JournalConfig { checkpoint_interval: 3, ..JournalConfig::default() });
+        assert!(states.last().unwrap().2.len() > 4);
+
+        for step in (0..states.len() - 1).rev() {
+            assert!(vm.reverse_step_bytecode());
+            assert_eq!(state(&vm), states[step], "back to {step}");
+        }
+        for (step, expected) in states.iter().enumerate().skip(1) {
+            vm.step_bytecode().unwrap();
+            assert_eq!(&state(&vm), expected, "forward to {step}");
+        }
+    }
+
+    #[test]
+    fn reports_the_faulting_pointer() {
+        let pointer = |recording: bool| {
+            let mut output = vec![];
+            let mut vm = vm("+>+<<<-", 16, TapeMode::Fixed, b"", &mut output);
+            if recording {
+                vm.start_recording(JournalConfig::default());
+            }
+            match vm.step() {
+                Err(BrainrotError::RuntimeError { pointer, .. }) => (pointer, vm.pointer()),
+                _ => panic!("expected a runtime error"),
+            }
+        };
+        let (plain, _) = pointer(false);
+        let (recorded, after) = pointer(true);
+        assert_eq!(recorded, plain);
+        // テープは失敗した bytecode の前に戻っている
+        assert_eq!(after, 1);
+    }
+}
@@ -1,12 +1,16 @@
-use crate::{bytecode::bytecode::Bytecode, error::BrainrotError, vm::{program::Program, tape::Tape, tier::{BrainrotResult, internal::Tier, run}}};
+use crate::{bytecode::verify::VerifiedBytecode, cell::Cell, error::BrainrotError, vm::{io::{InputSource, OutputSink}, program::{EofPolicy, Program}, tape::Tape, tier::{BrainrotResult, internal::Tier, run}}};
 
+pub mod io;
+pub mod journal;
 pub mod program;
 pub mod tape;
 pub mod tier;
+pub mod watch;
 
-pub fn run_cisc<I: FnMut() -> u8, O: FnMut(u8) -> ()>(insts: Box<[Bytecode]>, timeout: Option<usize>, input: I, output: O) -> Result<BrainrotResult, BrainrotError> {
-    let mut tape = Tape::new();
-    let mut program = Program::new(insts, timeout, input, output, false);
+/// Runs bytecode built by hand or loaded from elsewhere. The tape is taken from what `insts` was verified for.
+pub fn run_cisc<I: InputSource, O: OutputSink, C: Cell>(insts: &VerifiedBytecode<C>, timeout: Option<usize>, input: I, output: O, eof: EofPolicy) -> Result<BrainrotResult, BrainrotError> {
+    let mut tape = Tape::new(insts.tape_length(), insts.tape_mode());
+    let mut program = Program::new(insts, timeout, input, output, false, eof);
     let mut tier = Tier::Deopt;
     if cfg!(feature = "trace") {
         println!("[TRACE] first: {:?}", tier);
@@ -1,44 +1,253 @@
-use crate::{bytecode::bytecode::Bytecode, error::RuntimeError, trace::OperationCountMap};
+use std::{collections::BTreeSet, ops::RangeInclusive, sync::{Arc, atomic::{AtomicBool, Ordering}}};
 
-pub struct Program<I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+use crate::{bytecode::{bytecode::{Bytecode, BytecodeOrigin}, verify::VerifiedBytecode}, cell::Cell, error::RuntimeError, trace::OperationCountMap, vm::{io::{Input, InputSource, OutputSink}, journal::Journal, tape::{Tape, TapeMode}, tier::{internal::InterpreterResult, jit::JitCache}, watch::Watches}};
+
+/// What `,` stores when the input callback reports EOF.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofPolicy {
+    #[default]
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+/// Stops a running program from another thread or a signal handler. See `Brainrot::interrupt_handle`.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+impl InterruptHandle {
+    /// Asks the program to stop at its next loop back-edge or scan step.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Remaining fuel, `None` for no limit, and the interrupt flag. Both are checked at the same places.
+/// Uses `Cell`s so the tiers can charge it while borrowing the current bytecode.
+pub struct Fuel {
+    remains: std::cell::Cell<Option<usize>>,
+    /// A scan stopped midway. The next run of the same `Shift*` bytecode carries on from the pointer
+    /// instead of applying its delta again.
+    mid_scan: std::cell::Cell<bool>,
+    interrupt: InterruptHandle,
+}
+impl Fuel {
+    pub fn new(remains: Option<usize>) -> Fuel {
+        Fuel { remains: std::cell::Cell::new(remains), mid_scan: std::cell::Cell::new(false), interrupt: InterruptHandle::default() }
+    }
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+    /// The flag itself, for native code to poll.
+    pub fn interrupt_flag(&self) -> *const AtomicBool {
+        Arc::as_ptr(&self.interrupt.flag)
+    }
+    pub fn interrupted(&self) -> bool {
+        self.interrupt.flag.load(Ordering::Relaxed)
+    }
+    /// Called once an interrupt has been reported, so that resuming does not stop again right away.
+    pub fn clear_interrupt(&self) {
+        self.interrupt.flag.store(false, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> Option<usize> {
+        self.remains.get()
+    }
+    pub fn set(&self, remains: Option<usize>) {
+        self.remains.set(remains);
+    }
+    /// Spends `cost` fuel. Fuel is only charged at loop back-edges and scan steps, so straight-line code is free.
+    /// When there is not enough left it drops to zero and fails with `TimeoutError`. Also fails with `Interrupted` if asked to.
+    pub fn charge(&self, cost: usize) -> Result<(), RuntimeError> {
+        if let Some(rem) = self.remains.get() {
+            if rem < cost {
+                self.remains.set(Some(0));
+                return Err(RuntimeError::TimeoutError);
+            }
+            self.remains.set(Some(rem - cost));
+        }
+        if self.interrupted() {
+            return Err(RuntimeError::Interrupted);
+        }
+        Ok(())
+    }
+    /// How many scan steps may be taken now. Pass what is left to `end_scan`.
+    pub fn begin_scan(&self) -> usize {
+        self.remains.get().unwrap_or(usize::MAX)
+    }
+    pub fn end_scan(&self, budget: usize) {
+        if self.remains.get().is_some() {
+            self.remains.set(Some(budget));
+        }
+    }
+    /// Stops a scan that ran out of fuel or was interrupted, leaving the pointer where it got to.
+    pub fn pause_scan(&self, budget: usize) -> RuntimeError {
+        self.end_scan(budget);
+        self.mid_scan.set(true);
+        if self.interrupted() { RuntimeError::Interrupted } else { RuntimeError::TimeoutError }
+    }
+    pub fn in_scan(&self) -> bool {
+        self.mid_scan.get()
+    }
+    /// Whether this scan resumes a paused one, in which case the delta has already been applied.
+    pub fn resume_scan(&self) -> bool {
+        self.mid_scan.replace(false)
+    }
+}
+
+/// Breakpoints, watchpoints, single-stepping and the journal. Only the deopt tier checks them, so it is the only tier that runs while they are in use.
+pub struct DebugControl<C: Cell> {
+    breakpoints: BTreeSet<usize>,
+    /// How many more bytecodes to run before stopping with `Stepped`, if stepping.
+    step_budget: Option<usize>,
+    /// The bytecode execution stopped before. Resuming runs it instead of stopping on its breakpoint again.
+    resume_at: Option<usize>,
+    journal: Option<Journal<C>>,
+    watches: Watches<C>,
+}
+impl<C: Cell> Default for DebugControl<C> {
+    fn default() -> Self {
+        DebugControl {
+            breakpoints: BTreeSet::new(),
+            step_budget: None,
+            resume_at: None,
+            journal: None,
+            watches: Watches::default(),
+        }
+    }
+}
+impl<C: Cell> DebugControl<C> {
+    pub fn forces_deopt(&self) -> bool {
+        !self.breakpoints.is_empty() || self.step_budget.is_some() || self.journal.is_some() || !self.watches.is_empty()
+    }
+    pub fn journal(&self) -> Option<&Journal<C>> {
+        self.journal.as_ref()
+    }
+    pub fn journal_mut(&mut self) -> Option<&mut Journal<C>> {
+        self.journal.as_mut()
+    }
+    pub fn set_journal(&mut self, journal: Option<Journal<C>>) {
+        self.journal = journal;
+    }
+    /// Hands what the last bytecode did to the journal, if recording.
+    pub fn record(&mut self, tape: &mut Tape<C>, pc: usize) {
+        if let Some(journal) = &mut self.journal {
+            journal.record(tape, pc);
+        }
+    }
+    /// Undoes what a bytecode that failed part way did, if recording, so that the tape matches the journal again.
+    pub fn discard(&mut self, tape: &mut Tape<C>) {
+        if let Some(journal) = &mut self.journal {
+            journal.discard(tape);
+        }
+        self.watches.forget();
+    }
+    pub fn watches(&self) -> &Watches<C> {
+        &self.watches
+    }
+    pub fn watches_mut(&mut self) -> &mut Watches<C> {
+        &mut self.watches
+    }
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<usize> {
+        &mut self.breakpoints
+    }
+    pub fn set_step_budget(&mut self, budget: Option<usize>) {
+        self.step_budget = budget;
+    }
+    /// Marks `pc` as not run yet, for results that leave the pc on a bytecode to retry.
+    pub fn resume_at(&mut self, pc: usize) {
+        self.resume_at = Some(pc);
+    }
+    /// For results that stop right after a bytecode finished. If that was the one being stepped, the step is over.
+    pub fn finish_step(&mut self, pc: usize) {
+        if self.step_budget == Some(0) {
+            self.step_budget = None;
+            self.resume_at = Some(pc);
+        }
+    }
+    /// Called by the deopt tier before running the bytecode at `pc`.
+    pub fn check(&mut self, pc: usize) -> Option<InterpreterResult> {
+        let resumed = self.resume_at.take() == Some(pc);
+        if self.step_budget == Some(0) {
+            self.step_budget = None;
+            self.resume_at = Some(pc);
+            return Some(InterpreterResult::Stepped);
+        }
+        if !resumed && self.breakpoints.contains(&pc) {
+            self.step_budget = None;
+            self.resume_at = Some(pc);
+            return Some(InterpreterResult::Breakpoint);
+        }
+        if let Some(budget) = &mut self.step_budget {
+            *budget -= 1;
+        }
+        None
+    }
+}
+
+pub struct Program<I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
 {
     pub ocm: OperationCountMap,
-    insts: Box<[Bytecode]>,
+    insts: Arc<[Bytecode<C>]>,
+    origins: Arc<[BytecodeOrigin]>,
     pc: usize,
-    pub step_remains: Option<usize>,
+    pub step_remains: Fuel,
     input_fn: I,
     output_fn: O,
     io_break: bool,
+    eof: EofPolicy,
+    jit: JitCache,
+    debug: DebugControl<C>,
 }
-impl<I, O> Program<I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+impl<I, O, C> Program<I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
 {
-    pub fn new(bytecodes: Box<[Bytecode]>, timeout: Option<usize>, input_fn: I, output_fn: O, io_break: bool) -> Program<I, O> {
+    /// Run it on a `Tape` built from the same length and mode as `bytecodes`, which the verification was done for.
+    pub fn new(bytecodes: &VerifiedBytecode<C>, timeout: Option<usize>, input_fn: I, output_fn: O, io_break: bool, eof: EofPolicy) -> Program<I, O, C> {
+        let bytecodes = bytecodes.insts().clone();
         let ocm = OperationCountMap::new(bytecodes.len());
+        let jit = JitCache::new(bytecodes.len());
         Program {
-            ocm,
+            ocm, jit,
             insts: bytecodes,
+            origins: Arc::new([]),
             pc: 0,
-            step_remains: timeout,
-            input_fn, output_fn, io_break,
+            step_remains: Fuel::new(timeout),
+            input_fn, output_fn, io_break, eof,
+            debug: DebugControl::default(),
         }
     }
-    pub fn check_timeout(&mut self) -> Result<(), RuntimeError> {
-        if let Some(rem) = self.step_remains.as_mut() {
-            *rem = rem.checked_sub(1).ok_or_else(|| RuntimeError::TimeoutError)?;
-        }
-        Ok(())
+    pub fn charge(&self, cost: usize) -> Result<(), RuntimeError> {
+        self.step_remains.charge(cost)
+    }
+    pub fn fuel(&self) -> &Fuel {
+        &self.step_remains
     }
     pub fn pc(&self) -> usize {
         self.pc
     }
-    pub fn insts(&self) -> &[Bytecode] {
+    pub fn insts(&self) -> &[Bytecode<C>] {
         &self.insts
     }
-    pub fn inst(&self) -> &Bytecode {
+    /// Attaches the side table from `ir_to_bytecodes_with_origins`.
+    pub fn set_origins(&mut self, origins: Arc<[BytecodeOrigin]>) {
+        self.origins = origins;
+    }
+    pub fn origins(&self) -> &[BytecodeOrigin] {
+        &self.origins
+    }
+    pub fn source_range(&self, pc: usize) -> Option<RangeInclusive<usize>> {
+        self.origins.get(pc).and_then(|origin| origin.source_range.clone())
+    }
+    pub fn inst(&self) -> &Bytecode<C> {
         &self.insts[self.pc]
     }
     pub fn step(&mut self) {
@@ -50,32 +259,65 @@ where I: FnMut() -> u8,
     pub fn jump_back(&mut self, addr: usize) {
         self.pc = self.pc.wrapping_sub(addr);
     }
-    pub fn input(&mut self) -> u8 {
-        (self.input_fn)()
+    /// Reads one byte, applying the EOF policy. `Eof` is only left over for `EofPolicy::Unchanged`, meaning the cell must be left unchanged.
+    /// Flushes the output first, so that a prompt shows up before the program waits for input.
+    pub fn input(&mut self) -> Result<Input<C>, RuntimeError> {
+        self.output_fn.flush()?;
+        Ok(match self.input_fn.read()? {
+            Input::Byte(value) => Input::Byte(C::from_u8(value)),
+            Input::Eof => match self.eof {
+                EofPolicy::Zero => Input::Byte(C::ZERO),
+                EofPolicy::MinusOne => Input::Byte(C::MAX),
+                EofPolicy::Unchanged => Input::Eof,
+            }
+            Input::Pending => Input::Pending,
+        })
+    }
+    /// Writes one byte. Returns whether the sink wants `step` to stop here.
+    pub fn output(&mut self, value: C) -> Result<bool, RuntimeError> {
+        Ok(self.output_fn.write(value.to_u8())?)
     }
-    pub fn output(&mut self, value: u8) {
-        (self.output_fn)(value)
+    pub fn flush(&mut self) -> Result<(), RuntimeError> {
+        Ok(self.output_fn.flush()?)
+    }
+    pub fn input_source(&mut self) -> &mut I {
+        &mut self.input_fn
     }
     pub fn io_break(&self) -> bool {
         self.io_break
     }
+    pub fn debug(&self) -> &DebugControl<C> {
+        &self.debug
+    }
+    pub fn debug_mut(&mut self) -> &mut DebugControl<C> {
+        &mut self.debug
+    }
+    /// Whether the bytecode that ran since `Watches::before` hit a watchpoint.
+    pub fn check_watches(&mut self, tape: &Tape<C>) -> Option<InterpreterResult> {
+        self.debug.watches.after(tape, &self.insts)
+    }
+    pub fn jit_parts(&mut self) -> (&[Bytecode<C>], &mut JitCache) {
+        (&self.insts, &mut self.jit)
+    }
 }
 
-pub struct UnsafeProgram<'a, I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+pub struct UnsafeProgram<'a, I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
  {
-    pub inner: &'a mut Program<I, O>,
+    pub inner: &'a mut Program<I, O, C>,
     insts_len: usize,
-    internal_insts_at: *const Bytecode,
-    internal_pc: *const Bytecode,
+    internal_insts_at: *const Bytecode<C>,
+    internal_pc: *const Bytecode<C>,
 }
 #[allow(unsafe_op_in_unsafe_fn)]
-impl<'a, I, O> UnsafeProgram<'a, I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+impl<'a, I, O, C> UnsafeProgram<'a, I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
  {
-    pub unsafe fn new(program: &'a mut Program<I, O>) -> UnsafeProgram<'a, I, O> {
+    pub unsafe fn new(program: &'a mut Program<I, O, C>) -> UnsafeProgram<'a, I, O, C> {
         let insts_len = program.insts.len();
         let internal_insts_at = program.insts.as_ptr();
         let pc = program.pc();
@@ -87,14 +329,17 @@ where I: FnMut() -> u8,
         }
     }
 
-    pub fn check_timeout(&mut self) -> Result<(), RuntimeError> {
-        self.inner.check_timeout()
+    pub fn charge(&self, cost: usize) -> Result<(), RuntimeError> {
+        self.inner.charge(cost)
+    }
+    pub fn fuel(&self) -> &Fuel {
+        self.inner.fuel()
     }
     pub fn pc(&self) -> usize {
         // SAFETY: 差分を求めるだけだから安全なはず
         unsafe { self.internal_pc.offset_from_unsigned(self.internal_insts_at) }
     }
-    pub unsafe fn inst(&self) -> &Bytecode {
+    pub unsafe fn inst(&self) -> &Bytecode<C> {
         if cfg!(feature = "debug") && self.pc() >= self.insts_len {
             panic!("[UNSAFE] Runtime Error: Out of range insts");
         }
@@ -110,12 +355,160 @@ where I: FnMut() -> u8,
     pub unsafe fn jump_one(&mut self) {
         self.internal_pc = self.internal_pc.add(1);
     }
+    /// Call after taking a loop's back-edge. Returns whether to continue in the JIT tier.
+    /// Native code does not charge fuel, so the JIT tier is skipped while a limit is set.
+    pub fn back_edge(&mut self, mode: TapeMode) -> bool {
+        if self.inner.step_remains.get().is_some() {
+            return false;
+        }
+        let pc = self.pc();
+        self.inner.jit.hit(pc, mode)
+    }
 }
-impl<'a, I, O> Drop for UnsafeProgram<'a, I, O>
-where I: FnMut() -> u8,
-      O: FnMut(u8) -> (),
+impl<'a, I, O, C> Drop for UnsafeProgram<'a, I, O, C>
+where I: InputSource,
+      O: OutputSink,
+      C: Cell,
  {
     fn drop(&mut self) {
         self.inner.pc = self.pc();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::{self, Read, Write}, rc::Rc};
+
+    use crate::{brainrot::{Brainrot, BrainrotInit}, cell::Cell, vm::{program::EofPolicy, tape::TapeMode, tier::BrainrotResult}};
+
+    /// The output, and the cell the pointer ends on.
+    fn run<C: Cell>(code: &str, input: &[u8], eof: EofPolicy) -> (Vec<u8>, u32) {
+        let mut output = vec![];
+        let mut input = input.iter().copied();
+        let mut vm = Brainrot::<_, _, C>::new(code, BrainrotInit {
+            input: || input.next(),
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: None,
+            eof,
+            tape_length: 16,
+            tape_mode: Default::default(),
+        }).unwrap();
+        vm.step().unwrap();
+        let cell = vm.get_tape(0).unwrap().to_u32();
+        drop(vm);
+        (output, cell)
+    }
+
+    #[test]
+    fn eof_policies() {
+        assert_eq!(run::<u8>("+++,.", b"", EofPolicy::Zero), (vec![0], 0));
+        assert_eq!(run::<u8>("+++,.", b"", EofPolicy::MinusOne), (vec![255], 255));
+        assert_eq!(run::<u8>("+++,.", b"", EofPolicy::Unchanged), (vec![3], 3));
+    }
+
+    #[test]
+    fn input_is_read_before_eof() {
+        for eof in [EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Unchanged] {
+            let (output, _) = run::<u8>("+++,.,.", b"a", eof);
+            assert_eq!(output, [b'a', match eof {
+                EofPolicy::Zero => 0,
+                EofPolicy::MinusOne => 255,
+                EofPolicy::Unchanged => b'a',
+            }]);
+        }
+    }
+
+    #[test]
+    fn minus_one_fills_wide_cells() {
+        // 出力は下位 8 ビットだけ
+        assert_eq!(run::<u16>(",.", b"", EofPolicy::MinusOne), (vec![0xff], 0xffff));
+        assert_eq!(run::<u32>(",.", b"", EofPolicy::MinusOne), (vec![0xff], u32::MAX));
+        assert_eq!(run::<u16>(",+.", b"", EofPolicy::MinusOne), (vec![0], 0));
+    }
+
+    /// Whether `code` ends within `fuel`, or runs out.
+    fn ends_within(code: &str, fuel: usize, tape_mode: TapeMode) -> bool {
+        let mut vm = Brainrot::<_, _, u8>::new(code, BrainrotInit {
+            input: || None,
+            output: |_| {},
+            io_break: false,
+            timeout_step: Some(fuel),
+            eof: EofPolicy::Zero,
+            tape_length: 16,
+            tape_mode,
+        }).unwrap();
+        match vm.step() {
+            Ok(BrainrotResult::End) => true,
+            Ok(BrainrotResult::OutOfFuel) => false,
+            Ok(_) => panic!("unexpected result"),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    #[test]
+    fn infinite_loop_runs_out() {
+        for tape_mode in [TapeMode::Fixed, TapeMode::Growable, TapeMode::Wrapping] {
+            for fuel in [0, 1, 2, 100, 10000] {
+                assert!(!ends_within("+[]", fuel, tape_mode));
+            }
+        }
+    }
+
+    #[test]
+    fn charges_back_edges_and_scans() {
+        // 後方ジャンプ 1 回につき本体の長さ + 1、スキャンは 1 マスにつき 1
+        for (code, fuel) in [("+>+>+>+<<<[>]", 4), ("+++[>+.<-]", 8), ("+++[>+.<-]>>+>+>+<<<[>]", 12), ("++[>+++[>+.<-]<-]", 24)] {
+            for tape_mode in [TapeMode::Fixed, TapeMode::Growable, TapeMode::Wrapping] {
+                assert!(!ends_within(code, fuel - 1, tape_mode), "{code} {tape_mode:?}");
+                assert!(ends_within(code, fuel, tape_mode), "{code} {tape_mode:?}");
+            }
+        }
+    }
+
+    /// What reached the underlying writer, past its buffer.
+    #[derive(Clone, Default)]
+    struct Written(Rc<RefCell<Vec<u8>>>);
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Notes what had been written by the time of each read.
+    struct Prompted {
+        written: Written,
+        seen: Rc<RefCell<Vec<Vec<u8>>>>,
+        input: &'static [u8],
+    }
+    impl Read for Prompted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.seen.borrow_mut().push(self.written.0.borrow().clone());
+            self.input.read(buf)
+        }
+    }
+
+    #[test]
+    fn flushes_output_before_reading() {
+        let written = Written::default();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut vm = Brainrot::<_, _, u8>::with_io("++++++++[>++++++++<-]>+.>++++++++++.,.", BrainrotInit {
+            input: Prompted { written: written.clone(), seen: seen.clone(), input: b"x" },
+            output: written.clone(),
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+            tape_length: 16,
+            tape_mode: TapeMode::Fixed,
+        }).unwrap();
+        vm.step().unwrap();
+        drop(vm);
+
+        assert_eq!(*seen.borrow(), [b"A\n".to_vec()]);
+        assert_eq!(*written.0.borrow(), b"A\nx");
+    }
+}
@@ -1,108 +1,397 @@
-use crate::{TAPE_LENGTH, error::RuntimeError};
+use std::{mem::{size_of, take}, ops::RangeBounds};
 
-pub struct Tape {
-    pub buffer: Box<[u8; TAPE_LENGTH]>,
+use crate::{cell::Cell, error::RuntimeError, vm::program::Fuel};
+
+/// Growable でもポインタのレンジチェックを u32 で行えるように、これ以上は伸ばさない
+const GROWABLE_MAX_LENGTH: usize = i32::MAX as usize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TapeMode {
+    /// Going past either end is a runtime error.
+    #[default]
+    Fixed,
+    /// The tape is reallocated on demand when a write goes past either end.
+    Growable,
+    /// The pointer is taken modulo the tape length, so `<` from cell 0 lands on the last cell.
+    Wrapping,
+}
+
+pub struct Tape<C: Cell> {
+    pub buffer: Box<[C]>,
     pub data_pointer: usize,
+    pub mode: TapeMode,
+    /// How many cells a Growable tape has grown to the left. `index - origin` names the same cell before and after growing.
+    pub origin: usize,
+    /// `(index - origin, old, new)` for every write, while a `Journal` is recording.
+    pub writes: Option<Vec<(isize, C, C)>>,
 }
-impl Tape {
-    pub fn new() -> Tape {
+impl<C: Cell> Tape<C> {
+    pub fn new(length: usize, mode: TapeMode) -> Tape<C> {
         Tape {
-            buffer: Box::new([0; TAPE_LENGTH]),
+            buffer: vec![C::ZERO; length].into_boxed_slice(),
             data_pointer: 0,
+            mode,
+            origin: 0,
+            writes: None,
         }
     }
-    pub fn get(&self) -> Result<u8, RuntimeError> {
-        self.buffer.get(self.data_pointer).ok_or_else(|| RuntimeError::OOBGet(self.data_pointer)).copied()
+
+    /// Returns the index of `ptr`, wrapping it or growing the tape first if the mode allows it.
+    fn reserve(&mut self, ptr: usize) -> Option<usize> {
+        if ptr < self.buffer.len() {
+            return Some(ptr);
+        }
+        match self.mode {
+            TapeMode::Fixed => None,
+            TapeMode::Growable => self.grow(ptr),
+            TapeMode::Wrapping => self.wrap(ptr),
+        }
     }
-    pub fn set(&mut self, value: u8) -> Result<(), RuntimeError> {
-        let cell = self.buffer.get_mut(self.data_pointer).ok_or_else(|| RuntimeError::OOBSet(self.data_pointer, value))?;
-        Ok(*cell = value)
+    fn wrap(&self, ptr: usize) -> Option<usize> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        Some((ptr as isize).rem_euclid(self.buffer.len() as isize) as usize)
     }
-    pub fn add(&mut self, value: u8) -> Result<(), RuntimeError> {
-        let cell = self.buffer.get_mut(self.data_pointer).ok_or_else(|| RuntimeError::OOBAdd(self.data_pointer, value))?;
-        Ok(*cell = cell.wrapping_add(value))
+    /// Growing to the left shifts both `ptr` and `data_pointer`.
+    fn grow(&mut self, ptr: usize) -> Option<usize> {
+        let len = self.buffer.len();
+        if (ptr as isize) < 0 {
+            let extra = (ptr as isize).unsigned_abs().max(len);
+            if len + extra > GROWABLE_MAX_LENGTH {
+                return None;
+            }
+            let mut buffer = vec![C::ZERO; len + extra];
+            buffer[extra..].copy_from_slice(&self.buffer);
+            self.buffer = buffer.into_boxed_slice();
+            self.data_pointer = self.data_pointer.wrapping_add(extra);
+            self.origin += extra;
+            Some(ptr.wrapping_add(extra))
+        } else {
+            if ptr >= GROWABLE_MAX_LENGTH {
+                return None;
+            }
+            let mut buffer = take(&mut self.buffer).into_vec();
+            buffer.resize((ptr + 1).max(len * 2).min(GROWABLE_MAX_LENGTH), C::ZERO);
+            self.buffer = buffer.into_boxed_slice();
+            Some(ptr)
+        }
+    }
+
+    pub fn get(&self) -> Result<C, RuntimeError> {
+        match self.buffer.get(self.data_pointer) {
+            Some(value) => Ok(*value),
+            None if self.mode == TapeMode::Growable => Ok(C::ZERO),
+            None => Err(RuntimeError::OOBGet(self.data_pointer)),
+        }
+    }
+    pub fn set(&mut self, value: C) -> Result<(), RuntimeError> {
+        let ptr = self.reserve(self.data_pointer).ok_or_else(|| RuntimeError::OOBSet(self.data_pointer, value.to_u32()))?;
+        Ok(self.write(ptr, value))
+    }
+    pub fn add(&mut self, value: C) -> Result<(), RuntimeError> {
+        let ptr = self.reserve(self.data_pointer).ok_or_else(|| RuntimeError::OOBAdd(self.data_pointer, value.to_u32()))?;
+        Ok(self.write(ptr, self.buffer[ptr].wrapping_add(value)))
     }
     
-    pub fn add_with_offset(&mut self, delta: isize, value: u8) -> Result<(), RuntimeError> {
+    pub fn add_with_offset(&mut self, delta: isize, value: C) -> Result<(), RuntimeError> {
         let ptr = self.data_pointer.wrapping_add_signed(delta);
-        let cell = self.buffer.get_mut(ptr).ok_or_else(|| RuntimeError::OOBAdd(ptr, value))?;
-        Ok(*cell = cell.wrapping_add(value))
+        let ptr = self.reserve(ptr).ok_or_else(|| RuntimeError::OOBAdd(ptr, value.to_u32()))?;
+        Ok(self.write(ptr, self.buffer[ptr].wrapping_add(value)))
     }
-    pub fn sub_with_offset(&mut self, delta: isize, value: u8) -> Result<(), RuntimeError> {
+    pub fn sub_with_offset(&mut self, delta: isize, value: C) -> Result<(), RuntimeError> {
         let ptr = self.data_pointer.wrapping_add_signed(delta);
-        let cell = self.buffer.get_mut(ptr).ok_or_else(|| RuntimeError::OOBSub(ptr, value))?;
-        Ok(*cell = cell.wrapping_sub(value))
+        let ptr = self.reserve(ptr).ok_or_else(|| RuntimeError::OOBSub(ptr, value.to_u32()))?;
+        Ok(self.write(ptr, self.buffer[ptr].wrapping_sub(value)))
+    }
+    fn write(&mut self, ptr: usize, value: C) {
+        if let Some(writes) = &mut self.writes {
+            writes.push((ptr as isize - self.origin as isize, self.buffer[ptr], value));
+        }
+        self.buffer[ptr] = value;
     }
 
     pub fn step(&mut self, delta: isize) {
         self.data_pointer = self.data_pointer.wrapping_add_signed(delta);
+        if self.mode == TapeMode::Wrapping && self.data_pointer >= self.buffer.len() {
+            self.data_pointer = self.wrap(self.data_pointer).unwrap_or(self.data_pointer);
+        }
+    }
+
+    /// Moves by `delta`, then by `step` until a zero cell, charging one fuel per step.
+    /// Running out of fuel pauses the scan where it got to; running the same bytecode again resumes it.
+    pub fn shift(&mut self, delta: isize, step: isize, fuel: &Fuel) -> Result<(), RuntimeError> {
+        if !fuel.resume_scan() {
+            self.step(delta);
+        }
+        let mut budget = fuel.begin_scan();
+        while self.get()? != C::ZERO {
+            if budget == 0 || fuel.interrupted() {
+                return Err(fuel.pause_scan(budget));
+            }
+            budget -= 1;
+            self.step(step);
+        }
+        fuel.end_scan(budget);
+        Ok(())
+    }
+
+    /// Whether the opt tier may be entered here. A Growable tape reads zero past its allocation,
+    /// so the pointer itself has to be checked as well as the range operand.
+    pub fn can_opt(&self, range: &impl RangeBounds<u32>) -> bool {
+        self.data_pointer < self.buffer.len() && range.contains(&(self.data_pointer as u32))
     }
 }
 
-pub struct UnsafeTape<'a> {
-    pub inner: &'a mut Tape,
-    buffer_at: *mut u8,
-    data_pointer: *mut u8,
+pub struct UnsafeTape<'a, C: Cell> {
+    pub inner: &'a mut Tape<C>,
+    buffer_at: *mut C,
+    data_pointer: *mut C,
+    wrap_length: Option<usize>,
 }
 
 #[allow(unsafe_op_in_unsafe_fn)]
-impl<'a> UnsafeTape<'a> {
-    pub unsafe fn new(tape: &'a mut Tape) -> UnsafeTape<'a> {
+impl<'a, C: Cell> UnsafeTape<'a, C> {
+    pub unsafe fn new(tape: &'a mut Tape<C>) -> UnsafeTape<'a, C> {
         let buffer_at = tape.buffer.as_mut_ptr();
-        let data_pointer = buffer_at.add(tape.data_pointer);
-        UnsafeTape { inner: tape, buffer_at, data_pointer }
+        let data_pointer = buffer_at.wrapping_add(tape.data_pointer);
+        let wrap_length = (tape.mode == TapeMode::Wrapping && !tape.buffer.is_empty()).then_some(tape.buffer.len());
+        UnsafeTape { inner: tape, buffer_at, data_pointer, wrap_length }
     }
 
+    fn index_of(&self, pointer: *mut C) -> usize {
+        (pointer.addr().wrapping_sub(self.buffer_at.addr()) as isize / size_of::<C>() as isize) as usize
+    }
     pub fn get_ptr(&self) -> usize {
-        self.data_pointer.addr().wrapping_sub(self.buffer_at.addr())
+        self.index_of(self.data_pointer)
     }
 
-    pub fn rangecheck(&self, offset: isize) {
-        if TAPE_LENGTH <= (self.get_ptr().wrapping_add_signed(offset)) {
-            panic!("[UNSAFE] Runtime Error: Out of range memory operation. Address: {} ", self.get_ptr());
+    pub fn rangecheck(&self, pointer: *mut C) {
+        let index = self.index_of(pointer);
+        if self.inner.buffer.len() <= index {
+            panic!("[UNSAFE] Runtime Error: Out of range memory operation. Address: {} ", index);
+        }
+    }
+
+    /// Brings a pointer that left the buffer back in for Wrapping tapes.
+    fn wrap(&self, pointer: *mut C) -> *mut C {
+        match self.wrap_length {
+            Some(len) => {
+                let index = self.index_of(pointer);
+                if index < len {
+                    pointer
+                } else {
+                    self.buffer_at.wrapping_add((index as isize).rem_euclid(len as isize) as usize)
+                }
+            }
+            None => pointer,
         }
     }
 
     pub unsafe fn step_ptr(&mut self, delta: isize) {
-        self.data_pointer = self.data_pointer.wrapping_add(delta as usize);
+        self.data_pointer = self.wrap(self.data_pointer.wrapping_offset(delta));
     }
 
-    pub fn get_safe(&self, abs_ptr: usize) -> Result<u8, RuntimeError> {
-        self.inner.buffer.get(abs_ptr).ok_or_else(|| RuntimeError::OOBGet(abs_ptr)).copied()
+    /// Runs a checked operation on the inner tape. Growable tapes may reallocate, so the raw pointers are refreshed afterwards.
+    fn with_inner<R>(&mut self, f: impl FnOnce(&mut Tape<C>) -> R) -> R {
+        self.inner.data_pointer = self.get_ptr();
+        let result = f(self.inner);
+        self.buffer_at = self.inner.buffer.as_mut_ptr();
+        self.data_pointer = self.buffer_at.wrapping_add(self.inner.data_pointer);
+        result
     }
-    pub unsafe fn get(&self) -> u8 {
-        if cfg!(feature = "debug") { self.rangecheck(0); }
+
+    /// Moves by `delta`, then by `step` until a zero cell, charging one fuel per step like `Tape::shift`.
+    /// Returns `false` if a Growable tape ran off its allocation, in which case the caller has to deopt before touching the cell.
+    pub fn shift(&mut self, delta: isize, step: isize, fuel: &Fuel) -> Result<bool, RuntimeError> {
+        if !fuel.resume_scan() {
+            self.data_pointer = self.wrap(self.data_pointer.wrapping_offset(delta));
+        }
+        let mut budget = fuel.begin_scan();
+        let result = loop {
+            let ptr = self.get_ptr();
+            match self.inner.buffer.get(ptr) {
+                Some(value) if *value == C::ZERO => break Ok(true),
+                Some(_) if budget == 0 || fuel.interrupted() => return Err(fuel.pause_scan(budget)),
+                Some(_) => {
+                    budget -= 1;
+                    self.data_pointer = self.wrap(self.data_pointer.wrapping_offset(step));
+                }
+                None if self.inner.mode == TapeMode::Growable => break Ok(false),
+                None => break Err(RuntimeError::OOBGet(ptr)),
+            }
+        };
+        fuel.end_scan(budget);
+        result
+    }
+
+    pub fn get_safe(&mut self) -> Result<C, RuntimeError> {
+        self.with_inner(|tape| tape.get())
+    }
+    pub unsafe fn get(&self) -> C {
+        if cfg!(feature = "debug") { self.rangecheck(self.data_pointer); }
         *self.data_pointer
     }
-    pub fn set_safe(&mut self, abs_ptr: usize, value: u8) -> Result<(), RuntimeError> {
-        let cell = self.inner.buffer.get_mut(abs_ptr).ok_or_else(|| RuntimeError::OOBSet(abs_ptr, value))?;
-        Ok(*cell = value)
+    pub fn set_safe(&mut self, value: C) -> Result<(), RuntimeError> {
+        self.with_inner(|tape| tape.set(value))
     }
-    pub unsafe fn set(&mut self, value: u8) {
-        if cfg!(feature = "debug") { self.rangecheck(0); }
+    pub unsafe fn set(&mut self, value: C) {
+        if cfg!(feature = "debug") { self.rangecheck(self.data_pointer); }
         *self.data_pointer = value;
     }
-    pub fn add_safe(&mut self, abs_ptr: usize, value: u8) -> Result<(), RuntimeError> {
-        let cell = self.inner.buffer.get_mut(abs_ptr).ok_or_else(|| RuntimeError::OOBSet(abs_ptr, value))?;
-        Ok(*cell = cell.wrapping_add(value))
+    pub fn add_safe(&mut self, value: C) -> Result<(), RuntimeError> {
+        self.with_inner(|tape| tape.add(value))
     }
-    pub unsafe fn add(&mut self, value: u8) {
-        if cfg!(feature = "debug") { self.rangecheck(0); }
+    pub unsafe fn add(&mut self, value: C) {
+        if cfg!(feature = "debug") { self.rangecheck(self.data_pointer); }
         *self.data_pointer = (*self.data_pointer).wrapping_add(value);
     }
-    pub unsafe fn add_with_offset(&mut self, offset: isize, value: u8) {
-        if cfg!(feature = "debug") { self.rangecheck(offset); }
-        let p = self.data_pointer.wrapping_add(offset as usize);
+    pub unsafe fn add_with_offset(&mut self, offset: isize, value: C) {
+        let p = self.wrap(self.data_pointer.wrapping_offset(offset));
+        if cfg!(feature = "debug") { self.rangecheck(p); }
         *p = (*p).wrapping_add(value);
     }
-    pub unsafe fn sub_with_offset(&mut self, offset: isize, value: u8) {
-        if cfg!(feature = "debug") { self.rangecheck(offset); }
-        let p = self.data_pointer.wrapping_add(offset as usize);
+    pub unsafe fn sub_with_offset(&mut self, offset: isize, value: C) {
+        let p = self.wrap(self.data_pointer.wrapping_offset(offset));
+        if cfg!(feature = "debug") { self.rangecheck(p); }
         *p = (*p).wrapping_sub(value);
     }
 }
-impl<'a> Drop for UnsafeTape<'a> {
+impl<'a, C: Cell> Drop for UnsafeTape<'a, C> {
     fn drop(&mut self) {
         self.inner.data_pointer = self.get_ptr();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_to_the_left() {
+        let mut tape = Tape::<u8>::new(4, TapeMode::Growable);
+        tape.set(1).unwrap();
+        tape.step(1);
+        tape.set(2).unwrap();
+        tape.step(-2);
+        tape.set(3).unwrap();
+
+        // 足りない分と元の長さの大きい方だけ左に伸びる
+        assert_eq!(tape.buffer.len(), 8);
+        assert_eq!(tape.data_pointer, 3);
+        assert_eq!(tape.origin, 4);
+        assert_eq!(&tape.buffer[..], &[0, 0, 0, 3, 1, 2, 0, 0]);
+
+        tape.step(-5);
+        tape.add_with_offset(1, 4).unwrap();
+        assert_eq!(tape.buffer.len(), 16);
+        assert_eq!(tape.data_pointer, 6);
+        assert_eq!(tape.origin, 12);
+        assert_eq!(&tape.buffer[6..], &[0, 4, 0, 0, 0, 3, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn grows_to_the_right() {
+        let mut tape = Tape::<u8>::new(4, TapeMode::Growable);
+        tape.step(9);
+        assert!(matches!(tape.get(), Ok(0)));
+        tape.add(5).unwrap();
+        assert_eq!(tape.buffer.len(), 10);
+        assert_eq!(tape.buffer[9], 5);
+    }
+
+    #[test]
+    fn stops_growing_at_the_limit() {
+        let mut tape = Tape::<u8>::new(4, TapeMode::Growable);
+        tape.step(GROWABLE_MAX_LENGTH as isize);
+        assert!(matches!(tape.set(1), Err(RuntimeError::OOBSet(GROWABLE_MAX_LENGTH, 1))));
+        assert_eq!(tape.buffer.len(), 4);
+
+        let mut tape = Tape::<u8>::new(4, TapeMode::Growable);
+        tape.step(-(GROWABLE_MAX_LENGTH as isize));
+        assert!(matches!(tape.add(1), Err(RuntimeError::OOBAdd(ptr, 1)) if ptr == GROWABLE_MAX_LENGTH.wrapping_neg()));
+        assert_eq!(tape.buffer.len(), 4);
+        assert_eq!(tape.data_pointer, GROWABLE_MAX_LENGTH.wrapping_neg());
+    }
+
+    #[test]
+    fn wraps_left_from_zero() {
+        let mut tape = Tape::<u8>::new(4, TapeMode::Wrapping);
+        tape.step(-1);
+        assert_eq!(tape.data_pointer, 3);
+        tape.set(7).unwrap();
+        tape.add_with_offset(-4, 1).unwrap();
+        tape.sub_with_offset(2, 2).unwrap();
+        assert_eq!(&tape.buffer[..], &[0, 254, 0, 8]);
+
+        let mut tape = Tape::<u8>::new(4, TapeMode::Wrapping);
+        let mut unsafe_tape = unsafe { UnsafeTape::new(&mut tape) };
+        unsafe {
+            unsafe_tape.step_ptr(-1);
+            unsafe_tape.add(3);
+            unsafe_tape.add_with_offset(-3, 1);
+        }
+        assert_eq!(unsafe_tape.get_ptr(), 3);
+        drop(unsafe_tape);
+        assert_eq!(tape.data_pointer, 3);
+        assert_eq!(&tape.buffer[..], &[1, 0, 0, 3]);
+    }
+
+    #[test]
+    fn scans_across_the_end() {
+        let mut tape = Tape::<u8>::new(5, TapeMode::Wrapping);
+        tape.buffer.copy_from_slice(&[1, 0, 1, 1, 1]);
+        tape.data_pointer = 2;
+        let mut unsafe_tape = unsafe { UnsafeTape::new(&mut tape) };
+        // 2 -> 4 -> 1 で 0 に当たる
+        assert!(matches!(unsafe_tape.shift(0, 2, &Fuel::new(None)), Ok(true)));
+        assert_eq!(unsafe_tape.get_ptr(), 1);
+
+        let mut tape = Tape::<u8>::new(5, TapeMode::Wrapping);
+        tape.buffer.copy_from_slice(&[1, 1, 1, 0, 1]);
+        tape.data_pointer = 1;
+        let mut unsafe_tape = unsafe { UnsafeTape::new(&mut tape) };
+        assert!(matches!(unsafe_tape.shift(0, -1, &Fuel::new(None)), Ok(true)));
+        assert_eq!(unsafe_tape.get_ptr(), 3);
+    }
+
+    #[test]
+    fn shift_out_of_fuel_pauses() {
+        let mut tape = Tape::<u8>::new(8, TapeMode::Fixed);
+        tape.buffer.copy_from_slice(&[0, 1, 1, 1, 0, 0, 0, 0]);
+        let fuel = Fuel::new(Some(1));
+        assert!(matches!(tape.shift(1, 1, &fuel), Err(RuntimeError::TimeoutError)));
+        assert_eq!(tape.data_pointer, 2);
+        assert_eq!(fuel.get(), Some(0));
+
+        // 続きから再開し、delta はもう足さない
+        fuel.set(Some(1));
+        assert!(matches!(tape.shift(1, 1, &fuel), Err(RuntimeError::TimeoutError)));
+        assert_eq!(tape.data_pointer, 3);
+        fuel.set(Some(5));
+        tape.shift(1, 1, &fuel).unwrap();
+        assert_eq!(tape.data_pointer, 4);
+        assert_eq!(fuel.get(), Some(4));
+
+        tape.data_pointer = 0;
+        let fuel = Fuel::new(Some(2));
+        let mut unsafe_tape = unsafe { UnsafeTape::new(&mut tape) };
+        assert!(matches!(unsafe_tape.shift(1, 1, &fuel), Err(RuntimeError::TimeoutError)));
+        assert_eq!(unsafe_tape.get_ptr(), 3);
+        fuel.set(Some(1));
+        assert!(matches!(unsafe_tape.shift(1, 1, &fuel), Ok(true)));
+        assert_eq!(unsafe_tape.get_ptr(), 4);
+        assert_eq!(fuel.get(), Some(0));
+    }
+
+    #[test]
+    fn fixed_does_not_grow() {
+        let mut tape = Tape::<u8>::new(4, TapeMode::Fixed);
+        tape.step(-1);
+        assert!(matches!(tape.get(), Err(RuntimeError::OOBGet(usize::MAX))));
+        assert!(matches!(tape.add(1), Err(RuntimeError::OOBAdd(usize::MAX, 1))));
+        tape.step(5);
+        assert!(matches!(tape.set(1), Err(RuntimeError::OOBSet(4, 1))));
+        assert_eq!(tape.buffer.len(), 4);
+    }
+}
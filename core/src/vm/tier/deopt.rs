@@ -1,14 +1,31 @@
-use crate::{bytecode::bytecode::Bytecode, error::RuntimeError, vm::{program::Program, tape::Tape, tier::internal::{InterpreterResult, Tier}}};
+use crate::{bytecode::bytecode::Bytecode, cell::Cell, error::RuntimeError, vm::{io::{Input, InputSource, OutputSink}, program::Program, tape::Tape, tier::internal::{InterpreterResult, Tier}}};
 
-pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program: &mut Program<I, O>) -> Result<InterpreterResult, RuntimeError> {
-    let mut mul_val: u8 = 0;
+pub fn run_deopt<I: InputSource, O: OutputSink, C: Cell>(tape: &mut Tape<C>, program: &mut Program<I, O, C>) -> Result<InterpreterResult, RuntimeError> {
+    let mut mul_val: C = C::ZERO;
     
     loop {
         if cfg!(feature = "debug") {
             let pc = program.pc();
             program.ocm.deopt[pc] += 1;
+        }
 
-            program.check_timeout()?;
+        // MulStart / MoveStart に続く bytecode は mul_val を引き継ぐので、その間では止まらず記録もしない
+        if program.debug().forces_deopt() && !matches!(program.inst(), Bytecode::Mul { .. } | Bytecode::MoveAdd { .. } | Bytecode::MoveSub { .. }) {
+            let pc = program.pc();
+            // 途中で止まったスキャンは、終わってから 1 つの bytecode として記録し、watch も調べる
+            let in_scan = program.fuel().in_scan();
+            if !in_scan {
+                program.debug_mut().record(tape, pc);
+                if let Some(hit) = program.check_watches(tape) {
+                    return Ok(hit);
+                }
+            }
+            if let Some(stop) = program.debug_mut().check(pc) {
+                return Ok(stop);
+            }
+            if !in_scan {
+                program.debug_mut().watches_mut().before(tape, pc);
+            }
         }
 
         if cfg!(feature = "trace") {
@@ -18,7 +35,11 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
         match program.inst() {
             Bytecode::Breakpoint { delta } => {
                 tape.step(*delta as isize);
-                eprintln!("PC: {}, PTR: {}", program.pc(), tape.data_pointer);
+                // 同じ pc にデバッガのブレークポイントがあれば、実行する前にもう止まっている
+                if !program.debug().breakpoints().contains(&program.pc()) {
+                    program.step();
+                    return Ok(InterpreterResult::Breakpoint);
+                }
             }
 
             Bytecode::SingleAdd { delta, val } => {
@@ -55,51 +76,36 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             }
 
             Bytecode::BothRangeCheck { range } => {
-                if range.contains(&(tape.data_pointer as u16)) {
+                if tape.can_opt(range) {
                     program.step();
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
             }
             Bytecode::Shift { delta, step } => {
-                tape.step(*delta as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
+                tape.shift(*delta as isize, *step as isize, program.fuel())?;
             }
             Bytecode::ShiftP { delta, step, range } => {
-                tape.step(*delta as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     program.step();
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
             }
             Bytecode::ShiftN { delta, step, range } => {
-                tape.step(*delta as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     program.step();
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
             }
             Bytecode::ShiftAdd { delta1, step, delta2, val } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
                 tape.step(*delta2 as isize);
                 tape.add(*val)?;
             }
             Bytecode::ShiftAddP { delta1, step, delta2, val, range } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     tape.step(*delta2 as isize);
                     tape.add(*val)?;
                     program.step();
@@ -109,11 +115,8 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
                 tape.add(*val)?;
             }
             Bytecode::ShiftAddN { delta1, step, delta2, val, range } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     tape.step(*delta2 as isize);
                     tape.add(*val)?;
                     program.step();
@@ -123,19 +126,13 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
                 tape.add(*val)?;
             }
             Bytecode::ShiftSet { delta1, step, delta2, val } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
                 tape.step(*delta2 as isize);
                 tape.set(*val)?;
             }
             Bytecode::ShiftSetP { delta1, step, delta2, val, range } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     tape.step(*delta2 as isize);
                     tape.set(*val)?;
                     program.step();
@@ -145,11 +142,8 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
                 tape.set(*val)?;
             }
             Bytecode::ShiftSetN { delta1, step, delta2, val, range } => {
-                tape.step(*delta1 as isize);
-                while tape.get()? != 0 {
-                    tape.step(*step as isize);
-                }
-                if range.contains(&(tape.data_pointer as u16)) {
+                tape.shift(*delta1 as isize, *step as isize, program.fuel())?;
+                if tape.can_opt(range) {
                     tape.step(*delta2 as isize);
                     tape.set(*val)?;
                     program.step();
@@ -162,12 +156,12 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             Bytecode::MulStart { delta, jz_abs } => {
                 tape.step(*delta as isize);
                 let val = tape.get()?;
-                if val == 0 {
+                if val == C::ZERO {
                     program.jump_abs(*jz_abs as usize);
                     continue;
                 } else {
                     mul_val = val;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
             Bytecode::Mul { delta, val } => {
@@ -177,16 +171,16 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             Bytecode::SingleMoveAdd { delta, to } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
-                    tape.set(0)?;
+                if v != C::ZERO {
+                    tape.set(C::ZERO)?;
                     tape.add_with_offset(*to as isize, v)?;
                 }
             }
             Bytecode::SingleMoveSub { delta, to } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
-                    tape.set(0)?;
+                if v != C::ZERO {
+                    tape.set(C::ZERO)?;
                     tape.sub_with_offset(*to as isize, v)?;
                 }
             }
@@ -194,49 +188,49 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             Bytecode::DoubleMoveAddAdd { delta, to1, to2 } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
+                if v != C::ZERO {
                     tape.add_with_offset(*to1 as isize, v)?;
                     tape.add_with_offset(*to2 as isize, v)?;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
             Bytecode::DoubleMoveAddSub { delta, to1, to2 } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
+                if v != C::ZERO {
                     tape.add_with_offset(*to1 as isize, v)?;
                     tape.sub_with_offset(*to2 as isize, v)?;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
             Bytecode::DoubleMoveSubAdd { delta, to1, to2 } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
+                if v != C::ZERO {
                     tape.sub_with_offset(*to1 as isize, v)?;
                     tape.add_with_offset(*to2 as isize, v)?;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
             Bytecode::DoubleMoveSubSub { delta, to1, to2 } => {
                 tape.step(*delta as isize);
                 let v = tape.get()?;
-                if v != 0 {
+                if v != C::ZERO {
                     tape.sub_with_offset(*to1 as isize, v)?;
                     tape.sub_with_offset(*to2 as isize, v)?;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
 
             Bytecode::MoveStart { delta, jz_abs } => {
                 tape.step(*delta as isize);
                 let val = tape.get()?;
-                if val == 0 {
+                if val == C::ZERO {
                     program.jump_abs(*jz_abs as usize);
                     continue;
                 } else {
                     mul_val = val;
-                    tape.set(0)?;
+                    tape.set(C::ZERO)?;
                 }
             }
             Bytecode::MoveAdd { delta } => {
@@ -247,8 +241,17 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             }
 
             Bytecode::In { delta } => {
-                tape.step(*delta as isize);
-                tape.set(program.input())?;
+                // 入力待ちならポインタも動かさずに戻り、次の step でこの命令をやり直す
+                let delta = *delta as isize;
+                let input = program.input()?;
+                if input == Input::Pending {
+                    return Ok(InterpreterResult::NeedInput);
+                }
+                tape.step(delta);
+                match input {
+                    Input::Byte(value) => tape.set(value)?,
+                    _ => { tape.get()?; }
+                }
                 if program.io_break() {
                     program.step();
                     return Ok(InterpreterResult::IoBreak);
@@ -256,7 +259,11 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
             }
             Bytecode::Out { delta } => {
                 tape.step(*delta as isize);
-                program.output(tape.get()?);
+                let value = tape.get()?;
+                if program.output(value)? {
+                    program.step();
+                    return Ok(InterpreterResult::Output(value.to_u8()));
+                }
                 if program.io_break() {
                     program.step();
                     return Ok(InterpreterResult::IoBreak);
@@ -265,60 +272,74 @@ pub fn run_deopt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut Tape, program:
 
             Bytecode::JmpIfZero { delta, addr_abs } => {
                 tape.step(*delta as isize);
-                if tape.get()? == 0 {
+                if tape.get()? == C::ZERO {
                     program.jump_abs(*addr_abs as usize);
                     continue;
                 }
             }
             Bytecode::JmpIfNotZero { delta, addr_abs } => {
                 tape.step(*delta as isize);
-                if tape.get()? != 0 {
+                if tape.get()? != C::ZERO {
+                    let cost = program.pc() + 1 - *addr_abs as usize;
                     program.jump_abs((*addr_abs) as usize);
+                    program.charge(cost)?;
                     continue;
                 }
             }
             Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step(*delta as isize);
-                if range.contains(&(tape.data_pointer as u16)) {
-                    if tape.get()? != 0 {
-                        program.jump_back(*addr_back as usize);
+                if tape.can_opt(range) {
+                    if tape.get()? != C::ZERO {
+                        let back = *addr_back as usize;
+                        program.jump_back(back);
+                        program.charge(back + 1)?;
                     } else {
                         program.step();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
-                if tape.get()? != 0 {
-                    program.jump_back(*addr_back as usize);
+                if tape.get()? != C::ZERO {
+                    let back = *addr_back as usize;
+                    program.jump_back(back);
+                    program.charge(back + 1)?;
                     continue;
                 }
             }
             Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step(*delta as isize);
-                if range.contains(&(tape.data_pointer as u16)) {
-                    if tape.get()? != 0 {
-                        program.jump_back(*addr_back as usize);
+                if tape.can_opt(range) {
+                    if tape.get()? != C::ZERO {
+                        let back = *addr_back as usize;
+                        program.jump_back(back);
+                        program.charge(back + 1)?;
                     } else {
                         program.step();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
-                if tape.get()? != 0 {
-                    program.jump_back(*addr_back as usize);
+                if tape.get()? != C::ZERO {
+                    let back = *addr_back as usize;
+                    program.jump_back(back);
+                    program.charge(back + 1)?;
                     continue;
                 }
             }
             Bytecode::BothRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step(*delta as isize);
-                if range.contains(&(tape.data_pointer as u16)) {
-                    if tape.get()? != 0 {
-                        program.jump_back(*addr_back as usize);
+                if tape.can_opt(range) {
+                    if tape.get()? != C::ZERO {
+                        let back = *addr_back as usize;
+                        program.jump_back(back);
+                        program.charge(back + 1)?;
                     } else {
                         program.step();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Opt));
                 }
-                if tape.get()? != 0 {
-                    program.jump_back(*addr_back as usize);
+                if tape.get()? != C::ZERO {
+                    let back = *addr_back as usize;
+                    program.jump_back(back);
+                    program.charge(back + 1)?;
                     continue;
                 }
             }
@@ -2,10 +2,16 @@
 pub enum Tier {
     Deopt,
     Opt,
+    Jit,
 }
 
 pub enum InterpreterResult {
     End,
     IoBreak,
+    NeedInput,
+    Output(u8),
     ToggleTier(Tier),
+    Breakpoint,
+    Stepped,
+    Watchpoint { cell: usize, old: u32, new: u32, pc: usize },
 }
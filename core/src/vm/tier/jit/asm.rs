@@ -0,0 +1,239 @@
+// 必要な命令だけを直接エンコードする小さな x86-64 アセンブラ
+// レジスタの割り当て:
+//   rbx: Context へのポインタ
+//   r12: データポインタ (絶対アドレス)
+//   r13: テープの先頭
+//   r14: レンジチェックが失敗したときに戻すデータポインタ
+//   r15: Mul/Move の掛ける値
+//   rax, rcx: 作業用 (rcx は cmp_offset の中だけで使う)
+
+/// A general purpose register number, as encoded in ModRM.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Reg(u8);
+pub const RAX: Reg = Reg(0);
+pub const R12: Reg = Reg(12);
+pub const R14: Reg = Reg(14);
+pub const R15: Reg = Reg(15);
+
+#[derive(Clone, Copy)]
+pub enum Cond {
+    B = 0x2,
+    AE = 0x3,
+    E = 0x4,
+    NE = 0x5,
+    L = 0xC,
+    GE = 0xD,
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::E => Cond::NE,
+            Cond::NE => Cond::E,
+            Cond::AE => Cond::B,
+            Cond::B => Cond::AE,
+            Cond::L => Cond::GE,
+            Cond::GE => Cond::L,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Label(usize);
+
+/// Offsets of the `Context` fields that the generated code touches.
+pub const CTX_POINTER: u8 = 8;
+pub const CTX_LEN_BYTES: u8 = 16;
+pub const CTX_EXIT_PC: u8 = 24;
+pub const CTX_EXIT_TIER: u8 = 32;
+pub const CTX_INTERRUPT: u8 = 40;
+
+pub struct Assembler {
+    pub code: Vec<u8>,
+    /// Width of a cell in bytes: 1, 2 or 4.
+    width: u8,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label)>,
+}
+impl Assembler {
+    pub fn new(width: u8) -> Assembler {
+        Assembler { code: vec![], width, labels: vec![], fixups: vec![] }
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+    /// Patches every rel32 operand. Returns `None` if a label was used but never bound.
+    pub fn finish(mut self) -> Option<Vec<u8>> {
+        for (at, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0]?;
+            let rel = i32::try_from(target as isize - (at + 4) as isize).ok()?;
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        Some(self.code)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.emit(&[0; 4]);
+    }
+    fn imm_cell(&mut self, value: u32) {
+        match self.width {
+            1 => self.emit(&[value as u8]),
+            2 => self.emit(&(value as u16).to_le_bytes()),
+            _ => self.emit(&value.to_le_bytes()),
+        }
+    }
+    /// Operand-size prefix and REX for an instruction whose r/m operand is `[r12 + disp]` and whose reg field is `reg`.
+    fn cell_prefix(&mut self, reg: u8) {
+        if self.width == 2 {
+            self.emit(&[0x66]);
+        }
+        self.emit(&[0x41 | if reg >= 8 { 0x04 } else { 0 }]);
+    }
+    /// ModRM + SIB + disp32 for `[r12 + disp]`.
+    fn mem_r12(&mut self, reg: u8, disp: i32) {
+        self.emit(&[0x80 | ((reg & 7) << 3) | 0b100, 0x24]);
+        self.emit(&disp.to_le_bytes());
+    }
+
+    pub fn prologue(&mut self) {
+        self.emit(&[
+            0x53,             // push rbx
+            0x41, 0x54,       // push r12
+            0x41, 0x55,       // push r13
+            0x41, 0x56,       // push r14
+            0x41, 0x57,       // push r15
+            0x48, 0x89, 0xFB, // mov rbx, rdi
+            0x4C, 0x8B, 0x2B, // mov r13, [rbx]
+            0x4C, 0x8B, 0x63, CTX_POINTER, // mov r12, [rbx + pointer]
+            0x45, 0x31, 0xFF, // xor r15d, r15d
+        ]);
+    }
+    pub fn epilogue(&mut self) {
+        self.emit(&[
+            0x41, 0x5F, // pop r15
+            0x41, 0x5E, // pop r14
+            0x41, 0x5D, // pop r13
+            0x41, 0x5C, // pop r12
+            0x5B,       // pop rbx
+            0xC3,       // ret
+        ]);
+    }
+
+    /// `add r12, cells * width`
+    pub fn step(&mut self, cells: i32) {
+        if cells != 0 {
+            self.emit(&[0x49, 0x81, 0xC4]);
+            self.emit(&(cells * self.width as i32).to_le_bytes());
+        }
+    }
+    /// `mov r14, r12`
+    pub fn save_pointer(&mut self) {
+        self.emit(&[0x4D, 0x89, 0xE6]);
+    }
+    /// `mov [rbx + offset], reg` for r12 or r14.
+    pub fn store_ctx(&mut self, offset: u8, reg: Reg) {
+        self.emit(&[0x4C, 0x89, 0x43 | ((reg.0 & 7) << 3), offset]);
+    }
+    /// `mov qword [rbx + offset], imm32`
+    pub fn store_ctx_imm(&mut self, offset: u8, value: i32) {
+        self.emit(&[0x48, 0xC7, 0x43, offset]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// `add cell [r12 + cells * width], imm`
+    pub fn add_imm(&mut self, cells: i32, value: u32) {
+        self.cell_prefix(0);
+        self.emit(&[if self.width == 1 { 0x80 } else { 0x81 }]);
+        self.mem_r12(0, cells * self.width as i32);
+        self.imm_cell(value);
+    }
+    /// `mov cell [r12 + cells * width], imm`
+    pub fn set_imm(&mut self, cells: i32, value: u32) {
+        self.cell_prefix(0);
+        self.emit(&[if self.width == 1 { 0xC6 } else { 0xC7 }]);
+        self.mem_r12(0, cells * self.width as i32);
+        self.imm_cell(value);
+    }
+    /// `cmp cell [r12 + cells * width], 0`
+    pub fn cmp_zero(&mut self, cells: i32) {
+        self.cell_prefix(0);
+        self.emit(&[if self.width == 1 { 0x80 } else { 0x83 }]);
+        self.mem_r12(7, cells * self.width as i32);
+        self.emit(&[0]);
+    }
+    /// Zero-extending load of `[r12 + cells * width]` into `reg`.
+    pub fn load(&mut self, reg: Reg, cells: i32) {
+        // movzx は 0x66 を付けずに幅をオペコードで選ぶ
+        self.emit(&[0x41 | if reg.0 >= 8 { 0x04 } else { 0 }]);
+        match self.width {
+            1 => self.emit(&[0x0F, 0xB6]),
+            2 => self.emit(&[0x0F, 0xB7]),
+            _ => self.emit(&[0x8B]),
+        }
+        self.mem_r12(reg.0, cells * self.width as i32);
+    }
+    /// `add cell [r12 + cells * width], reg` (or `sub` when `negate`). `reg` must be rax or rcx.
+    pub fn add_reg(&mut self, cells: i32, reg: Reg, negate: bool) {
+        self.cell_prefix(reg.0);
+        let op = if negate { 0x28 } else { 0x00 };
+        self.emit(&[if self.width == 1 { op } else { op | 1 }]);
+        self.mem_r12(reg.0, cells * self.width as i32);
+    }
+    /// `test r15d, r15d`
+    pub fn test_factor(&mut self) {
+        self.emit(&[0x45, 0x85, 0xFF]);
+    }
+    /// `imul eax, r15d, imm32`
+    pub fn mul_factor(&mut self, value: u32) {
+        self.emit(&[0x41, 0x69, 0xC7]);
+        self.emit(&value.to_le_bytes());
+    }
+    /// `mov eax, r15d`
+    pub fn load_factor(&mut self) {
+        self.emit(&[0x44, 0x89, 0xF8]);
+    }
+
+    /// `rax = r12 - r13`, the byte offset of the data pointer.
+    pub fn pointer_offset(&mut self) {
+        self.emit(&[
+            0x4C, 0x89, 0xE0, // mov rax, r12
+            0x4C, 0x29, 0xE8, // sub rax, r13
+        ]);
+    }
+    /// `cmp rax, cells * width`
+    pub fn cmp_offset(&mut self, cells: u32) {
+        self.emit(&[0x48, 0xB9]); // mov rcx, imm64
+        self.emit(&(cells as u64 * self.width as u64).to_le_bytes());
+        self.emit(&[0x48, 0x39, 0xC8]); // cmp rax, rcx
+    }
+    /// `cmp rax, [rbx + len_bytes]`
+    pub fn cmp_tape_length(&mut self) {
+        self.emit(&[0x48, 0x3B, 0x43, CTX_LEN_BYTES]);
+    }
+
+    /// `cmp byte [rbx + interrupt], 0`, leaving NE set when the interrupt flag is raised. Clobbers rax.
+    pub fn poll_interrupt(&mut self) {
+        self.emit(&[
+            0x48, 0x8B, 0x43, CTX_INTERRUPT, // mov rax, [rbx + interrupt]
+            0x80, 0x38, 0x00,                // cmp byte [rax], 0
+        ]);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.emit(&[0x0F, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+    pub fn jmp(&mut self, label: Label) {
+        self.emit(&[0xE9]);
+        self.rel32(label);
+    }
+}
@@ -0,0 +1,312 @@
+use std::mem::{size_of, transmute};
+
+use crate::{bytecode::bytecode::Bytecode, cell::Cell, vm::tier::jit::{asm::{Assembler, Cond, Label, Reg, CTX_EXIT_PC, CTX_EXIT_TIER, CTX_POINTER, RAX, R12, R14, R15}, memory::ExecutableMemory}};
+
+pub const EXIT_DEOPT: usize = 0;
+pub const EXIT_OPT: usize = 1;
+
+/// Shared between Rust and the generated code. The field offsets are hard-coded in `asm`.
+#[repr(C)]
+pub struct Context {
+    pub buffer: *mut u8,
+    pub pointer: *mut u8,
+    pub len_bytes: usize,
+    pub exit_pc: usize,
+    pub exit_tier: usize,
+    /// `InterruptHandle` の AtomicBool。後方ジャンプのたびに読む
+    pub interrupt: *const u8,
+}
+
+/// A loop body compiled to native code. It is entered at the body's first bytecode and returns
+/// through `Context` with the pc and tier to resume in.
+pub struct Region {
+    memory: ExecutableMemory,
+}
+impl Region {
+    /// # Safety
+    /// `ctx` must describe a live Fixed tape whose pointer satisfies the opt tier's range invariant at the region's entry.
+    pub unsafe fn call(&self, ctx: &mut Context) {
+        // SAFETY: compile が生成した sysv64 の関数
+        let f = unsafe { transmute::<*const u8, extern "sysv64" fn(*mut Context)>(self.memory.as_ptr()) };
+        f(ctx);
+    }
+}
+
+struct Compiler {
+    asm: Assembler,
+    start: usize,
+    labels: Vec<Label>,
+    epilogue: Label,
+    /// Exits emitted after the main body: label, tier, pc, register holding the pointer to resume with.
+    stubs: Vec<(Label, usize, usize, Reg)>,
+}
+impl Compiler {
+    fn label_of(&self, pc: usize) -> Option<Label> {
+        pc.checked_sub(self.start).and_then(|i| self.labels.get(i)).copied()
+    }
+
+    fn exit(&mut self, tier: usize, pc: usize, pointer: Reg) {
+        self.asm.store_ctx(CTX_POINTER, pointer);
+        self.asm.store_ctx_imm(CTX_EXIT_PC, pc as i32);
+        self.asm.store_ctx_imm(CTX_EXIT_TIER, tier as i32);
+        self.asm.jmp(self.epilogue);
+    }
+    fn stub(&mut self, tier: usize, pc: usize, pointer: Reg) -> Label {
+        let label = self.asm.new_label();
+        self.stubs.push((label, tier, pc, pointer));
+        label
+    }
+
+    /// Jumps to `target` if `cond` holds, leaving the region for the opt tier if `target` is outside it.
+    /// Backward jumps first check the interrupt flag and leave for the opt tier at `target` if it is set,
+    /// where the next back-edge reports it.
+    fn jump_if(&mut self, cond: Cond, pc: usize, target: usize) {
+        let label = match self.label_of(target) {
+            Some(label) => label,
+            None => self.stub(EXIT_OPT, target, R12),
+        };
+        if target > pc {
+            self.asm.jcc(cond, label);
+            return;
+        }
+        let skip = self.asm.new_label();
+        let interrupted = self.stub(EXIT_OPT, target, R12);
+        self.asm.jcc(cond.negate(), skip);
+        self.asm.poll_interrupt();
+        self.asm.jcc(Cond::NE, interrupted);
+        self.asm.jmp(label);
+        self.asm.bind(skip);
+    }
+
+    /// Range check on the data pointer. On failure, exits to the deopt tier so that it re-executes `pc` from the pointer in r14.
+    fn guard(&mut self, pc: usize, start: Option<u32>, end: Option<u32>) {
+        let fail = self.stub(EXIT_DEOPT, pc, R14);
+        self.asm.pointer_offset();
+        if let Some(start) = start {
+            self.asm.cmp_offset(start);
+            self.asm.jcc(Cond::L, fail);
+        }
+        if let Some(end) = end {
+            self.asm.cmp_offset(end);
+            self.asm.jcc(Cond::GE, fail);
+        }
+    }
+
+    /// Moves by `step` until a zero cell. Leaving the tape exits to the deopt tier, which reports the error.
+    fn scan(&mut self, pc: usize, step: i32) {
+        let fail = self.stub(EXIT_DEOPT, pc, R14);
+        let head = self.asm.new_label();
+        let done = self.asm.new_label();
+        self.asm.bind(head);
+        self.asm.pointer_offset();
+        self.asm.cmp_tape_length();
+        self.asm.jcc(Cond::AE, fail);
+        self.asm.cmp_zero(0);
+        self.asm.jcc(Cond::E, done);
+        self.asm.step(step);
+        self.asm.jmp(head);
+        self.asm.bind(done);
+    }
+
+    fn compile_one<C: Cell>(&mut self, pc: usize, inst: &Bytecode<C>) {
+        let asm = &mut self.asm;
+        match inst {
+            // I/O などはインタプリタに任せる。ポインタは命令の前のまま返す
+            Bytecode::Breakpoint { .. } | Bytecode::In { .. } | Bytecode::Out { .. } | Bytecode::End { .. } => {
+                self.exit(EXIT_OPT, pc, R12);
+            }
+
+            Bytecode::SingleAdd { delta, val } => {
+                asm.step(*delta as i32);
+                asm.add_imm(0, val.to_u32());
+            }
+            Bytecode::SingleSet { delta, val } => {
+                asm.step(*delta as i32);
+                asm.set_imm(0, val.to_u32());
+            }
+            Bytecode::AddAdd { delta1, val1, delta2, val2 } => {
+                asm.step(*delta1 as i32);
+                asm.add_imm(0, val1.to_u32());
+                asm.step(*delta2 as i32);
+                asm.add_imm(0, val2.to_u32());
+            }
+            Bytecode::AddSet { delta1, val1, delta2, val2 } => {
+                asm.step(*delta1 as i32);
+                asm.add_imm(0, val1.to_u32());
+                asm.step(*delta2 as i32);
+                asm.set_imm(0, val2.to_u32());
+            }
+            Bytecode::SetAdd { delta1, val1, delta2, val2 } => {
+                asm.step(*delta1 as i32);
+                asm.set_imm(0, val1.to_u32());
+                asm.step(*delta2 as i32);
+                asm.add_imm(0, val2.to_u32());
+            }
+            Bytecode::SetSet { delta1, val1, delta2, val2 } => {
+                asm.step(*delta1 as i32);
+                asm.set_imm(0, val1.to_u32());
+                asm.step(*delta2 as i32);
+                asm.set_imm(0, val2.to_u32());
+            }
+
+            Bytecode::BothRangeCheck { range } => {
+                asm.save_pointer();
+                self.guard(pc, Some(range.start), Some(range.end));
+            }
+            Bytecode::Shift { delta, step } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.scan(pc, *step as i32);
+            }
+            Bytecode::ShiftP { delta, step, range } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.scan(pc, *step as i32);
+                self.guard(pc, None, Some(range.end));
+            }
+            Bytecode::ShiftN { delta, step, range } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.scan(pc, *step as i32);
+                self.guard(pc, Some(range.start), None);
+            }
+            Bytecode::ShiftAdd { delta1, step, delta2, val }
+            | Bytecode::ShiftSet { delta1, step, delta2, val }
+            | Bytecode::ShiftAddP { delta1, step, delta2, val, .. }
+            | Bytecode::ShiftSetP { delta1, step, delta2, val, .. }
+            | Bytecode::ShiftAddN { delta1, step, delta2, val, .. }
+            | Bytecode::ShiftSetN { delta1, step, delta2, val, .. } => {
+                asm.save_pointer();
+                asm.step(*delta1 as i32);
+                self.scan(pc, *step as i32);
+                match inst {
+                    Bytecode::ShiftAddP { range, .. } | Bytecode::ShiftSetP { range, .. } => self.guard(pc, None, Some(range.end)),
+                    Bytecode::ShiftAddN { range, .. } | Bytecode::ShiftSetN { range, .. } => self.guard(pc, Some(range.start), None),
+                    _ => {}
+                }
+                self.asm.step(*delta2 as i32);
+                match inst {
+                    Bytecode::ShiftAdd { .. } | Bytecode::ShiftAddP { .. } | Bytecode::ShiftAddN { .. } => self.asm.add_imm(0, val.to_u32()),
+                    _ => self.asm.set_imm(0, val.to_u32()),
+                }
+            }
+
+            Bytecode::MulStart { delta, jz_abs } | Bytecode::MoveStart { delta, jz_abs } => {
+                asm.step(*delta as i32);
+                asm.load(R15, 0);
+                asm.test_factor();
+                self.jump_if(Cond::E, pc, *jz_abs as usize);
+                self.asm.set_imm(0, 0);
+            }
+            Bytecode::Mul { delta, val } => {
+                asm.mul_factor(val.to_u32());
+                asm.add_reg(*delta as i32, RAX, false);
+            }
+            Bytecode::MoveAdd { delta } => {
+                asm.load_factor();
+                asm.add_reg(*delta as i32, RAX, false);
+            }
+            Bytecode::MoveSub { delta } => {
+                asm.load_factor();
+                asm.add_reg(*delta as i32, RAX, true);
+            }
+
+            Bytecode::SingleMoveAdd { delta, to } => {
+                asm.step(*delta as i32);
+                asm.load(RAX, 0);
+                asm.add_reg(*to as i32, RAX, false);
+                asm.set_imm(0, 0);
+            }
+            Bytecode::SingleMoveSub { delta, to } => {
+                asm.step(*delta as i32);
+                asm.load(RAX, 0);
+                asm.add_reg(*to as i32, RAX, true);
+                asm.set_imm(0, 0);
+            }
+            Bytecode::DoubleMoveAddAdd { delta, to1, to2 }
+            | Bytecode::DoubleMoveAddSub { delta, to1, to2 }
+            | Bytecode::DoubleMoveSubAdd { delta, to1, to2 }
+            | Bytecode::DoubleMoveSubSub { delta, to1, to2 } => {
+                let (sub1, sub2) = match inst {
+                    Bytecode::DoubleMoveAddAdd { .. } => (false, false),
+                    Bytecode::DoubleMoveAddSub { .. } => (false, true),
+                    Bytecode::DoubleMoveSubAdd { .. } => (true, false),
+                    _ => (true, true),
+                };
+                asm.step(*delta as i32);
+                asm.load(RAX, 0);
+                asm.add_reg(*to1 as i32, RAX, sub1);
+                asm.add_reg(*to2 as i32, RAX, sub2);
+                asm.set_imm(0, 0);
+            }
+
+            Bytecode::JmpIfZero { delta, addr_abs } => {
+                asm.step(*delta as i32);
+                asm.cmp_zero(0);
+                self.jump_if(Cond::E, pc, *addr_abs as usize);
+            }
+            Bytecode::JmpIfNotZero { delta, addr_abs } => {
+                asm.step(*delta as i32);
+                asm.cmp_zero(0);
+                self.jump_if(Cond::NE, pc, *addr_abs as usize);
+            }
+            Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.guard(pc, None, Some(range.end));
+                self.asm.cmp_zero(0);
+                self.jump_if(Cond::NE, pc, pc - *addr_back as usize);
+            }
+            Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.guard(pc, Some(range.start), None);
+                self.asm.cmp_zero(0);
+                self.jump_if(Cond::NE, pc, pc - *addr_back as usize);
+            }
+            Bytecode::BothRangeCheckJNZ { delta, addr_back, range } => {
+                asm.save_pointer();
+                asm.step(*delta as i32);
+                self.guard(pc, Some(range.start), Some(range.end));
+                self.asm.cmp_zero(0);
+                self.jump_if(Cond::NE, pc, pc - *addr_back as usize);
+            }
+        }
+    }
+}
+
+/// Compiles the loop body entered at `start`, up to and including its back-edge.
+/// `start - 1` has to be the loop's `JmpIfZero`, which tells where the loop ends.
+pub fn compile<C: Cell>(insts: &[Bytecode<C>], start: usize) -> Option<Region> {
+    let Some(Bytecode::JmpIfZero { addr_abs, .. }) = start.checked_sub(1).and_then(|i| insts.get(i)) else {
+        return None;
+    };
+    let end = (*addr_abs as usize).checked_sub(1)?;
+    // 出口の pc は imm32 で書き込むので、それに収まらないプログラムはあきらめる
+    if end < start || end >= insts.len() || end + 1 > i32::MAX as usize {
+        return None;
+    }
+
+    let mut asm = Assembler::new(size_of::<C>() as u8);
+    let labels = (start..=end).map(|_| asm.new_label()).collect();
+    let epilogue = asm.new_label();
+    let mut compiler = Compiler { asm, start, labels, epilogue, stubs: vec![] };
+
+    compiler.asm.prologue();
+    for (i, inst) in insts[start..=end].iter().enumerate() {
+        compiler.asm.bind(compiler.labels[i]);
+        compiler.compile_one(start + i, inst);
+    }
+    // ループを抜けたら opt に戻る
+    compiler.exit(EXIT_OPT, end + 1, R12);
+
+    for (label, tier, pc, pointer) in std::mem::take(&mut compiler.stubs) {
+        compiler.asm.bind(label);
+        compiler.exit(tier, pc, pointer);
+    }
+    compiler.asm.bind(epilogue);
+    compiler.asm.epilogue();
+
+    let code = compiler.asm.finish()?;
+    Some(Region { memory: ExecutableMemory::new(&code)? })
+}
@@ -0,0 +1,37 @@
+use std::ptr::{copy_nonoverlapping, null_mut};
+
+/// An mmap'd buffer holding machine code. It is written while RW and then flipped to RX, never both at once.
+pub struct ExecutableMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+impl ExecutableMemory {
+    pub fn new(code: &[u8]) -> Option<ExecutableMemory> {
+        let len = code.len().max(1);
+        // SAFETY: 新しい無名マッピングを作って、そこにだけ書き込む
+        unsafe {
+            let ptr = libc::mmap(null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            let memory = ExecutableMemory { ptr: ptr.cast(), len };
+            copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(memory)
+        }
+    }
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+}
+// SAFETY: 書き込みは new の中だけで、その後は読み取り専用
+unsafe impl Send for ExecutableMemory {}
+unsafe impl Sync for ExecutableMemory {}
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        // SAFETY: new で作ったマッピングをそのまま返す
+        unsafe { libc::munmap(self.ptr.cast(), self.len); }
+    }
+}
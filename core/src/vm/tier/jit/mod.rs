@@ -0,0 +1,192 @@
+use crate::{cell::Cell, error::RuntimeError, vm::{io::{InputSource, OutputSink}, program::Program, tape::{Tape, TapeMode}, tier::internal::{InterpreterResult, Tier}}};
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use std::collections::HashMap;
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod asm;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod compile;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod memory;
+
+/// ループの後方ジャンプがこの回数を超えたら、そのループ本体をネイティブコードにする
+const HOT_THRESHOLD: u32 = 1000;
+
+/// Whether this build can run the JIT tier. The `debug` feature counts every executed bytecode,
+/// which native code does not do, so it keeps the JIT off.
+pub const JIT_AVAILABLE: bool = cfg!(all(feature = "jit", target_arch = "x86_64", unix)) && !cfg!(feature = "debug");
+
+/// Per-program JIT state: back-edge counters and the compiled loop regions, keyed by the pc of the loop body's first bytecode.
+pub struct JitCache {
+    hits: Box<[u32]>,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    regions: HashMap<usize, Option<compile::Region>>,
+}
+impl JitCache {
+    pub fn new(insts_len: usize) -> JitCache {
+        JitCache {
+            hits: vec![0; if JIT_AVAILABLE { insts_len } else { 0 }].into_boxed_slice(),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            regions: HashMap::new(),
+        }
+    }
+
+    /// Counts a taken back-edge into `target` and reports whether the loop should now run in the JIT tier.
+    /// Only Fixed tapes are supported, since native code keeps raw pointers into a buffer that never moves or wraps.
+    pub fn hit(&mut self, target: usize, mode: TapeMode) -> bool {
+        if !JIT_AVAILABLE || mode != TapeMode::Fixed {
+            return false;
+        }
+        let Some(count) = self.hits.get_mut(target) else {
+            return false;
+        };
+        *count = count.saturating_add(1);
+        if *count < HOT_THRESHOLD {
+            return false;
+        }
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        {
+            // コンパイルに失敗したループは二度と試さない
+            !matches!(self.regions.get(&target), Some(None))
+        }
+        #[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+        {
+            false
+        }
+    }
+}
+
+/// Runs the compiled region starting at the current pc, compiling it first if needed.
+/// Native code exits before any bytecode it cannot handle (I/O, `End`, a failed range check, a scan leaving the tape),
+/// with the pointer as it was before that bytecode, so the interpreter tiers simply re-execute it.
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+pub fn run_jit<I: InputSource, O: OutputSink, C: Cell>(tape: &mut Tape<C>, program: &mut Program<I, O, C>) -> Result<InterpreterResult, RuntimeError> {
+    let start = program.pc();
+    let interrupt = program.fuel().interrupt_flag();
+    let (insts, cache) = program.jit_parts();
+    let Some(region) = cache.regions.entry(start).or_insert_with(|| compile::compile(insts, start)) else {
+        return Ok(InterpreterResult::ToggleTier(Tier::Opt));
+    };
+
+    let buffer = tape.buffer.as_mut_ptr();
+    let mut ctx = compile::Context {
+        buffer: buffer.cast(),
+        pointer: buffer.wrapping_add(tape.data_pointer).cast(),
+        len_bytes: size_of_val(&*tape.buffer),
+        exit_pc: 0,
+        exit_tier: 0,
+        interrupt: interrupt.cast(),
+    };
+    // SAFETY: テープは Fixed なので、実行中にバッファが動くことはない
+    unsafe { region.call(&mut ctx) };
+
+    tape.data_pointer = (ctx.pointer.addr().wrapping_sub(ctx.buffer.addr()) as isize / size_of::<C>() as isize) as usize;
+    program.jump_abs(ctx.exit_pc);
+    Ok(InterpreterResult::ToggleTier(if ctx.exit_tier == compile::EXIT_DEOPT { Tier::Deopt } else { Tier::Opt }))
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+pub fn run_jit<I: InputSource, O: OutputSink, C: Cell>(_tape: &mut Tape<C>, _program: &mut Program<I, O, C>) -> Result<InterpreterResult, RuntimeError> {
+    Ok(InterpreterResult::ToggleTier(Tier::Opt))
+}
+
+#[cfg(all(test, feature = "jit", target_arch = "x86_64", unix, not(feature = "debug")))]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{cell::Cell, compiled::CompiledProgram, error::BrainrotError, vm::{program::{EofPolicy, Program}, tape::{Tape, TapeMode}, tier::{BrainrotResult, deopt::run_deopt, internal::{InterpreterResult, Tier}, run}}};
+
+    /// What a run left behind. `error` is the pc, pointer and message of a runtime error.
+    #[derive(Debug, PartialEq)]
+    struct Outcome<C> {
+        tape: Vec<C>,
+        output: Vec<u8>,
+        error: Option<(usize, usize, String)>,
+    }
+
+    /// Runs through every tier, and also returns how many loops the JIT compiled.
+    fn run_tiered<C: Cell>(code: &str, tape_length: usize) -> (Outcome<C>, usize) {
+        let compiled = CompiledProgram::<C>::new(code, tape_length, TapeMode::Fixed).unwrap();
+        let mut output = vec![];
+        let mut tape = Tape::new(tape_length, TapeMode::Fixed);
+        let mut program = Program::new(compiled.verified(), None, || None, |value| output.push(value), false, EofPolicy::Zero);
+        let mut tier = if compiled.verified().opt_first() { Tier::Opt } else { Tier::Deopt };
+        let error = match run(&mut tier, &mut tape, &mut program) {
+            Ok(BrainrotResult::End) => None,
+            Err(BrainrotError::RuntimeError { err, pc, pointer, .. }) => Some((pc, pointer, err.to_string())),
+            _ => panic!("unexpected result"),
+        };
+        let regions = program.jit_parts().1.regions.values().filter(|region| region.is_some()).count();
+        drop(program);
+        (Outcome { tape: tape.buffer.to_vec(), output, error }, regions)
+    }
+
+    fn run_deopt_only<C: Cell>(code: &str, tape_length: usize) -> Outcome<C> {
+        let compiled = CompiledProgram::<C>::new(code, tape_length, TapeMode::Fixed).unwrap();
+        let mut output = vec![];
+        let mut tape = Tape::new(tape_length, TapeMode::Fixed);
+        let mut program = Program::new(compiled.verified(), None, || None, |value| output.push(value), false, EofPolicy::Zero);
+        let error = loop {
+            match run_deopt(&mut tape, &mut program) {
+                Ok(InterpreterResult::End) => break None,
+                // opt に切り替えずに続ける
+                Ok(_) => {}
+                Err(err) => break Some((program.pc(), tape.data_pointer, err.to_string())),
+            }
+        };
+        drop(program);
+        Outcome { tape: tape.buffer.to_vec(), output, error }
+    }
+
+    fn check<C: Cell>(code: &str, tape_length: usize) {
+        let (outcome, regions) = run_tiered::<C>(code, tape_length);
+        assert_ne!(regions, 0, "no loop got hot in {code}");
+        assert_eq!(outcome, run_deopt_only::<C>(code, tape_length), "{code}");
+    }
+    fn check_all_widths(code: &str, tape_length: usize) {
+        check::<u8>(code, tape_length);
+        check::<u16>(code, tape_length);
+        check::<u32>(code, tape_length);
+    }
+
+    #[test]
+    fn mul_body() {
+        check_all_widths("++++++++++++[>++++++++++++[>++++++++++++[>++++++++++++[>+>++>---<<<-]<-]<-]<-]>>>>.>.>.", 16);
+    }
+
+    #[test]
+    fn move_bodies() {
+        check_all_widths("++++++++++++[>++++++++++++[>++++++++++++[>+++[->+>-<<]>[-<+>]>[->+>+>+<<<]<<<-]<-]<-]>>>>.>.>.>.>.", 16);
+    }
+
+    #[test]
+    fn guard_failure_exits_to_deopt() {
+        // テープの端で範囲チェックに失敗し、r14 のポインタから deopt が同じ bytecode をやり直して範囲外になる。
+        // 後方ジャンプがポインタを動かすので、動かす前のポインタで戻らないと結果がずれる
+        check_all_widths("+[>+>+<]", 3000);
+    }
+
+    #[test]
+    fn scan_leaving_the_tape() {
+        check_all_widths("+[[>]+]", 2000);
+    }
+
+    #[test]
+    fn interrupt_at_back_edge() {
+        let compiled = CompiledProgram::<u8>::new("+[>+<]", 16, TapeMode::Fixed).unwrap();
+        let mut tape = Tape::new(16, TapeMode::Fixed);
+        let mut program = Program::new(compiled.verified(), None, || None, |_| {}, false, EofPolicy::Zero);
+        let handle = program.fuel().interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+
+        let mut tier = if compiled.verified().opt_first() { Tier::Opt } else { Tier::Deopt };
+        assert!(matches!(run(&mut tier, &mut tape, &mut program), Ok(BrainrotResult::Interrupted)));
+        assert_ne!(program.jit_parts().1.regions.values().filter(|region| region.is_some()).count(), 0);
+        interrupter.join().unwrap();
+    }
+}
@@ -1,38 +1,138 @@
-use crate::{error::BrainrotError, vm::{program::{Program, UnsafeProgram}, tape::{Tape, UnsafeTape}, tier::{deopt::run_deopt, internal::{InterpreterResult, Tier}, opt::run_opt}}};
+use crate::{cell::Cell, error::{BrainrotError, RuntimeError}, vm::{io::{InputSource, OutputSink}, program::{Program, UnsafeProgram}, tape::{Tape, UnsafeTape}, tier::{deopt::run_deopt, internal::{InterpreterResult, Tier}, jit::run_jit, opt::run_opt}}};
 
 pub mod internal;
 mod deopt;
 mod opt;
+pub mod jit;
 
 pub enum BrainrotResult {
     End, IoBreak,
+    /// The step limit ran out. The pc, tape and tier are left where execution can pick up again
+    /// after topping up with `Brainrot::set_timeout`.
+    OutOfFuel,
+    /// An `InterruptHandle` fired. Like `OutOfFuel`, calling `step` again resumes.
+    Interrupted,
+    /// The input source returned `Input::Pending` at a `,`. Feed it and call `step` again to retry that `,`.
+    NeedInput,
+    /// A `.` wrote this byte to a sink that asked to stop, such as `PullOutput`.
+    Output(u8),
+    /// Stopped before a bytecode with a breakpoint. Calling `step` again runs it.
+    Breakpoint,
+    /// `Brainrot::step_bytecode` ran its bytecode.
+    Stepped,
+    /// The bytecode at `pc` touched a cell watched with `Brainrot::watch`, and took it from `old` to `new`,
+    /// which are the same if it only read it. Calling `step` again carries on after it.
+    Watchpoint { cell: usize, old: u32, new: u32, pc: usize },
 }
 
-pub fn run<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tier: &mut Tier, tape: &mut Tape, program: &mut Program<I, O>) -> Result<BrainrotResult, BrainrotError> {
+/// Runs until something needs the host, then flushes the output sink. A flush failure is only reported
+/// when nothing else went wrong.
+pub fn run<I: InputSource, O: OutputSink, C: Cell>(tier: &mut Tier, tape: &mut Tape<C>, program: &mut Program<I, O, C>) -> Result<BrainrotResult, BrainrotError> {
+    let result = run_tiers(tier, tape, program);
+    match (result, program.flush()) {
+        (Ok(result), Ok(())) => Ok(result),
+        (Ok(_), Err(err)) => Err(BrainrotError::RuntimeError {
+            err,
+            pc: program.pc(),
+            pointer: tape.data_pointer,
+            source_range: program.source_range(program.pc()),
+        }),
+        (Err(err), _) => Err(err),
+    }
+}
+
+fn run_tiers<I: InputSource, O: OutputSink, C: Cell>(tier: &mut Tier, tape: &mut Tape<C>, program: &mut Program<I, O, C>) -> Result<BrainrotResult, BrainrotError> {
     loop {
+        // エラーで報告するのは、巻き戻す前の失敗した時点のポインタ
+        let mut pointer = None;
         let result = match tier {
-            Tier::Deopt => run_deopt(tape, program),
+            Tier::Deopt => {
+                let result = run_deopt(tape, program);
+                match result {
+                    // End は pc を進めないので、記録するとなぞった後にもう一度実行してしまう
+                    Ok(InterpreterResult::End) => {}
+                    Ok(_) => {
+                        let pc = program.pc();
+                        program.debug_mut().record(tape, pc);
+                    }
+                    // 燃料切れと割り込みはそのまま続きを実行できる
+                    Err(RuntimeError::TimeoutError | RuntimeError::Interrupted) => {}
+                    // 失敗した bytecode は途中まで進んでいても、実行しなかったことにする
+                    Err(_) => {
+                        pointer = Some(tape.data_pointer);
+                        program.debug_mut().discard(tape);
+                    }
+                }
+                result
+            }
             Tier::Opt => unsafe {
                 run_opt(&mut UnsafeTape::new(tape), &mut UnsafeProgram::new(program))
             },
+            Tier::Jit => run_jit(tape, program),
         };
         match result {
             Ok(InterpreterResult::End) => {
                 return Ok(BrainrotResult::End);
             }
             Ok(InterpreterResult::IoBreak) => {
+                let pc = program.pc();
+                program.debug_mut().finish_step(pc);
                 return Ok(BrainrotResult::IoBreak)
             }
+            Ok(InterpreterResult::NeedInput) => {
+                let pc = program.pc();
+                program.debug_mut().resume_at(pc);
+                return Ok(BrainrotResult::NeedInput)
+            }
+            Ok(InterpreterResult::Output(value)) => {
+                let pc = program.pc();
+                program.debug_mut().finish_step(pc);
+                return Ok(BrainrotResult::Output(value))
+            }
+            // `#` は実行し終えてから止まる。デバッガのブレークポイントは step を使い切らないので何もしない
+            Ok(InterpreterResult::Breakpoint) => {
+                let pc = program.pc();
+                program.debug_mut().finish_step(pc);
+                return Ok(BrainrotResult::Breakpoint)
+            }
+            Ok(InterpreterResult::Stepped) => {
+                return Ok(BrainrotResult::Stepped)
+            }
+            Ok(InterpreterResult::Watchpoint { cell, old, new, pc }) => {
+                return Ok(BrainrotResult::Watchpoint { cell, old, new, pc })
+            }
             Ok(InterpreterResult::ToggleTier(t)) => {
-                *tier = t;
+                // デバッグ中は deopt から上がらない。deopt はどの pc からでも続きを実行できる
+                if !program.debug().forces_deopt() {
+                    *tier = t;
+                }
+            }
+            Err(RuntimeError::TimeoutError) => {
+                resume_scan(program);
+                return Ok(BrainrotResult::OutOfFuel);
+            }
+            Err(RuntimeError::Interrupted) => {
+                program.fuel().clear_interrupt();
+                resume_scan(program);
+                return Ok(BrainrotResult::Interrupted);
             }
             Err(err) => {
                 return Err(BrainrotError::RuntimeError {
                     err,
                     pc: program.pc(),
-                    pointer: tape.data_pointer,
+                    pointer: pointer.unwrap_or(tape.data_pointer),
+                    source_range: program.source_range(program.pc()),
                 })
             }
         }
     }
 }
+
+/// A scan stopped midway carries on from where it got to, so its breakpoint has already been passed.
+/// Other stops leave the pc on a bytecode that has not started yet.
+fn resume_scan<I: InputSource, O: OutputSink, C: Cell>(program: &mut Program<I, O, C>) {
+    if program.fuel().in_scan() {
+        let pc = program.pc();
+        program.debug_mut().resume_at(pc);
+    }
+}
@@ -1,15 +1,13 @@
-use crate::{bytecode::bytecode::Bytecode, error::RuntimeError, vm::{program::UnsafeProgram, tape::UnsafeTape, tier::internal::{InterpreterResult, Tier}}};
+use crate::{bytecode::bytecode::Bytecode, cell::Cell, error::RuntimeError, vm::{io::{Input, InputSource, OutputSink}, program::UnsafeProgram, tape::UnsafeTape, tier::internal::{InterpreterResult, Tier}}};
 
 #[allow(unsafe_op_in_unsafe_fn)]
-pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTape, program: &mut UnsafeProgram<I, O>) -> Result<InterpreterResult, RuntimeError> {
-    let mut mul_val: u8 = 0;
+pub unsafe fn run_opt<I: InputSource, O: OutputSink, C: Cell>(tape: &mut UnsafeTape<C>, program: &mut UnsafeProgram<I, O, C>) -> Result<InterpreterResult, RuntimeError> {
+    let mut mul_val: C = C::ZERO;
     
     loop {
         if cfg!(feature = "debug") {
             let pc = program.pc();
             program.inner.ocm.opt[pc] += 1;
-
-            program.check_timeout()?;
         }
 
         if cfg!(feature = "trace") {
@@ -19,7 +17,8 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
         match program.inst() {
             Bytecode::Breakpoint { delta } => {
                 tape.step_ptr((*delta) as isize);
-                eprintln!("PC: {}, PTR: {}", program.pc(), tape.get_ptr());
+                program.jump_one();
+                return Ok(InterpreterResult::Breakpoint);
             }
 
             Bytecode::SingleAdd { delta, val } => {
@@ -56,53 +55,43 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
             }
 
             Bytecode::BothRangeCheck { range } => {
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !range.contains(&(tape.get_ptr() as u32)) {
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
             }
             Bytecode::Shift { delta, step } => {
-                tape.step_ptr((*delta) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
+                if !tape.shift((*delta) as isize, (*step) as isize, program.fuel())? {
+                    program.jump_one();
+                    return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
             }
             Bytecode::ShiftP { delta, step, range } => {
-                tape.step_ptr((*delta) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
             }
             Bytecode::ShiftN { delta, step, range } => {
-                tape.step_ptr((*delta) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
             }
             Bytecode::ShiftAdd { delta1, step, delta2, val } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? {
+                    tape.step_ptr((*delta2) as isize);
+                    tape.add_safe(*val)?;
+                    program.jump_one();
+                    return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
                 tape.step_ptr((*delta2) as isize);
                 tape.add(*val);
             }
             Bytecode::ShiftAddP { delta1, step, delta2, val, range } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     tape.step_ptr((*delta2) as isize);
-                    tape.add_safe(tape.get_ptr(), *val)?;
+                    tape.add_safe(*val)?;
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
@@ -110,13 +99,9 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
                 tape.add(*val);
             }
             Bytecode::ShiftAddN { delta1, step, delta2, val, range } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     tape.step_ptr((*delta2) as isize);
-                    tape.add_safe(tape.get_ptr(), *val)?;
+                    tape.add_safe(*val)?;
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
@@ -124,21 +109,19 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
                 tape.add(*val);
             }
             Bytecode::ShiftSet { delta1, step, delta2, val } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? {
+                    tape.step_ptr((*delta2) as isize);
+                    tape.set_safe(*val)?;
+                    program.jump_one();
+                    return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
                 tape.step_ptr((*delta2) as isize);
                 tape.set(*val);
             }
             Bytecode::ShiftSetP { delta1, step, delta2, val, range } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     tape.step_ptr((*delta2) as isize);
-                    tape.set_safe(tape.get_ptr(), *val)?;
+                    tape.set_safe(*val)?;
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
@@ -146,13 +129,9 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
                 tape.set(*val);
             }
             Bytecode::ShiftSetN { delta1, step, delta2, val, range } => {
-                tape.step_ptr((*delta1) as isize);
-                while tape.get_safe(tape.get_ptr())? != 0 {
-                    tape.step_ptr((*step) as isize);
-                }
-                if !range.contains(&(tape.get_ptr() as u16)) {
+                if !tape.shift((*delta1) as isize, (*step) as isize, program.fuel())? || !range.contains(&(tape.get_ptr() as u32)) {
                     tape.step_ptr((*delta2) as isize);
-                    tape.set_safe(tape.get_ptr(), *val)?;
+                    tape.set_safe(*val)?;
                     program.jump_one();
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
@@ -163,12 +142,12 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
             Bytecode::MulStart { delta, jz_abs } => {
                 tape.step_ptr((*delta) as isize);
                 let val = tape.get();
-                if val == 0 {
+                if val == C::ZERO {
                     program.jump_abs(*jz_abs);
                     continue;
                 } else {
                     mul_val = val;
-                    tape.set(0);
+                    tape.set(C::ZERO);
                 }
             }
             Bytecode::Mul { delta, val } => {
@@ -178,12 +157,12 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
             Bytecode::SingleMoveAdd { delta, to } => {
                 tape.step_ptr((*delta) as isize);
                 tape.add_with_offset((*to) as isize, tape.get());
-                tape.set(0);
+                tape.set(C::ZERO);
             }
             Bytecode::SingleMoveSub { delta, to } => {
                 tape.step_ptr((*delta) as isize);
                 tape.sub_with_offset((*to) as isize, tape.get());
-                tape.set(0);
+                tape.set(C::ZERO);
             }
 
             Bytecode::DoubleMoveAddAdd { delta, to1, to2 } => {
@@ -191,39 +170,39 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
                 let v = tape.get();
                 tape.add_with_offset((*to1) as isize, v);
                 tape.add_with_offset((*to2) as isize, v);
-                tape.set(0);
+                tape.set(C::ZERO);
             }
             Bytecode::DoubleMoveAddSub { delta, to1, to2 } => {
                 tape.step_ptr((*delta) as isize);
                 let v = tape.get();
                 tape.add_with_offset((*to1) as isize, v);
                 tape.sub_with_offset((*to2) as isize, v);
-                tape.set(0);
+                tape.set(C::ZERO);
             }
             Bytecode::DoubleMoveSubAdd { delta, to1, to2 } => {
                 tape.step_ptr((*delta) as isize);
                 let v = tape.get();
                 tape.sub_with_offset((*to1) as isize, v);
                 tape.add_with_offset((*to2) as isize, v);
-                tape.set(0);
+                tape.set(C::ZERO);
             }
             Bytecode::DoubleMoveSubSub { delta, to1, to2 } => {
                 tape.step_ptr((*delta) as isize);
                 let v = tape.get();
                 tape.sub_with_offset((*to1) as isize, v);
                 tape.sub_with_offset((*to2) as isize, v);
-                tape.set(0);
+                tape.set(C::ZERO);
             }
 
             Bytecode::MoveStart { delta, jz_abs } => {
                 tape.step_ptr((*delta) as isize);
                 let val = tape.get();
-                if val == 0 {
+                if val == C::ZERO {
                     program.jump_abs(*jz_abs);
                     continue;
                 } else {
                     mul_val = val;
-                    tape.set(0);
+                    tape.set(C::ZERO);
                 }
             }
             Bytecode::MoveAdd { delta } => {
@@ -234,8 +213,15 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
             }
 
             Bytecode::In { delta } => {
-                tape.step_ptr((*delta) as isize);
-                tape.set(program.inner.input());
+                let delta = *delta as isize;
+                let input = program.inner.input()?;
+                if input == Input::Pending {
+                    return Ok(InterpreterResult::NeedInput);
+                }
+                tape.step_ptr(delta);
+                if let Input::Byte(value) = input {
+                    tape.set(value);
+                }
                 if program.inner.io_break() {
                     program.jump_one();
                     return Ok(InterpreterResult::IoBreak);
@@ -243,7 +229,11 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
             }
             Bytecode::Out { delta } => {
                 tape.step_ptr((*delta) as isize);
-                program.inner.output(tape.get());
+                let value = tape.get();
+                if program.inner.output(value)? {
+                    program.jump_one();
+                    return Ok(InterpreterResult::Output(value.to_u8()));
+                }
                 if program.inner.io_break() {
                     program.jump_one();
                     return Ok(InterpreterResult::IoBreak);
@@ -252,61 +242,81 @@ pub unsafe fn run_opt<I: FnMut() -> u8, O: FnMut(u8) -> ()>(tape: &mut UnsafeTap
 
             Bytecode::JmpIfZero { delta, addr_abs } => {
                 tape.step_ptr((*delta) as isize);
-                if tape.get() == 0 {
+                if tape.get() == C::ZERO {
                     program.jump_abs(*addr_abs);
                     continue;
                 }
             }
             Bytecode::JmpIfNotZero { delta, addr_abs } => {
                 tape.step_ptr((*delta) as isize);
-                if tape.get() != 0 {
+                if tape.get() != C::ZERO {
+                    let cost = program.pc() + 1 - *addr_abs as usize;
                     program.jump_abs(*addr_abs);
+                    program.charge(cost)?;
+                    if program.back_edge(tape.inner.mode) {
+                        return Ok(InterpreterResult::ToggleTier(Tier::Jit));
+                    }
                     continue;
                 }
             }
             Bytecode::PositiveRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step_ptr((*delta) as isize);
-                if !range.contains(&(tape.get_ptr() as u16)) {
-                    if tape.get_safe(tape.get_ptr())? != 0 {
+                if !range.contains(&(tape.get_ptr() as u32)) {
+                    if tape.get_safe()? != C::ZERO {
                         program.jump_back(*addr_back);
                     } else {
                         program.jump_one();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
-                if tape.get() != 0 {
-                    program.jump_back(*addr_back);
+                if tape.get() != C::ZERO {
+                    let back = *addr_back;
+                    program.jump_back(back);
+                    program.charge(back as usize + 1)?;
+                    if program.back_edge(tape.inner.mode) {
+                        return Ok(InterpreterResult::ToggleTier(Tier::Jit));
+                    }
                     continue;
                 }
             }
             Bytecode::NegativeRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step_ptr((*delta) as isize);
-                if !range.contains(&(tape.get_ptr() as u16)) {
-                    if tape.get_safe(tape.get_ptr())? != 0 {
+                if !range.contains(&(tape.get_ptr() as u32)) {
+                    if tape.get_safe()? != C::ZERO {
                         program.jump_back(*addr_back);
                     } else {
                         program.jump_one();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
-                if tape.get() != 0 {
-                    program.jump_back(*addr_back);
+                if tape.get() != C::ZERO {
+                    let back = *addr_back;
+                    program.jump_back(back);
+                    program.charge(back as usize + 1)?;
+                    if program.back_edge(tape.inner.mode) {
+                        return Ok(InterpreterResult::ToggleTier(Tier::Jit));
+                    }
                     continue;
                 }
             }
             Bytecode::BothRangeCheckJNZ { delta, addr_back, range } => {
                 tape.step_ptr((*delta) as isize);
                 let ptr = tape.get_ptr();
-                if !range.contains(&(ptr as u16)) {
-                    if tape.get_safe(ptr)? != 0 {
+                if !range.contains(&(ptr as u32)) {
+                    if tape.get_safe()? != C::ZERO {
                         program.jump_back(*addr_back);
                     } else {
                         program.jump_one();
                     }
                     return Ok(InterpreterResult::ToggleTier(Tier::Deopt));
                 }
-                if tape.get() != 0 {
-                    program.jump_back(*addr_back);
+                if tape.get() != C::ZERO {
+                    let back = *addr_back;
+                    program.jump_back(back);
+                    program.charge(back as usize + 1)?;
+                    if program.back_edge(tape.inner.mode) {
+                        return Ok(InterpreterResult::ToggleTier(Tier::Jit));
+                    }
                     continue;
                 }
             }
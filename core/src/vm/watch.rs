@@ -0,0 +1,318 @@
+use std::iter::once;
+
+use crate::{bytecode::bytecode::{Access, Bytecode}, cell::Cell, vm::{tape::{Tape, TapeMode}, tier::internal::InterpreterResult}};
+
+/// What makes a watchpoint from `Brainrot::watch` stop the program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind<C: Cell> {
+    /// A bytecode reads the cell, as `.`, a loop condition, a scan or the source of a move does.
+    Read,
+    /// A bytecode changes the cell.
+    Write,
+    /// A bytecode changes the cell to this value.
+    Equals(C),
+}
+
+struct Watch<C: Cell> {
+    /// `index - origin` of the tape, see `Tape::origin`.
+    cell: isize,
+    kind: WatchKind<C>,
+}
+
+/// The pointer and the watched cells before the bytecode at `pc`, to tell afterwards what it did.
+struct Before<C: Cell> {
+    pc: usize,
+    pointer: isize,
+    values: Vec<(isize, C)>,
+}
+
+/// The watchpoints, and what the deopt tier needs to check them around each bytecode.
+pub struct Watches<C: Cell> {
+    watches: Vec<Watch<C>>,
+    before: Option<Before<C>>,
+}
+impl<C: Cell> Default for Watches<C> {
+    fn default() -> Self {
+        Watches { watches: vec![], before: None }
+    }
+}
+
+impl<C: Cell> Watches<C> {
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+    pub fn insert(&mut self, tape: &Tape<C>, index: usize, kind: WatchKind<C>) {
+        let cell = index as isize - tape.origin as isize;
+        if !self.watches.iter().any(|watch| watch.cell == cell && watch.kind == kind) {
+            self.watches.push(Watch { cell, kind });
+        }
+    }
+    pub fn remove(&mut self, tape: &Tape<C>, index: usize, kind: WatchKind<C>) -> bool {
+        let cell = index as isize - tape.origin as isize;
+        let len = self.watches.len();
+        self.watches.retain(|watch| watch.cell != cell || watch.kind != kind);
+        self.watches.len() != len
+    }
+    pub fn clear(&mut self) {
+        self.watches.clear();
+    }
+    /// The watched cells as tape indices, which shift when a Growable tape grows to the left.
+    pub fn iter<'a>(&'a self, tape: &'a Tape<C>) -> impl Iterator<Item = (usize, WatchKind<C>)> + 'a {
+        self.watches.iter().map(|watch| ((watch.cell + tape.origin as isize) as usize, watch.kind))
+    }
+
+    /// Called before the bytecode at `pc` runs.
+    pub fn before(&mut self, tape: &Tape<C>, pc: usize) {
+        self.before = (!self.watches.is_empty()).then(|| Before {
+            pc,
+            pointer: tape.data_pointer as isize - tape.origin as isize,
+            values: self.watches.iter().map(|watch| (watch.cell, value(tape, watch.cell))).collect(),
+        });
+    }
+    /// Drops what `before` saw, when the bytecode didn't run after all.
+    pub fn forget(&mut self) {
+        self.before = None;
+    }
+
+    /// Called once the bytecode given to `before` has run, along with any `Mul`, `MoveAdd` or `MoveSub` after it.
+    /// Returns the first watchpoint it hit.
+    pub fn after(&mut self, tape: &Tape<C>, insts: &[Bytecode<C>]) -> Option<InterpreterResult> {
+        let before = self.before.take()?;
+        let pointer = tape.data_pointer as isize - tape.origin as isize;
+        let wrap = |cell: isize| match tape.mode {
+            TapeMode::Wrapping if !tape.buffer.is_empty() => cell.rem_euclid(tape.buffer.len() as isize),
+            _ => cell,
+        };
+
+        // mul_val を引き継ぐ bytecode は MulStart / MoveStart と一緒に調べる
+        let body = match insts[before.pc] {
+            Bytecode::MulStart { .. } | Bytecode::MoveStart { .. } => &insts[before.pc + 1..],
+            _ => &[],
+        };
+        let body = body.iter().take_while(|inst| matches!(inst, Bytecode::Mul { .. } | Bytecode::MoveAdd { .. } | Bytecode::MoveSub { .. }));
+        let (mut reads, mut writes, mut scans) = (vec![], vec![], vec![]);
+        for inst in once(&insts[before.pc]).chain(body) {
+            inst.accesses(|access| match access {
+                Access::Read(offset) => reads.push(wrap(pointer + offset)),
+                Access::Write(offset) => writes.push(wrap(pointer + offset)),
+                Access::Scan { delta, step, end } => scans.push((wrap(before.pointer + delta), step, wrap(pointer + end))),
+            });
+        }
+
+        self.watches.iter().find_map(|watch| {
+            let new = value(tape, watch.cell);
+            // before の後に足された watch には前の値がない
+            let old = before.values.iter().find(|(cell, _)| *cell == watch.cell).map_or(new, |(_, old)| *old);
+            let changed = old != new && writes.contains(&watch.cell);
+            let hit = match watch.kind {
+                WatchKind::Read => reads.contains(&watch.cell) || scans.iter().any(|&(from, step, to)| scanned(tape, from, step, to, watch.cell)),
+                WatchKind::Write => changed,
+                WatchKind::Equals(value) => changed && new == value,
+            };
+            hit.then(|| InterpreterResult::Watchpoint {
+                cell: (watch.cell + tape.origin as isize) as usize,
+                old: old.to_u32(),
+                new: new.to_u32(),
+                pc: before.pc,
+            })
+        })
+    }
+}
+
+/// A Growable tape reads zero past its allocation.
+fn value<C: Cell>(tape: &Tape<C>, cell: isize) -> C {
+    usize::try_from(cell + tape.origin as isize).ok().and_then(|i| tape.buffer.get(i).copied()).unwrap_or(C::ZERO)
+}
+
+/// Whether a scan from `from` by `step` that stopped at `to` read `cell`.
+fn scanned<C: Cell>(tape: &Tape<C>, from: isize, step: isize, to: isize, cell: isize) -> bool {
+    if tape.mode == TapeMode::Wrapping {
+        let len = tape.buffer.len() as isize;
+        // 1 周する前に 0 のセルで止まっている
+        let mut at = from;
+        for _ in 0..len {
+            if at == cell {
+                return true;
+            }
+            if at == to {
+                return false;
+            }
+            at = (at + step).rem_euclid(len);
+        }
+        return false;
+    }
+    let (offset, length) = (cell - from, to - from);
+    step != 0 && offset % step == 0 && (0..=length / step).contains(&(offset / step))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{brainrot::{Brainrot, BrainrotInit}, bytecode::bytecode::Bytecode, vm::{journal::JournalConfig, program::EofPolicy, tape::{Tape, TapeMode}, tier::{BrainrotResult, internal::InterpreterResult}, watch::{WatchKind, Watches}}};
+
+    /// One bytecode at `pc` of `insts`, with the tape before it and what it does to the tape.
+    struct Case {
+        insts: Vec<Bytecode<u8>>,
+        pc: usize,
+        tape: fn() -> Tape<u8>,
+        run: fn(&mut Tape<u8>),
+    }
+    impl Case {
+        /// The cell, old and new value if watching `index` for `kind` stops after the bytecode.
+        fn hit(&self, index: usize, kind: WatchKind<u8>) -> Option<(usize, u32, u32)> {
+            let mut tape = (self.tape)();
+            let mut watches = Watches::default();
+            watches.insert(&tape, index, kind);
+            watches.before(&tape, self.pc);
+            (self.run)(&mut tape);
+            match watches.after(&tape, &self.insts)? {
+                InterpreterResult::Watchpoint { cell, old, new, pc } => {
+                    assert_eq!(pc, self.pc);
+                    Some((cell, old, new))
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn tape(length: usize, mode: TapeMode, pointer: usize, cells: &[(usize, u8)]) -> Tape<u8> {
+        let mut tape = Tape::new(length, mode);
+        tape.data_pointer = pointer;
+        for &(i, value) in cells {
+            tape.buffer[i] = value;
+        }
+        tape
+    }
+
+    #[test]
+    fn mul_body() {
+        // [->++>+++<<] を 5 から
+        let case = Case {
+            insts: vec![
+                Bytecode::MulStart { delta: 0, jz_abs: 3 },
+                Bytecode::Mul { delta: 1, val: 2 },
+                Bytecode::Mul { delta: 2, val: 3 },
+                Bytecode::End { delta: 0 },
+            ],
+            pc: 0,
+            tape: || tape(8, TapeMode::Fixed, 0, &[(0, 5)]),
+            run: |tape| {
+                tape.buffer[0] = 0;
+                tape.buffer[1] = 10;
+                tape.buffer[2] = 15;
+            },
+        };
+        assert_eq!(case.hit(0, WatchKind::Read), Some((0, 5, 0)));
+        assert_eq!(case.hit(0, WatchKind::Write), Some((0, 5, 0)));
+        assert_eq!(case.hit(1, WatchKind::Read), None);
+        assert_eq!(case.hit(1, WatchKind::Write), Some((1, 0, 10)));
+        assert_eq!(case.hit(2, WatchKind::Equals(15)), Some((2, 0, 15)));
+        assert_eq!(case.hit(2, WatchKind::Equals(14)), None);
+        assert_eq!(case.hit(3, WatchKind::Write), None);
+    }
+
+    #[test]
+    fn mul_body_on_a_wrapping_tape() {
+        let case = Case {
+            insts: vec![Bytecode::MulStart { delta: 0, jz_abs: 2 }, Bytecode::Mul { delta: 2, val: 1 }, Bytecode::End { delta: 0 }],
+            pc: 0,
+            tape: || tape(6, TapeMode::Wrapping, 5, &[(5, 4)]),
+            run: |tape| {
+                tape.buffer[5] = 0;
+                tape.buffer[1] = 4;
+            },
+        };
+        assert_eq!(case.hit(1, WatchKind::Write), Some((1, 0, 4)));
+        assert_eq!(case.hit(5, WatchKind::Read), Some((5, 4, 0)));
+        assert_eq!(case.hit(0, WatchKind::Write), None);
+    }
+
+    #[test]
+    fn double_move() {
+        // >[->+<<->] を、1 が 3、0 が 10 で
+        let case = Case {
+            insts: vec![Bytecode::DoubleMoveAddSub { delta: 1, to1: 1, to2: -1 }, Bytecode::End { delta: 0 }],
+            pc: 0,
+            tape: || tape(8, TapeMode::Fixed, 0, &[(0, 10), (1, 3)]),
+            run: |tape| {
+                tape.data_pointer = 1;
+                tape.buffer[0] = 7;
+                tape.buffer[1] = 0;
+                tape.buffer[2] = 3;
+            },
+        };
+        assert_eq!(case.hit(1, WatchKind::Read), Some((1, 3, 0)));
+        assert_eq!(case.hit(1, WatchKind::Equals(0)), Some((1, 3, 0)));
+        // 移動先は書くだけ。元のポインタの位置も読んでいない
+        assert_eq!(case.hit(0, WatchKind::Read), None);
+        assert_eq!(case.hit(0, WatchKind::Write), Some((0, 10, 7)));
+        assert_eq!(case.hit(2, WatchKind::Read), None);
+        assert_eq!(case.hit(2, WatchKind::Write), Some((2, 0, 3)));
+    }
+
+    #[test]
+    fn shift_add() {
+        // [>>]>++++ が 0 から 2 つおきに走査し、4 で止まって 5 に足す
+        let case = Case {
+            insts: vec![Bytecode::ShiftAdd { delta1: 0, step: 2, delta2: 1, val: 4 }, Bytecode::End { delta: 0 }],
+            pc: 0,
+            tape: || tape(8, TapeMode::Fixed, 0, &[(0, 1), (2, 1), (3, 9)]),
+            run: |tape| {
+                tape.data_pointer = 5;
+                tape.buffer[5] = 4;
+            },
+        };
+        assert_eq!(case.hit(0, WatchKind::Read), Some((0, 1, 1)));
+        assert_eq!(case.hit(2, WatchKind::Read), Some((2, 1, 1)));
+        assert_eq!(case.hit(4, WatchKind::Read), Some((4, 0, 0)));
+        assert_eq!(case.hit(3, WatchKind::Read), None);
+        assert_eq!(case.hit(6, WatchKind::Read), None);
+        assert_eq!(case.hit(5, WatchKind::Write), Some((5, 0, 4)));
+        assert_eq!(case.hit(5, WatchKind::Equals(4)), Some((5, 0, 4)));
+        assert_eq!(case.hit(4, WatchKind::Write), None);
+    }
+
+    #[test]
+    fn wrapping_scan() {
+        // 4 から 2 つおきに 4, 0, 2 と回って 2 で止まる
+        for after in [2, 8] {
+            let case = Case {
+                insts: vec![Bytecode::Shift { delta: 0, step: 2 }, Bytecode::End { delta: 0 }],
+                pc: 0,
+                tape: || tape(6, TapeMode::Wrapping, 4, &[(4, 1), (0, 1), (1, 1), (3, 1)]),
+                run: if after == 2 { |tape| tape.data_pointer = 2 } else { |tape| tape.data_pointer = 8 },
+            };
+            for cell in [4, 0, 2] {
+                assert!(case.hit(cell, WatchKind::Read).is_some(), "{cell} with the pointer at {after}");
+            }
+            for cell in [1, 3, 5] {
+                assert_eq!(case.hit(cell, WatchKind::Read), None, "{cell} with the pointer at {after}");
+            }
+            assert_eq!(case.hit(2, WatchKind::Write), None);
+        }
+    }
+
+    #[test]
+    fn replay_checks_watches() {
+        let mut output = vec![];
+        let mut vm = Brainrot::<_, _, u8>::new("+++>++<[->+<]>.", BrainrotInit {
+            input: || None,
+            output: |value| output.push(value),
+            io_break: false,
+            timeout_step: None,
+            eof: EofPolicy::Zero,
+            tape_length: 8,
+            tape_mode: TapeMode::Fixed,
+        }).unwrap();
+        vm.start_recording(JournalConfig::default());
+        assert!(matches!(vm.step(), Ok(BrainrotResult::End)));
+        while vm.reverse_step_bytecode() {}
+
+        // 記録をなぞる間も止まる
+        vm.watch(1, WatchKind::Equals(5));
+        assert!(matches!(vm.step(), Ok(BrainrotResult::Watchpoint { cell: 1, old: 2, new: 5, .. })));
+        assert!(!vm.journal().unwrap().is_live());
+        assert!(matches!(vm.step(), Ok(BrainrotResult::End)));
+        drop(vm);
+        assert_eq!(output, [5]);
+    }
+}